@@ -308,9 +308,11 @@ pmgdt:
 
 align 8
 lmgdt_base:
+    ; The accessed bits are preset, the kernel page table maps this read-only and the CPU would
+    ; fault trying to set them when the selectors are loaded
     dq 0x0000000000000000 ; Null descriptor
-    dq 0x00209A0000000000 ; 64-bit, present, code
-    dq 0x0000920000000000 ; Present, data r/w
+    dq 0x00209B0000000000 ; 64-bit, present, code, accessed
+    dq 0x0000930000000000 ; Present, data r/w, accessed
 
 lmgdt:
     dw (lmgdt - lmgdt_base) - 1
@@ -323,7 +325,8 @@ _enter64:
     ; qword [esp + 0x04] - Entry
     ; qword [esp + 0x0c] - Stack
     ; qword [esp + 0x14] - Param
-    ; qword [esp + 0x1c] - New cr3
+    ; dword [esp + 0x1c] - New cr3
+    ; dword [esp + 0x20] - Extra cr4 bits (SMEP, SMAP) supported by the CPU
 
     ; Get the parameters passed in to this function
     mov esi, [esp + 0x1c] ; New cr3
@@ -343,6 +346,7 @@ _enter64:
     or eax, (1 << 10) ; OSXMMEXCPT
     or eax, (1 << 5) ; PAE
     or eax, (1 << 3) ; DE
+    or eax, [esp + 0x20] ; SMEP and SMAP, if supported
     mov cr4, eax
 
     ; Normalize the cr0
@@ -370,16 +374,7 @@ lm_entry:
     mov gs, ax
     mov ss, ax
 
-    ; Enable OSXSAVE
-    ;mov rax, cr4
-    ;or rax, 1 << 18
-    ;mov cr4, rax
-
-    ; Enable AVX512
-    ;xor ecx, ecx
-    ;xor edx, edx
-    ;mov eax, (7 << 5) | 7
-    ;xsetbv
+    ; OSXSAVE and XCR0 are set up by the kernel, once it knows what the CPU supports
 
     mov rdi, qword [rsp + 0x4] ; Entry point
     mov rbp, qword [rsp + 0xc] ; Stack
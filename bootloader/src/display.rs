@@ -0,0 +1,165 @@
+//! Screen output for the bootloader. By default we draw to the VGA text mode buffer the BIOS left
+//! us in. With the `vbe` feature we try to switch to a linear framebuffer mode through the VBE
+//! BIOS interface instead, falling back to text mode if no suitable mode exists.
+
+use console::Console;
+
+/// Create the screen console
+pub fn init() -> Console {
+    #[cfg(feature = "vbe")]
+    if let Some(info) = vbe::set_mode(vbe::PREFERRED_WIDTH, vbe::PREFERRED_HEIGHT) {
+        return unsafe { Console::framebuffer(info) };
+    }
+
+    unsafe { Console::text() }
+}
+
+#[cfg(feature = "vbe")]
+mod vbe {
+    use core::convert::TryInto;
+    use console::FramebufferInfo;
+    use crate::realmode::{invoke_realmode, segoff_to_linear, RegisterState};
+
+    /// Resolution we try to switch to
+    pub const PREFERRED_WIDTH: u32 = 1024;
+    pub const PREFERRED_HEIGHT: u32 = 768;
+
+    /// Return value in `ax` for successful VBE calls
+    const VBE_SUCCESS: u32 = 0x004f;
+
+    /// VBE function numbers, passed in `ax` to int 0x10
+    const VBE_GET_CONTROLLER_INFO: u32 = 0x4f00;
+    const VBE_GET_MODE_INFO: u32 = 0x4f01;
+    const VBE_SET_MODE: u32 = 0x4f02;
+
+    /// Mode attributes: supported by hardware, graphics mode and linear framebuffer available
+    const MODE_ATTR_SUPPORTED: u16 = 1 << 0;
+    const MODE_ATTR_GRAPHICS: u16 = 1 << 4;
+    const MODE_ATTR_LINEAR: u16 = 1 << 7;
+
+    /// Memory model for direct color modes
+    const MEMORY_MODEL_DIRECT: u8 = 6;
+
+    /// Flag to pass in `bx` to VBE_SET_MODE to use the linear framebuffer
+    const SET_MODE_LINEAR: u32 = 1 << 14;
+
+    /// Upper bound on the number of modes we look at, in case the mode list is not terminated
+    const MAX_MODES: usize = 256;
+
+    /// Get the address of the 8x16 font in the VGA BIOS ROM
+    fn rom_font() -> Option<u64> {
+        let mut regs = RegisterState::default();
+        regs.eax = 0x1130;
+        regs.ebx = 0x0600;
+        unsafe { invoke_realmode(0x10, &mut regs); }
+
+        let font = segoff_to_linear(regs.es, regs.ebp as u16);
+        if font == 0 {
+            return None;
+        }
+
+        Some(font as u64)
+    }
+
+    /// Find a direct color mode with a linear framebuffer at `width`x`height` and switch to it,
+    /// preferring the mode with the most bits per pixel
+    pub fn set_mode(width: u32, height: u32) -> Option<FramebufferInfo> {
+        let font = rom_font()?;
+
+        // Get the controller information, which contains the list of supported modes. Setting the
+        // signature to "VBE2" requests the VBE 2.0 version of the structure.
+        let mut ctrl_info = [0u8; 512];
+        ctrl_info[..4].copy_from_slice(b"VBE2");
+
+        let mut regs = RegisterState::default();
+        regs.eax = VBE_GET_CONTROLLER_INFO;
+        regs.edi = &mut ctrl_info as *mut _ as u32;
+        unsafe { invoke_realmode(0x10, &mut regs); }
+
+        if regs.eax & 0xffff != VBE_SUCCESS || &ctrl_info[..4] != b"VESA" {
+            return None;
+        }
+
+        // Get the far pointer to the mode list
+        let modes_off = u16::from_le_bytes(ctrl_info[0xe..0x10].try_into().ok()?);
+        let modes_seg = u16::from_le_bytes(ctrl_info[0x10..0x12].try_into().ok()?);
+        let modes = segoff_to_linear(modes_seg, modes_off) as *const u16;
+
+        // The best mode found so far, and the framebuffer it would give us
+        let mut best: Option<(u16, FramebufferInfo)> = None;
+
+        for ii in 0..MAX_MODES {
+            let mode = unsafe { core::ptr::read_unaligned(modes.add(ii)) };
+            if mode == 0xffff {
+                break;
+            }
+
+            let mut mode_info = [0u8; 256];
+
+            let mut regs = RegisterState::default();
+            regs.eax = VBE_GET_MODE_INFO;
+            regs.ecx = mode as u32;
+            regs.edi = &mut mode_info as *mut _ as u32;
+            unsafe { invoke_realmode(0x10, &mut regs); }
+
+            if regs.eax & 0xffff != VBE_SUCCESS {
+                continue;
+            }
+
+            // Extract the fields we need to decide on this mode
+            let attributes = u16::from_le_bytes(mode_info[0x0..0x2].try_into().ok()?);
+            let pitch  = u16::from_le_bytes(mode_info[0x10..0x12].try_into().ok()?) as u32;
+            let mode_w = u16::from_le_bytes(mode_info[0x12..0x14].try_into().ok()?) as u32;
+            let mode_h = u16::from_le_bytes(mode_info[0x14..0x16].try_into().ok()?) as u32;
+            let bpp    = mode_info[0x19] as u32;
+            let model  = mode_info[0x1b];
+            let base   = u32::from_le_bytes(mode_info[0x28..0x2c].try_into().ok()?) as u64;
+
+            let required = MODE_ATTR_SUPPORTED | MODE_ATTR_GRAPHICS | MODE_ATTR_LINEAR;
+            if attributes & required != required || model != MEMORY_MODEL_DIRECT ||
+                    mode_w != width || mode_h != height || base == 0 ||
+                    !matches!(bpp, 16 | 24 | 32) {
+                continue;
+            }
+
+            if best.map_or(false, |(_, info)| info.bytes_per_pixel * 8 >= bpp) {
+                continue;
+            }
+
+            // Compute white from the size and position of each color channel
+            let mut white = 0u32;
+            for &(size, pos) in &[(0x1f, 0x20), (0x21, 0x22), (0x23, 0x24)] {
+                let size = mode_info[size] as u32;
+                let pos = mode_info[pos] as u32;
+                white |= 1u32.checked_shl(size).map_or(!0, |x| x - 1)
+                    .checked_shl(pos).unwrap_or(0);
+            }
+
+            best = Some((mode, FramebufferInfo {
+                base,
+                font,
+                width: mode_w,
+                height: mode_h,
+                pitch,
+                bytes_per_pixel: bpp / 8,
+                font_height: 16,
+                foreground: white,
+                background: 0,
+            }));
+        }
+
+        let (mode, info) = best?;
+
+        // Switch to the mode
+        let mut regs = RegisterState::default();
+        regs.eax = VBE_SET_MODE;
+        regs.ebx = mode as u32 | SET_MODE_LINEAR;
+        unsafe { invoke_realmode(0x10, &mut regs); }
+
+        if regs.eax & 0xffff != VBE_SUCCESS {
+            return None;
+        }
+
+        Some(info)
+    }
+}
@@ -0,0 +1,167 @@
+//! Loading of the kernel image. The kernel can either be a PE, as built for
+//! `x86_64-pc-windows-msvc`, or an ELF, as built for `x86_64-unknown-none`. The format is detected
+//! from the magic bytes at the start of the file.
+
+use alloc::vec::Vec;
+use parse_pe::PeParser;
+use parse_elf::ElfParser;
+
+/// A region of the kernel image which must be mapped with one set of permissions
+pub struct Region {
+    /// Offset of the region from the base of the image, always page aligned
+    pub offset: u64,
+
+    /// Size of the region in bytes, a multiple of the page size once validated
+    pub size: u64,
+
+    /// Permissions for the region
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+/// A kernel image laid out as it will be in memory and relocated to `base`
+pub struct KernelImage {
+    /// The image contents, `image[offset]` is the byte at virtual address `base + offset`
+    pub image: Vec<u8>,
+
+    /// Virtual address the image was relocated to
+    pub base: u64,
+
+    /// Virtual address of the entry point
+    pub entry_point: u64,
+
+    /// Regions of the image to map
+    pub regions: Vec<Region>,
+}
+
+/// Pick the address to load an image of `size` bytes at. If the kernel has no relocations we have
+/// to use the base it was linked at.
+fn pick_base(relocatable: bool, linked_base: u64, size: u64) -> u64 {
+    if relocatable {
+        crate::kaslr_base(size)
+    } else {
+        print!("Kernel is not relocatable, using the linked base\n");
+        linked_base
+    }
+}
+
+/// Size of a page, regions of the image are mapped at this granularity
+const PAGE_SIZE: u64 = 4096;
+
+/// Make sure every region is page aligned, inside of the image, not both writable and executable,
+/// and does not share a page with any other region, as pages can only have one set of permissions.
+/// Empty regions are dropped and sizes are rounded up to whole pages. Panics if the image does not
+/// satisfy this.
+fn validate(mut regions: Vec<Region>, image_size: u64) -> Vec<Region> {
+    regions.retain(|region| region.size != 0);
+    regions.sort_by_key(|region| region.offset);
+
+    let mut prev_end = 0;
+    for region in &mut regions {
+        if region.offset & (PAGE_SIZE - 1) != 0 {
+            panic!("Kernel region at offset {:#x} is not page aligned", region.offset);
+        }
+
+        region.size = region.size.checked_add(PAGE_SIZE - 1)
+            .expect("Kernel region size overflow") & !(PAGE_SIZE - 1);
+
+        if region.write && region.execute {
+            panic!("Kernel region at offset {:#x} is both writable and executable",
+                region.offset);
+        }
+
+        let end = region.offset + region.size;
+        if region.offset < prev_end {
+            panic!("Kernel region at offset {:#x} overlaps the previous region", region.offset);
+        }
+        if end > ((image_size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)) {
+            panic!("Kernel region at offset {:#x} for {:#x} bytes is outside of the image",
+                region.offset, region.size);
+        }
+
+        prev_end = end;
+    }
+
+    regions
+}
+
+/// Parse, lay out and relocate the kernel image in `bytes`
+pub fn load(bytes: &[u8]) -> KernelImage {
+    match bytes.get(..4) {
+        Some(b"\x7fELF") => load_elf(bytes),
+        Some([b'M', b'Z', ..]) => load_pe(bytes),
+        _ => panic!("Kernel image is neither a PE nor an ELF"),
+    }
+}
+
+/// Load a PE kernel image
+fn load_pe(bytes: &[u8]) -> KernelImage {
+    let pe = PeParser::parse(bytes)
+        .unwrap_or_else(|err| panic!("Failed to parse kernel PE: {}", err));
+
+    let base = pick_base(pe.relocatable(), pe.optional_header.image_base,
+        pe.optional_header.size_of_image as u64);
+
+    // Lay out the kernel as it will be in memory and relocate it to the new base
+    let mut image = vec![0u8; pe.optional_header.size_of_image as usize];
+    pe.load(&mut image)
+        .unwrap_or_else(|err| panic!("Failed to load kernel image: {}", err));
+    pe.relocate(&mut image, base)
+        .unwrap_or_else(|err| panic!("Failed to relocate kernel image: {}", err));
+
+    let regions = pe.section_headers().map(|section| Region {
+        offset:  section.virtual_address as u64,
+        size:    section.virtual_size as u64,
+        read:    section.read(),
+        write:   section.write(),
+        execute: section.execute(),
+    }).collect();
+    let regions = validate(regions, pe.optional_header.size_of_image as u64);
+
+    KernelImage {
+        image,
+        base,
+        entry_point: base + pe.optional_header.address_of_entry_point as u64,
+        regions,
+    }
+}
+
+/// Load an ELF kernel image
+fn load_elf(bytes: &[u8]) -> KernelImage {
+    let elf = ElfParser::parse(bytes)
+        .unwrap_or_else(|err| panic!("Failed to parse kernel ELF: {}", err));
+
+    let base = pick_base(elf.relocatable(), elf.image_base, elf.image_size);
+
+    // Lay out the kernel as it will be in memory and relocate it to the new base
+    let mut image = vec![0u8; elf.image_size as usize];
+    elf.load(&mut image)
+        .unwrap_or_else(|err| panic!("Failed to load kernel image: {}", err));
+    elf.relocate(&mut image, base)
+        .unwrap_or_else(|err| panic!("Failed to relocate kernel image: {}", err));
+
+    // Segments do not have to start on a page boundary, map from the start of the page they are
+    // in. The loaded image has the bytes for the whole page.
+    let mut regions = Vec::new();
+    elf.segments(|vaddr, vsize, _, read, write, execute| {
+        let start = vaddr & !0xfff;
+        regions.push(Region {
+            offset: start - elf.image_base,
+            size: vaddr + vsize as u64 - start,
+            read,
+            write,
+            execute,
+        });
+        Ok::<(), parse_elf::Error>(())
+    }).unwrap_or_else(|err| panic!("Failed to parse kernel segments: {}", err));
+    let regions = validate(regions, elf.image_size);
+
+    KernelImage {
+        image,
+        base,
+        entry_point: base + elf.entry_point.checked_sub(elf.image_base)
+            .expect("Kernel entry point is below the image"),
+        regions,
+    }
+}
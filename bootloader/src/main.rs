@@ -3,35 +3,266 @@
 #![no_std]
 #![no_main]
 
-extern crate alloc;
+#[macro_use] extern crate alloc;
 extern crate core_reqs;
 
 #[macro_use] mod print;
 mod realmode;
+mod display;
+mod loader;
 mod mm;
 mod panic;
 mod pxe;
+mod time;
 
 use core::sync::atomic::{AtomicU64, Ordering};
-use boot_args::{BootArgs, KERNEL_STACK_SIZE, KERNEL_STACK_PAD, PHYS_WINDOW_BASE};
-use parse_pe::PeParser;
-use page_table::{VirtAddr, PageTable, PageSize, PAGE_PRESENT, PAGE_WRITE, PAGE_NX};
+use boot_args::{BootArgs, KERNEL_STACK_SIZE, KERNEL_STACK_PAD, KERNEL_PHYS_WINDOW_BASE};
+use boot_args::{KERNEL_IMAGE_WINDOW_BASE, KERNEL_IMAGE_WINDOW_SIZE, KERNEL_IMAGE_ALIGN};
+use boot_args::{KERNEL_IMAGE_GUARD, KERNEL_STACK_WINDOW_BASE, KERNEL_STACK_WINDOW_SIZE};
+use boot_args::{KERNEL_STACK_MAX_GAP, Modules, CommandLine};
+use page_table::{VirtAddr, PageTable, PageSize, CacheType, MapFlags};
+use page_table::{PAGE_PRESENT, PAGE_WRITE, PAGE_NX, PAGE_HUGE};
+use rangeset::{Range, RangeSet};
 use lockcell::LockCell;
 use serial::SerialPort;
+use rng::Rng;
+use manifest::Manifest;
+use boot_config::Config;
+use pxe::PxeError;
+use alloc::string::String;
 
 
 /// Global arguments shared between the kernel and the bootloader. It is critical that every
 /// structure in here is identical in shape between boot 64-bit and 32-bit representations.
 pub static BOOT_ARGS: BootArgs = BootArgs {
     free_memory: LockCell::new(None),
+    ram: LockCell::new(None),
     serial: LockCell::new(None),
+    console: LockCell::new(None),
     page_table: LockCell::new(None), 
     trampoline_page_table: LockCell::new(None),
     kernel_entry: LockCell::new(None),
-    stack_vaddr: AtomicU64::new(0x0000_7473_0000_0000), // "st" in ascii LE
+    modules: LockCell::new(Modules::new()),
+    cmdline: LockCell::new(CommandLine::new()),
+    dhcp: LockCell::new(None),
+    stack_vaddr: AtomicU64::new(KERNEL_STACK_WINDOW_BASE),
     print_lock: LockCell::new(()),
 };
 
+/// Start of the bootloader code which runs with paging enabled, the AP entry point followed by the
+/// flattened bootloader image
+const BOOTLOADER_CODE_START: u64 = 0x8000;
+
+/// Size of the low memory identity mapped with 4 KiB pages, such that the bootloader image can
+/// have different permissions than the memory around it
+const LOW_IDENTITY_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Amount of physical memory identity mapped, and mapped at `KERNEL_PHYS_WINDOW_BASE`, in the
+/// kernel page table
+const PHYS_MAP_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Legacy VGA memory and option ROMs, which are device memory even though they are below 1 MiB
+const LEGACY_DEVICE_MEMORY: core::ops::Range<u64> = 0xa_0000..0x10_0000;
+
+/// Get the memory type to map `size` bytes of physical memory at `paddr` with. Anything which is
+/// not RAM is device memory and must never be cached, so only memory which is entirely RAM is
+/// cached.
+fn cache_type(ram: &RangeSet, paddr: u64, size: u64) -> CacheType {
+    let end = paddr + size - 1;
+
+    let is_legacy_device = paddr < LEGACY_DEVICE_MEMORY.end && end >= LEGACY_DEVICE_MEMORY.start;
+    let is_ram = ram.entries().iter().any(|range| range.start <= paddr && range.end >= end);
+
+    if is_ram && !is_legacy_device {
+        CacheType::WriteBack
+    } else {
+        CacheType::Uncacheable
+    }
+}
+
+/// Whether `size` bytes of physical memory at `paddr` are only partly RAM, such that they have to
+/// be mapped with smaller pages to cache the RAM without caching the device memory next to it
+fn partly_ram(ram: &RangeSet, paddr: u64, size: u64) -> bool {
+    let end = paddr + size - 1;
+
+    ram.entries().iter().any(|range| range.start <= end && range.end >= paddr) &&
+        cache_type(ram, paddr, size) != CacheType::WriteBack
+}
+
+/// Get the CR4 bits `enter64` should set on top of the ones it always sets. SMEP and SMAP are
+/// enabled if the CPU supports them. Panics if the CPU cannot enforce non-executable pages, as
+/// the kernel page table relies on them.
+fn protection_cr4() -> u32 {
+    assert!(cpu::has_nx(), "CPU does not support NX, cannot enforce W^X");
+
+    let mut cr4 = 0;
+    if cpu::has_smep() { cr4 |= cpu::CR4_SMEP as u32; }
+    if cpu::has_smap() { cr4 |= cpu::CR4_SMAP as u32; }
+    cr4
+}
+
+/// Random number generator for randomizing the kernel layout, seeded from `rng::entropy()` by
+/// the first core to boot
+static RNG: Rng = Rng::new(0);
+
+/// Pick a random, `KERNEL_IMAGE_ALIGN` aligned base address for a kernel image of `image_size`
+/// bytes, such that the whole image fits in the kernel image window
+fn kaslr_base(image_size: u64) -> u64 {
+    // Keep a guard region after the image inside of the window
+    let image_size = image_size + KERNEL_IMAGE_GUARD;
+    let image_size = (image_size + KERNEL_IMAGE_ALIGN - 1) & !(KERNEL_IMAGE_ALIGN - 1);
+    let slots = KERNEL_IMAGE_WINDOW_SIZE.checked_sub(image_size)
+        .expect("Kernel image too large for the image window") / KERNEL_IMAGE_ALIGN + 1;
+
+    KERNEL_IMAGE_WINDOW_BASE + RNG.range(slots) * KERNEL_IMAGE_ALIGN
+}
+
+/// Ed25519 public key the kernel manifest must be signed with, passed in by the build tool
+#[cfg(feature = "signed")]
+const KERNEL_PUBLIC_KEY: [u8; 32] = manifest::decode_hex(env!("SHERLOCK_PUBLIC_KEY"));
+
+/// Download the manifest of `filename`, `<filename>.manifest`, over TFTP. When built with the
+/// `signed` feature the manifest must be signed with `KERNEL_PUBLIC_KEY`. Panics if anything does
+/// not check out, we never boot from a file we cannot vouch for.
+fn download_manifest(filename: &str) -> Manifest {
+    let manifest_name = format!("{}.manifest", filename);
+    let manifest = pxe::download(&manifest_name).unwrap_or_else(|err|
+        panic!("Failed to download {} over TFTP: {}", manifest_name, err));
+    let manifest = Manifest::parse(&manifest).unwrap_or_else(|err|
+        panic!("Invalid manifest {}: {}", manifest_name, err));
+
+    #[cfg(feature = "signed")]
+    manifest.verify(&KERNEL_PUBLIC_KEY).unwrap_or_else(|err|
+        panic!("Refusing to boot {}: {}", filename, err));
+
+    manifest
+}
+
+/// Number of bytes of physical memory allocated for a file of `size` bytes, whole pages and never
+/// empty
+fn frames_size(size: u64) -> u64 {
+    core::cmp::max((size + 0xfff) & !0xfff, 4096)
+}
+
+/// Download `filename` over TFTP straight into pages allocated from `BOOT_ARGS.free_memory`,
+/// hashing it as the blocks arrive, and check it against its manifest. Files can be far larger
+/// than the heap, so they are sized from their manifests rather than buffered. Returns the
+/// physical address and the size of the file, the pages are `frames_size(size)` bytes.
+fn download_to_frames(filename: &str) -> (usize, u64) {
+    let manifest = download_manifest(filename);
+
+    let paddr = {
+        let mut pmem = BOOT_ARGS.free_memory.lock();
+        let pmem = pmem.as_mut().expect("Whoa, physical memory not init yet");
+        pmem.allocate(frames_size(manifest.size), 4096)
+            .unwrap_or_else(|| panic!("Out of memory downloading {}", filename))
+    };
+
+    let frames = unsafe {
+        core::slice::from_raw_parts_mut(paddr as *mut u8, manifest.size as usize)
+    };
+
+    // Blocks arrive in order, but a retried transfer starts over from offset 0, and so does the
+    // hash
+    let mut hasher = sha2::Sha256::default();
+    let size = pxe::download_with(filename, |block| {
+        if block.offset == 0 {
+            hasher = sha2::Sha256::default();
+        }
+
+        // Anything past the size in the manifest would not be covered by its hash, so refuse it
+        // rather than writing past the allocation
+        let dest = frames.get_mut(block.offset as usize..)
+            .and_then(|dest| dest.get_mut(..block.data.len()))
+            .ok_or(PxeError::FileTooLarge(manifest.size))?;
+        dest.copy_from_slice(block.data);
+        hasher.update(block.data);
+        Ok(())
+    }).unwrap_or_else(|err| panic!("Failed to download {} over TFTP: {}", filename, err));
+
+    manifest.check_digest(size, &hasher.finish()).unwrap_or_else(|err|
+        panic!("Refusing to boot {}: {}", filename, err));
+
+    print!("Verified {} | {} bytes | SHA-256 ", filename, size);
+    for byte in manifest.sha256 {
+        print!("{:02x}", byte);
+    }
+    print!("{}\n", if manifest.signature.is_some() { " | signed" } else { "" });
+
+    (paddr, size)
+}
+
+/// Download the boot config from the TFTP server, falling back to `boot_config::DEFAULT` if the
+/// server does not have one
+fn download_config() -> String {
+    let config = match pxe::download(boot_config::FILENAME) {
+        Ok(config) => config,
+        Err(err) if err.is_file_not_found() => {
+            print!("No {} on the server, using the default config\n", boot_config::FILENAME);
+            return boot_config::DEFAULT.into();
+        }
+        Err(err) => panic!("Failed to download {} over TFTP: {}", boot_config::FILENAME, err),
+    };
+
+    String::from_utf8(config).unwrap_or_else(|_|
+        panic!("{} is not valid UTF-8", boot_config::FILENAME))
+}
+
+/// DHCP option the kernel command line is taken from when the boot config has none. Etherboot
+/// used this option for kernel parameters, so DHCP servers tend to know it.
+const DHCP_OPTION_CMDLINE: u8 = 129;
+
+/// Record the kernel command line in `BOOT_ARGS`, from the boot config if it has one, otherwise
+/// from the DHCP ACK
+fn set_cmdline(config: &Config) {
+    let dhcp_ack;
+    let cmdline = match config.cmdline() {
+        Some(cmdline) => cmdline,
+        None => {
+            dhcp_ack = pxe::dhcp_ack().unwrap_or_else(|err|
+                panic!("Failed to get the cached DHCP ACK: {}", err));
+            pxe::dhcp_option(&dhcp_ack, DHCP_OPTION_CMDLINE)
+                .map(|cmdline| core::str::from_utf8(cmdline)
+                    .expect("Command line from DHCP is not valid UTF-8"))
+                .unwrap_or("")
+        }
+    };
+
+    BOOT_ARGS.cmdline.lock().set(cmdline).unwrap_or_else(|| panic!(
+        "Kernel command line is {} bytes, at most {} are supported", cmdline.len(),
+        boot_args::MAX_CMDLINE));
+
+    print!("Kernel command line: \"{}\"\n", cmdline);
+}
+
+/// Record the addressing the PXE stack got from DHCP in `BOOT_ARGS`, for the kernel to reuse
+fn set_dhcp_info() {
+    let info = pxe::dhcp_info().unwrap_or_else(|err|
+        panic!("Failed to get the cached DHCP ACK: {}", err));
+
+    print!("DHCP: IP {} | mask {} | router {} | server {} | boot file \"{}\"\n",
+        info.client_ip, info.subnet_mask, info.router, info.server_ip,
+        core::str::from_utf8(info.boot_file()).unwrap_or("<non UTF-8 filename>"));
+
+    *BOOT_ARGS.dhcp.lock() = Some(info);
+}
+
+/// Download the modules listed in `config` straight into pages of their own, which stay allocated
+/// for the kernel, and record them in `BOOT_ARGS`
+fn load_modules(config: &Config) {
+    let mut modules = BOOT_ARGS.modules.lock();
+
+    for (name, file) in config.modules() {
+        let (paddr, size) = download_to_frames(file);
+
+        modules.push(name, paddr as u64, size).unwrap_or_else(|| panic!(
+            "Cannot pass module {} to the kernel, too many modules or its name is too long",
+            name));
+
+        print!("Loaded module {} at {:#x} | {} bytes\n", name, paddr, size);
+    }
+}
+
 /// Rust entry point for the bootloader
 #[no_mangle]
 pub extern fn entry(bootloader_end: usize) -> !{
@@ -49,8 +280,25 @@ pub extern fn entry(bootloader_end: usize) -> !{
             for _ in 0..100 {
                 print!("\n");
             }
+
+            // Set up the screen console as a second output, it clears itself
+            *BOOT_ARGS.console.lock() = Some(display::init());
+
             print!("Chocolate Milk bootloader initialized!\n");
             print!("Bootloader end at {:#x}\n", bootloader_end);
+
+            let cpu = cpu::features();
+            print!("CPU: {} | family {:#x} model {:#x} stepping {:#x} | {} physical address bits\n",
+                cpu.brand().unwrap_or("unknown"), cpu.family, cpu.model, cpu.stepping,
+                cpu.max_phys_addr_bits);
+            print!("TSC frequency: {} MHz\n", time::calibrate());
+
+            // Seed the layout randomization, and start the kernel stacks at a random page in the
+            // lower half of the stack window
+            RNG.reseed(rng::entropy());
+            let stack_pages = KERNEL_STACK_WINDOW_SIZE / 2 / 4096;
+            BOOT_ARGS.stack_vaddr.store(KERNEL_STACK_WINDOW_BASE + RNG.range(stack_pages) * 4096,
+                Ordering::SeqCst);
         }
     }
 
@@ -66,12 +314,29 @@ pub extern fn entry(bootloader_end: usize) -> !{
         if kernel_entry.is_none() {
             assert!(page_table.is_none(), "Page table set up before kernel!?");
 
-            // Download the kerneL
-            let kernel = pxe::download("sherlock.kern")
-                .expect("Failed to download chocolate_milk.kern over TFTP");
+            // Find out what to boot
+            let config = download_config();
+            let config = Config::parse(&config).unwrap_or_else(|err|
+                panic!("Invalid {}: {}", boot_config::FILENAME, err));
+
+            // Download the kernel, and make sure it is the one the build tool produced
+            let (kernel_paddr, kernel_size) = download_to_frames(config.kernel());
+
+            // Lay out the kernel as it will be in memory, at a random base if we can. The file is
+            // not needed past this point, so give its pages back.
+            let kernel = loader::load(unsafe {
+                core::slice::from_raw_parts(kernel_paddr as *const u8, kernel_size as usize)
+            });
+            BOOT_ARGS.free_memory.lock().as_mut().expect("Whoa, physical memory not init yet")
+                .insert(Range {
+                    start: kernel_paddr as u64,
+                    end:   kernel_paddr as u64 + frames_size(kernel_size) - 1,
+                });
 
-            // Parse the kernel PE
-            let pe = PeParser::parse(&kernel).expect("Failed to parse PE");
+            // Load everything else the kernel was configured with
+            set_cmdline(&config);
+            set_dhcp_info();
+            load_modules(&config);
 
             // Get exclusive access to physical memory
             let mut pmem = BOOT_ARGS.free_memory.lock();
@@ -103,40 +368,94 @@ pub extern fn entry(bootloader_end: usize) -> !{
             // Create a new page table
             let mut table = PageTable::new(&mut pmem).expect("Failed to create page table");
 
-            // Make an identity map, because after we enable the CR3 paging we will not know where
-            // we are in memory
-            // Create a 2 GiB identity map
-            for paddr in (0..(4u64 * 1024 * 1024 * 1024)).step_by(4096) {
+            // Make an identity map of the first 4 GiB, because after we enable the CR3 paging we
+            // will not know where we are in memory. The bootloader image is the only thing which
+            // is executed with paging enabled, so it is mapped read-only and executable, and all
+            // other memory is writable and non-executable. Memory which is not RAM is mapped
+            // uncacheable. A 2 MiB page which is partially RAM is split into 4 KiB pages, such that
+            // device memory sharing it is never cached.
+            let ram = BOOT_ARGS.ram.lock().expect("RAM map not set up");
+            let bootloader_code = BOOTLOADER_CODE_START..
+                ((bootloader_end as u64 + 0xfff) & !0xfff);
+            assert!(bootloader_code.end <= LOW_IDENTITY_SIZE, "Bootloader too large");
+
+            for paddr in (0..LOW_IDENTITY_SIZE).step_by(4096) {
+                let perms = if bootloader_code.contains(&paddr) {
+                    PAGE_PRESENT
+                } else {
+                    PAGE_PRESENT | PAGE_WRITE | PAGE_NX
+                };
+                let cache = cache_type(&ram, paddr, 4096).page_bits(PageSize::Page4K);
+
                 unsafe {
                     table
-                        .map_raw(&mut pmem, VirtAddr(paddr), PageSize::Page4K, paddr | 3, true, false, false)
+                        .map_raw(&mut pmem, VirtAddr(paddr), PageSize::Page4K,
+                            paddr | perms | cache, true, false, false)
                         .unwrap();
                 }
             }
 
-            // Load all the sections from the PE into the page table
-            pe.sections(|vaddr, vsize, raw, read, write, execute| {
-                // Create a new virtual mapping for the PE range and initialize it to the raw bytes
-                // from the PE file, otherwise to zero for all the bytes that were not initialized in
-                // the file
+            // Map the rest of the identity map, and the physical window, with 2 MiB pages
+            for paddr in (0..PHYS_MAP_SIZE).step_by(PageSize::Page2M as usize) {
+                let (page_type, huge) = if partly_ram(&ram, paddr, PageSize::Page2M as u64) {
+                    (PageSize::Page4K, 0)
+                } else {
+                    (PageSize::Page2M, PAGE_HUGE)
+                };
+
+                let end = paddr + PageSize::Page2M as u64;
+                for paddr in (paddr..end).step_by(page_type as usize) {
+                    let cache = cache_type(&ram, paddr, page_type as u64).page_bits(page_type);
+                    let ent = paddr | huge | PAGE_PRESENT | PAGE_WRITE | PAGE_NX | cache;
+
+                    unsafe {
+                        if paddr >= LOW_IDENTITY_SIZE {
+                            table
+                                .map_raw(&mut pmem, VirtAddr(paddr), page_type, ent,
+                                    true, false, false)
+                                .unwrap();
+                        }
+
+                        table
+                            .map_raw(&mut pmem, VirtAddr(KERNEL_PHYS_WINDOW_BASE + paddr),
+                                page_type, ent, true, false, false)
+                            .unwrap();
+                    }
+                }
+            }
+
+            // Map all the regions of the kernel image into the page table
+            for region in &kernel.regions {
+                let vaddr = kernel.base + region.offset;
+                let vsize = region.size;
+                let data = &kernel.image[region.offset as usize..];
+
+                // Create a new virtual mapping for the region and initialize it to the loaded
+                // image. The image is zero past the end of the region.
                 unsafe {
-                    table.map_init(&mut pmem, VirtAddr(vaddr), PageSize::Page4K, vsize as u64, 
-                        read, write, execute,
-                        Some(|off| raw.get(off as usize).copied().unwrap_or(0)))?;
+                    let flags = MapFlags {
+                        read: region.read,
+                        write: region.write,
+                        exec: region.execute,
+                        cache: CacheType::WriteBack,
+                    };
+                    table.map_init(&mut pmem, VirtAddr(vaddr), PageSize::Page4K, vsize, flags,
+                        Some(|off| data.get(off as usize).copied().unwrap_or(0)))
+                        .expect("Failed to map kernel section");
                 }
                 print!("Created map at {:x?} for {:x?} bytes | permissions {}{}{}\n",
-                    vaddr as usize, vsize as usize,
-                    if read { "R" } else { "-" },
-                    if write { "W" } else { "-" },
-                    if execute { "X" } else { "-" },
+                    vaddr, vsize,
+                    if region.read { "R" } else { "-" },
+                    if region.write { "W" } else { "-" },
+                    if region.execute { "X" } else { "-" },
                 );
-                Some(())
-            }).unwrap();
+            }
 
-            print!("Entry point is {:#x}\n", pe.entry_point);
+            let entry_point = kernel.entry_point;
+            print!("Kernel loaded at {:#x}, entry point is {:#x}\n", kernel.base, entry_point);
 
             // Set upt the entry point and page table
-            *kernel_entry = Some(pe.entry_point);
+            *kernel_entry = Some(entry_point);
             *page_table = Some(table);
         }
 
@@ -148,29 +467,40 @@ pub extern fn entry(bootloader_end: usize) -> !{
         // At this point the page table is always set up
         let page_table = page_table.as_mut().unwrap();
 
-        // Get a unique stack address for this core
+        // Get a unique stack address for this core, followed by a random gap such that the
+        // stacks of other cores cannot be found from this one
+        let gap = RNG.range(KERNEL_STACK_MAX_GAP / 4096 + 1) * 4096;
         let stack_addr = BOOT_ARGS.stack_vaddr
-            .fetch_add(KERNEL_STACK_SIZE + KERNEL_STACK_PAD, Ordering::SeqCst);
+            .fetch_add(KERNEL_STACK_SIZE + KERNEL_STACK_PAD + gap, Ordering::SeqCst);
 
-        // Map in a stack for every core
+        // Map in a stack for every core. The stack goes at the top of the slot, leaving the
+        // padding below it unmapped as a guard. The padding and gap of the next slot guard the
+        // top.
+        let stack_base = stack_addr + KERNEL_STACK_PAD;
         unsafe {
-            page_table.map(&mut pmem, VirtAddr(stack_addr), PageSize::Page4K, KERNEL_STACK_SIZE, true, true, false).unwrap();
+            page_table.map(&mut pmem, VirtAddr(stack_base), PageSize::Page4K, KERNEL_STACK_SIZE,
+                MapFlags { read: true, write: true, exec: false, cache: CacheType::WriteBack })
+                .unwrap();
         }
 
         (
             *kernel_entry.as_ref().unwrap(),
-            stack_addr + KERNEL_STACK_SIZE,
+            stack_base + KERNEL_STACK_SIZE,
             page_table.table().0 as u32,
         )
     };
 
     extern {
-        fn enter64(entry_point: u64, stack: u64, param: u64, cr3: u32) -> !;
+        fn enter64(entry_point: u64, stack: u64, param: u64, cr3: u32, cr4: u32) -> !;
     }
 
+    // The kernel cannot write to the bootloader image through the identity map, so give it the
+    // boot arguments through the physical window
+    let boot_args = KERNEL_PHYS_WINDOW_BASE + &BOOT_ARGS as *const BootArgs as u64;
+
     // Enter 64-bit long mode and the kernel
     unsafe {
-        enter64(entry_point, stack, &BOOT_ARGS as *const BootArgs as u64, cr3); 
+        enter64(entry_point, stack, boot_args, cr3, protection_cr4());
     }
 }
 
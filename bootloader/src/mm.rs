@@ -136,6 +136,9 @@ pub fn init() {
         }
     }
 
+    // Save off all of the RAM, before we start handing it out
+    *BOOT_ARGS.ram.lock() = Some(free_memory);
+
     // Remove the IVT and BDA being marked as free so we do not overwrite them
     free_memory.remove(Range {
         start: 0,
@@ -1,12 +1,16 @@
-/// Dummy type to implement `core::fmt::Write` for `print` macros
-pub struct SerialWriter;
+/// Dummy type to implement `core::fmt::Write` for `print` macros. Output goes to both the serial
+/// ports and the screen console.
+pub struct Writer;
 use crate::BOOT_ARGS;
 
-impl core::fmt::Write for SerialWriter {
+impl core::fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         if let Some(serial) = BOOT_ARGS.serial.lock().as_mut() {
             serial.write(s.as_bytes());
         }
+        if let Some(console) = BOOT_ARGS.console.lock().as_mut() {
+            console.write(s.as_bytes());
+        }
         Ok(())
     }
 }
@@ -18,7 +22,7 @@ macro_rules! print {
     ($($arg:tt)*) => {{
         let _lock = $crate::BOOT_ARGS.print_lock.lock(); 
         let _ = core::fmt::Write::write_fmt(
-            &mut $crate::print::SerialWriter,
+            &mut $crate::print::Writer,
             format_args!($($arg)*)
         );
     }}
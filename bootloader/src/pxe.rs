@@ -1,279 +1,646 @@
 use core::convert::TryInto;
 use lockcell::LockCell;
 use alloc::vec::Vec;
-use crate::realmode::{invoke_realmode, pxecall, RegisterState};
+use boot_args::{DhcpInfo, Ipv4Addr};
+use crate::realmode::{invoke_realmode, pxecall, segoff_to_linear, RegisterState};
 
 /// A guard to prevent multiple uses of the PXE API at the same time
 static PXE_GUARD: LockCell<()> = LockCell::new(());
 
-/// Convert a 16-bit `seg:off` pointer into a linear address
-fn segoff_to_linear(seg: u16, off: u16) -> usize {
-    ((seg as usize) << 4) + off as usize
+/// Packet sizes to request when opening a file, largest first. 1456 is the largest the PXE spec
+/// allows, as it fits a standard Ethernet frame, but some PXE stacks accept larger ones and let
+/// IP fragment them. 512 is the TFTP default, which every PXE stack supports.
+const TFTP_PACKET_SIZES: [u16; 4] = [8192, 1456, 1024, 512];
+
+/// Largest packet size in `TFTP_PACKET_SIZES`
+const TFTP_MAX_PACKET_SIZE: usize = 8192;
+
+/// Number of times a download is attempted before giving up on transient errors
+const DOWNLOAD_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry of a failed download, doubled for every following retry
+const RETRY_BACKOFF_US: u64 = 100_000;
+
+/// Size of the buffer the cached DHCP ACK is read into, the largest UDP payload which fits in an
+/// Ethernet frame
+const DHCP_MAX_PACKET_SIZE: usize = 1472;
+
+/// Offset of the options in a DHCP packet, after the fixed BOOTP fields and the magic cookie
+const DHCP_OPTIONS_OFFSET: usize = 240;
+
+/// Magic cookie in front of the DHCP options
+const DHCP_MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// DHCP options with no length byte
+const DHCP_OPTION_PAD: u8 = 0;
+const DHCP_OPTION_END: u8 = 255;
+
+/// DHCP options recorded in `DhcpInfo`
+const DHCP_OPTION_SUBNET_MASK: u8 = 1;
+const DHCP_OPTION_ROUTER: u8 = 3;
+const DHCP_OPTION_VENDOR: u8 = 43;
+const DHCP_OPTION_BOOT_FILE: u8 = 67;
+
+/// Offsets of the fixed BOOTP fields in a DHCP packet
+const BOOTP_YIADDR: usize = 0x10;
+const BOOTP_SIADDR: usize = 0x14;
+const BOOTP_GIADDR: usize = 0x18;
+const BOOTP_FILE: usize = 0x6c;
+
+/// Size of the fixed BOOTP fields, up to the magic cookie
+const BOOTP_SIZE: usize = 0xec;
+
+/// PXE API opcodes we use
+const PXENV_TFTP_OPEN: u16 = 0x20;
+const PXENV_TFTP_CLOSE: u16 = 0x21;
+const PXENV_TFTP_READ: u16 = 0x22;
+const PXENV_TFTP_GET_FSIZE: u16 = 0x25;
+const PXENV_GET_CACHED_INFO: u16 = 0x71;
+
+/// Get the name of the PXE API opcode `opcode`, as used in the PXE spec
+fn opcode_name(opcode: u16) -> &'static str {
+    match opcode {
+        PXENV_TFTP_OPEN => "PXENV_TFTP_OPEN",
+        PXENV_TFTP_CLOSE => "PXENV_TFTP_CLOSE",
+        PXENV_TFTP_READ => "PXENV_TFTP_READ",
+        PXENV_TFTP_GET_FSIZE => "PXENV_TFTP_GET_FSIZE",
+        PXENV_GET_CACHED_INFO => "PXENV_GET_CACHED_INFO",
+        _ => "unknown opcode",
+    }
 }
 
-/// Download a file with the `filename` over TFTP with the PXE 16-bit API
-pub fn download<P: AsRef<[u8]>>(filename: P) -> Option<Vec<u8>> {
-    // Lock access to PXE
-    let _guard = PXE_GUARD.lock();
+/// Get the name of the PXENV status code `status`, as used in the PXE spec
+fn status_name(status: u16) -> &'static str {
+    match status {
+        0x00 => "PXENV_STATUS_SUCCESS",
+        0x01 => "PXENV_STATUS_FAILURE",
+        0x02 => "PXENV_STATUS_BAD_FUNC",
+        0x03 => "PXENV_STATUS_UNSUPPORTED",
+        0x04 => "PXENV_STATUS_KEEP_UNDI",
+        0x05 => "PXENV_STATUS_KEEP_ALL",
+        0x06 => "PXENV_STATUS_OUT_OF_RESOURCES",
+        0x11 => "PXENV_STATUS_ARP_TIMEOUT",
+        0x18 => "PXENV_STATUS_UDP_CLOSED",
+        0x19 => "PXENV_STATUS_UDP_OPEN",
+        0x1a => "PXENV_STATUS_TFTP_CLOSED",
+        0x1b => "PXENV_STATUS_TFTP_OPEN",
+        0x20 => "PXENV_STATUS_MCOPY_PROBLEM",
+        0x21 => "PXENV_STATUS_BIS_INTEGRITY_FAILURE",
+        0x22 => "PXENV_STATUS_BIS_VALIDATE_FAILURE",
+        0x23 => "PXENV_STATUS_BIS_INIT_FAILURE",
+        0x24 => "PXENV_STATUS_BIS_SHUTDOWN_FAILURE",
+        0x25 => "PXENV_STATUS_BIS_GBOA_FAILURE",
+        0x26 => "PXENV_STATUS_BIS_FREE_FAILURE",
+        0x27 => "PXENV_STATUS_BIS_GSI_FAILURE",
+        0x28 => "PXENV_STATUS_BIS_BAD_CKSUM",
+        0x30 => "PXENV_STATUS_TFTP_CANNOT_ARP_ADDRESS",
+        0x32 => "PXENV_STATUS_TFTP_OPEN_TIMEOUT",
+        0x33 => "PXENV_STATUS_TFTP_UNKNOWN_OPCODE",
+        0x35 => "PXENV_STATUS_TFTP_READ_TIMEOUT",
+        0x36 => "PXENV_STATUS_TFTP_ERROR_OPCODE",
+        0x38 => "PXENV_STATUS_TFTP_CANNOT_OPEN_CONNECTION",
+        0x39 => "PXENV_STATUS_TFTP_CANNOT_READ_FROM_CONNECTION",
+        0x3a => "PXENV_STATUS_TFTP_TOO_MANY_PACKAGES",
+        0x3b => "PXENV_STATUS_TFTP_FILE_NOT_FOUND",
+        0x3c => "PXENV_STATUS_TFTP_ACCESS_VIOLATION",
+        0x3d => "PXENV_STATUS_TFTP_NO_MCAST_ADDRESS",
+        0x3e => "PXENV_STATUS_TFTP_NO_FILESIZE",
+        0x3f => "PXENV_STATUS_TFTP_INVALID_PACKET_SIZE",
+        0x51 => "PXENV_STATUS_DHCP_TIMEOUT",
+        0x52 => "PXENV_STATUS_DHCP_NO_IP_ADDRESS",
+        0x53 => "PXENV_STATUS_DHCP_NO_BOOTFILE_NAME",
+        0x54 => "PXENV_STATUS_DHCP_BAD_IP_ADDRESS",
+        _ => "unknown status",
+    }
+}
 
-    // Convert the filename to a slice of bytes
-    let filename_bytes: &[u8] = filename.as_ref();
+/// Reasons a PXE download can fail
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PxeError {
+    /// The PXE installation check failed, there is no PXE stack to use
+    NotInstalled,
 
-    // Invoke the PXE installation check with int 0x1a
-    let mut regs = RegisterState::default();
-    regs.eax = 0x5650;
-    unsafe { invoke_realmode(0x1a, &mut regs); }
+    /// The PXENV+ or !PXE structure has a bad signature, length or checksum, or no entry point
+    BadStructure(&'static str),
 
-    if regs.eax != 0x564e || (regs.efl & 1) != 0 {
-        return None;
-    }
+    /// The filename does not fit in a PXE request with its NULL terminator
+    FilenameTooLong(usize),
 
-    // Get the linear address to the PXENV+ structure
-    let pxenv = segoff_to_linear(regs.es, regs.ebx as u16);
-    let pxenv = unsafe {
-        core::slice::from_raw_parts(pxenv as *const u8, 0x2c)
-    };
+    /// The PXE API call `opcode` failed with the PXENV status `status`
+    Call { opcode: u16, status: u16 },
 
-    // Extract the fields we need to validate the structure
-    let signature = &pxenv[..6];
-    let length = pxenv[0x8];
-    // Compute the checksum of the PXENV structure
-    let checksum = pxenv.iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
+    /// The server agreed on a packet size which is not in the range we asked for
+    BadPacketSize(u16),
 
-    // Check the signature and length for sanity
-    if &signature != b"PXENV+" || length != 0x2c || checksum != 0 {
-        return None;
-    }
+    /// The server sent a packet larger than the negotiated packet size
+    PacketTooLarge(usize),
 
-    // Get the pointer to the !PXE structure
-    let off = u16::from_le_bytes(pxenv[0x28..0x2a].try_into().ok()?);
-    let seg = u16::from_le_bytes(pxenv[0x2a..0x2c].try_into().ok()?);
-    let pxe = segoff_to_linear(seg, off);
+    /// The file is larger than the `n` bytes there is room for. This is not transient: the room
+    /// is sized from a manifest, which a file that changed on the server no longer matches, so
+    /// downloading it again cannot help.
+    FileTooLarge(u64),
 
-    let pxe = unsafe {
-        core::slice::from_raw_parts(pxe as *const u8, 0x58)
-    };
+    /// The cached DHCP packet is too short to hold the fields we need, or a field is too long
+    BadDhcpPacket,
+}
 
-    // Extract the fields we need to validate the !PXE structure
-    let signature = &pxe[..4];
-    let length = pxe[4];
-    let checksum = pxe.iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
+impl PxeError {
+    /// Whether the error may go away when trying again, as it comes from a timeout or a lost
+    /// connection rather than from a missing file or a broken PXE stack
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            PxeError::Call { status, .. } => matches!(status,
+                0x01 | 0x11 | 0x30 | 0x32 | 0x35 | 0x38 | 0x39),
+            PxeError::PacketTooLarge(_) => true,
+            _ => false,
+        }
+    }
 
-    if signature != b"!PXE" || length != 0x58 || checksum != 0 {
-        return None;
+    /// Whether the error is the server reporting that the file does not exist
+    pub fn is_file_not_found(&self) -> bool {
+        matches!(*self, PxeError::Call { status: 0x3b, .. })
     }
 
-    // Get the 16-bit PXE API entry point
-    let ep_off = u16::from_le_bytes(pxe[0x10..0x12].try_into().ok()?);
-    let ep_seg = u16::from_le_bytes(pxe[0x12..0x14].try_into().ok()?);
+    /// Whether the error is a timeout waiting for the server or the network. Unlike
+    /// `is_transient`, this excludes the generic `PXENV_STATUS_FAILURE`.
+    pub fn is_timeout(&self) -> bool {
+        matches!(*self, PxeError::Call { status: 0x11 | 0x32 | 0x35, .. })
+    }
+}
 
-    // According to the spec "CS must not be 0000h"
-    if ep_seg == 0 {
-        return None;
+impl core::fmt::Display for PxeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            PxeError::NotInstalled => write!(f, "PXE installation check failed"),
+            PxeError::BadStructure(name) => write!(f, "malformed {} structure", name),
+            PxeError::FilenameTooLong(len) =>
+                write!(f, "filename of {} bytes is too long", len),
+            PxeError::Call { opcode, status } =>
+                write!(f, "{} ({:#x}) failed with {} ({:#x})",
+                    opcode_name(opcode), opcode, status_name(status), status),
+            PxeError::BadPacketSize(size) =>
+                write!(f, "server negotiated an invalid packet size of {} bytes", size),
+            PxeError::PacketTooLarge(size) =>
+                write!(f, "received a {} byte packet, larger than negotiated", size),
+            PxeError::FileTooLarge(size) =>
+                write!(f, "file is larger than the {} bytes there is room for", size),
+            PxeError::BadDhcpPacket => write!(f, "cached DHCP packet is malformed"),
+        }
     }
+}
+
+/// The 16-bit PXE API entry point
+#[derive(Clone, Copy)]
+struct Pxe {
+    seg: u16,
+    off: u16,
+}
 
-    // Determine the server IP from the cached information used during the PXE boot process. We
-    // grab the DHCP ACK packet and extract the server IP field from it.
-    let server_ip: [u8; 4] = {
-        const PXE_OPCODE_GET_CACHED_INFO: u16 = 0x71;
-        const PXENV_PACKET_TYPE_DHCP_ACK: u16 = 2;
-        let mut pkt_buf = [0u8; 128];
-        #[derive(Debug, Default)]
-        #[repr(C)]
-        struct GetCachedInfo {
-            status: u16,
-            packet_type: u16,
-            buffer_size: u16,
-            buffer_off: u16,
-            buffer_seg: u16,
-            buffer_limit: u16,
+impl Pxe {
+    /// Find the PXE API entry point with the installation check, validating the PXENV+ and !PXE
+    /// structures
+    fn find() -> Result<Self, PxeError> {
+        // Invoke the PXE installation check with int 0x1a
+        let mut regs = RegisterState::default();
+        regs.eax = 0x5650;
+        unsafe { invoke_realmode(0x1a, &mut regs); }
+
+        if regs.eax != 0x564e || (regs.efl & 1) != 0 {
+            return Err(PxeError::NotInstalled);
         }
 
-        let mut st = GetCachedInfo::default();
-        st.packet_type = PXENV_PACKET_TYPE_DHCP_ACK;
-        st.buffer_size = 128;
-        st.buffer_seg = 0;
-        st.buffer_off = &mut pkt_buf as *mut _ as u16;
+        // Get the linear address to the PXENV+ structure
+        let pxenv = segoff_to_linear(regs.es, regs.ebx as u16);
+        let pxenv = unsafe {
+            core::slice::from_raw_parts(pxenv as *const u8, 0x2c)
+        };
 
-        unsafe { pxecall(ep_seg, ep_off, PXE_OPCODE_GET_CACHED_INFO, 0, &mut st as *mut _ as u16); }
+        // Extract the fields we need to validate the structure
+        let signature = &pxenv[..6];
+        let length = pxenv[0x8];
+        // Compute the checksum of the PXENV structure
+        let checksum = pxenv.iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
 
-        // Make sure the call was successfule
-        if st.status != 0 {
-            return None;
+        // Check the signature and length for sanity
+        if signature != b"PXENV+" || length != 0x2c || checksum != 0 {
+            return Err(PxeError::BadStructure("PXENV+"));
         }
 
-        pkt_buf[0x14..0x18].try_into().ok()?
-    };
+        // Get the pointer to the !PXE structure
+        let off = u16::from_le_bytes(pxenv[0x28..0x2a].try_into().unwrap());
+        let seg = u16::from_le_bytes(pxenv[0x2a..0x2c].try_into().unwrap());
+        let pxe = segoff_to_linear(seg, off);
+
+        let pxe = unsafe {
+            core::slice::from_raw_parts(pxe as *const u8, 0x58)
+        };
 
-    print!("TFTP Server IP: {}.{}.{}.{}\n",
-        server_ip[0],
-        server_ip[1],
-        server_ip[2],
-        server_ip[3],
-    );    
-
-    // Get the file size for the next stage
-    let file_size = {
-        const PXENV_TFTP_GET_FILE_SIZE: u16 = 0x25;
-
-        #[repr(C, packed)]
-        struct GetFileSize {
-            status: u16,
-            server_ip: [u8; 4],
-            gateway_ip: [u8; 4],
-            filename: [u8; 128],
-            file_size: u32,
+        // Extract the fields we need to validate the !PXE structure
+        let signature = &pxe[..4];
+        let length = pxe[4];
+        let checksum = pxe.iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
+
+        if signature != b"!PXE" || length != 0x58 || checksum != 0 {
+            return Err(PxeError::BadStructure("!PXE"));
         }
 
-        let mut st = GetFileSize {
-            status: 0,
-            server_ip: server_ip,
-            gateway_ip: [0; 4],
-            filename: [0; 128],
-            file_size: 0,
-        };
+        // Get the 16-bit PXE API entry point
+        let off = u16::from_le_bytes(pxe[0x10..0x12].try_into().unwrap());
+        let seg = u16::from_le_bytes(pxe[0x12..0x14].try_into().unwrap());
 
-        // Check to see if we have enough room for the filename and the NULL terminator
-        if filename_bytes.len() + 1 > st.filename.len() {
-            return None;
+        // According to the spec "CS must not be 0000h"
+        if seg == 0 {
+            return Err(PxeError::BadStructure("!PXE"));
         }
 
-        // Copy the file name
-        st.filename[..filename_bytes.len()].copy_from_slice(filename_bytes);
+        Ok(Pxe { seg, off })
+    }
 
+    /// Invoke the PXE API call `opcode` with the parameter structure `param`, which must start
+    /// with the 16-bit status the call fills in, and live in the first 64 KiB of memory
+    fn call<T>(&self, opcode: u16, param: &mut T) -> Result<(), PxeError> {
+        let param = param as *mut T;
         unsafe {
-            pxecall(ep_seg, ep_off, PXENV_TFTP_GET_FILE_SIZE, 0, &mut st as *mut _ as u16);
+            pxecall(self.seg, self.off, opcode, 0, param as u16);
         }
 
-        // Check that the call was successful
-        if st.status != 0 {
-            return None;
+        let status = unsafe { core::ptr::read_unaligned(param as *const u16) };
+        if status != 0 {
+            return Err(PxeError::Call { opcode, status });
         }
 
-        st.file_size as usize
-    };
-    
-    print!("Requested file \"{:?}\" is {} bytes\n",
-        core::str::from_utf8(filename.as_ref()),
-        file_size
-    );
+        Ok(())
+    }
+}
+
+/// Copy `filename` into the NULL terminated filename field of a PXE request
+fn copy_filename(dest: &mut [u8; 128], filename: &[u8]) -> Result<(), PxeError> {
+    // Check to see if we have enough room for the filename and the NULL terminator
+    if filename.len() + 1 > dest.len() {
+        return Err(PxeError::FilenameTooLong(filename.len()));
+    }
 
-    // Open the file
-    {
-        const PXE_OPCODE_TFTP_OPEN: u16 = 0x20;
-        
-        #[repr(C)]
-        struct TftpOpen {
-            status: u16,
-            server_ip: [u8; 4],
-            gateway_ip: [u8; 4],
-            filename: [u8; 128],
-            tftp_port: u16,
-            packet_size: u16,
+    // Copy the file name
+    dest[..filename.len()].copy_from_slice(filename);
+    Ok(())
+}
+
+/// A block of a file being downloaded
+pub struct Block<'a> {
+    /// Offset of the block in the file
+    pub offset: u64,
+
+    /// Contents of the block. The last block of the file is shorter than the others, and may be
+    /// empty.
+    pub data: &'a [u8],
+
+    /// Size of the file, if the server reported it
+    pub file_size: Option<u64>,
+}
+
+/// Download a file with the `filename` over TFTP into a `Vec`. Transient failures, like timeouts,
+/// are retried with an exponential backoff.
+pub fn download<P: AsRef<[u8]>>(filename: P) -> Result<Vec<u8>, PxeError> {
+    let mut download = Vec::new();
+
+    download_with(filename, |block| {
+        if block.offset == 0 {
+            // Start over when the download is retried. Allocate the whole file at once if we know
+            // its size, growing allocations is not handled well by our high-fragmentation heap.
+            download.clear();
+            if let Some(size) = block.file_size {
+                download.reserve_exact(size as usize);
+            }
         }
 
-        let mut st = TftpOpen {
-            status: 0,
-            server_ip: server_ip,
-            gateway_ip: [0; 4],
-            filename: [0; 128],
-            tftp_port: 69_u16.to_be(),
-            packet_size: 512,
+        download.extend_from_slice(block.data);
+        Ok(())
+    })?;
+
+    Ok(download)
+}
+
+/// Download a file with the `filename` over TFTP with the PXE 16-bit API, passing every block to
+/// `block` as it arrives, starting at offset 0 and at least once. Nothing is buffered, so the
+/// file does not have to fit in the heap. An error from `block` aborts the attempt. Transient
+/// failures, like timeouts, are retried with an exponential backoff from the start of the file,
+/// so `block` may see the same offsets again. Returns the size of the file.
+///
+/// PXE is locked while `block` runs, it must not download anything itself.
+pub fn download_with<P, F>(filename: P, mut block: F) -> Result<u64, PxeError>
+        where P: AsRef<[u8]>, F: FnMut(Block) -> Result<(), PxeError> {
+    // Lock access to PXE
+    let _guard = PXE_GUARD.lock();
+
+    // Convert the filename to a slice of bytes
+    let filename_bytes: &[u8] = filename.as_ref();
+    let name = printable(filename_bytes);
+
+    let pxe = Pxe::find()?;
+    let server_ip = server_ip(pxe)?;
+
+    print!("TFTP Server IP: {}\n", Ipv4Addr(server_ip));
+
+    let mut backoff = RETRY_BACKOFF_US;
+    for attempt in 1..=DOWNLOAD_ATTEMPTS {
+        let err = match download_attempt(pxe, server_ip, filename_bytes, &mut block) {
+            Ok(download) => return Ok(download),
+            Err(err) => err,
         };
 
-        // Check to see if we have enough room for the filename and the NULL terminator
-        if filename_bytes.len() + 1 > st.filename.len() {
-            return None;
+        print!("TFTP download of \"{}\" from {} failed (attempt {}/{}): {}\n",
+            name, Ipv4Addr(server_ip), attempt, DOWNLOAD_ATTEMPTS, err);
+
+        if !err.is_transient() || attempt == DOWNLOAD_ATTEMPTS {
+            return Err(err);
         }
 
-        // Copy the file name
-        st.filename[..filename_bytes.len()].copy_from_slice(filename_bytes);
+        print!("Retrying in {} ms\n", backoff / 1000);
+        crate::time::sleep(backoff);
+        backoff *= 2;
+    }
 
-        unsafe {
-            pxecall(ep_seg, ep_off, PXE_OPCODE_TFTP_OPEN, 0, &mut st as *mut _ as u16);
-        }
+    unreachable!();
+}
 
-        // Check that the call was successful
-        if st.status != 0 || st.packet_size != 512 {
-            return None;
+/// Get the DHCP ACK packet from the cached information used during the PXE boot process
+fn cached_dhcp_ack(pxe: Pxe) -> Result<Vec<u8>, PxeError> {
+    const PXENV_PACKET_TYPE_DHCP_ACK: u16 = 2;
+
+    #[derive(Debug, Default)]
+    #[repr(C)]
+    struct GetCachedInfo {
+        status: u16,
+        packet_type: u16,
+        buffer_size: u16,
+        buffer_off: u16,
+        buffer_seg: u16,
+        buffer_limit: u16,
+    }
+
+    // Room for the largest packet which fits in an Ethernet frame. It is passed to PXE as a
+    // segment and offset, so it must be in the first 1 MiB.
+    let mut pkt_buf = [0u8; DHCP_MAX_PACKET_SIZE];
+    let pkt_buf_addr = pkt_buf.as_mut_ptr() as usize;
+    assert!(pkt_buf_addr + pkt_buf.len() <= 0x10_0000, "DHCP buffer not in real mode memory");
+
+    let mut st = GetCachedInfo::default();
+    st.packet_type = PXENV_PACKET_TYPE_DHCP_ACK;
+    st.buffer_size = pkt_buf.len() as u16;
+    st.buffer_off = (pkt_buf_addr & 0xf) as u16;
+    st.buffer_seg = (pkt_buf_addr >> 4) as u16;
+
+    pxe.call(PXENV_GET_CACHED_INFO, &mut st)?;
+
+    let size = core::cmp::min(st.buffer_size as usize, pkt_buf.len());
+    Ok(pkt_buf[..size].to_vec())
+}
+
+/// Get the DHCP ACK packet the PXE stack received when it booted us
+pub fn dhcp_ack() -> Result<Vec<u8>, PxeError> {
+    // Lock access to PXE
+    let _guard = PXE_GUARD.lock();
+
+    cached_dhcp_ack(Pxe::find()?)
+}
+
+/// Find the option `code` in the DHCP packet `packet`, returning its value
+pub fn dhcp_option(packet: &[u8], code: u8) -> Option<&[u8]> {
+    // The options follow the fixed BOOTP fields and the magic cookie
+    if packet.get(DHCP_OPTIONS_OFFSET - 4..DHCP_OPTIONS_OFFSET)? != DHCP_MAGIC_COOKIE {
+        return None;
+    }
+
+    let mut options = &packet[DHCP_OPTIONS_OFFSET..];
+    loop {
+        match *options.first()? {
+            DHCP_OPTION_PAD => options = &options[1..],
+            DHCP_OPTION_END => return None,
+            option => {
+                let len = *options.get(1)? as usize;
+                let value = options.get(2..2 + len)?;
+                if option == code {
+                    return Some(value);
+                }
+                options = &options[2 + len..];
+            }
         }
+    }
+}
 
-        print!("Openend file\n");
+/// Get the addressing the PXE stack got from DHCP out of the cached DHCP ACK
+pub fn dhcp_info() -> Result<DhcpInfo, PxeError> {
+    let packet = dhcp_ack()?;
+    if packet.len() < BOOTP_SIZE {
+        return Err(PxeError::BadDhcpPacket);
     }
 
-    let mut download = alloc::vec::Vec::with_capacity(file_size);
+    let ip = |offset: usize| Ipv4Addr(packet[offset..offset + 4].try_into().unwrap());
+    let option_ip = |code: u8| dhcp_option(&packet, code)
+        .and_then(|value| Some(Ipv4Addr(value.get(..4)?.try_into().unwrap())))
+        .unwrap_or_default();
 
-    // Read the file
-    {
-        const PXE_OPCODE_TFTP_READ: u16 = 0x22;
+    let mut info = DhcpInfo::new();
+    info.client_ip = ip(BOOTP_YIADDR);
+    info.server_ip = ip(BOOTP_SIADDR);
+    info.relay_ip = ip(BOOTP_GIADDR);
+    info.subnet_mask = option_ip(DHCP_OPTION_SUBNET_MASK);
+    info.router = option_ip(DHCP_OPTION_ROUTER);
 
-        #[repr(C)]
-        struct TftpRead {
-            status: u16,
-            packet_number: u16,
-            buffer_size: u16,
-            buffer_off: u16,
-            buffer_seg: u16,
-        }
+    // The boot file option takes precedence over the BOOTP field, both may be NULL terminated
+    let boot_file = dhcp_option(&packet, DHCP_OPTION_BOOT_FILE)
+        .unwrap_or(&packet[BOOTP_FILE..BOOTP_SIZE]);
+    let boot_file = boot_file.split(|&x| x == 0).next().unwrap();
+    info.set_boot_file(boot_file).ok_or(PxeError::BadDhcpPacket)?;
 
-        // Enough room to hold the packet size requested during open, which we use the minimum
-        let mut read_buf = [0u8; 512];
+    // An option is at most 255 bytes, so they always fit
+    info.set_vendor_options(dhcp_option(&packet, DHCP_OPTION_VENDOR).unwrap_or(&[])).unwrap();
 
-        // Create the read request
-        let mut st = TftpRead {
-            status: 0,
-            packet_number: 0,
-            buffer_size: 0,
-            buffer_off: &mut read_buf as *mut _ as u16, 
-            buffer_seg: 0,
-        };
+    Ok(info)
+}
 
-        loop {
-            // Do the request
-            unsafe {
-                pxecall(ep_seg, ep_off, PXE_OPCODE_TFTP_READ, 0, &mut st as *mut _ as u16);
-            }
+/// Determine the server IP from the cached information used during the PXE boot process. We grab
+/// the DHCP ACK packet and extract the server IP field from it.
+fn server_ip(pxe: Pxe) -> Result<[u8; 4], PxeError> {
+    let packet = cached_dhcp_ack(pxe)?;
+    Ok(packet.get(BOOTP_SIADDR..BOOTP_SIADDR + 4).ok_or(PxeError::BadDhcpPacket)?
+        .try_into().unwrap())
+}
 
-            // The number of bytes we read in the request
-            let bytes_read = st.buffer_size as usize;
+/// Get the size of `filename` on the TFTP server at `server_ip`, this needs the server to support
+/// the `tsize` option
+fn tftp_file_size(pxe: Pxe, server_ip: [u8; 4], filename: &[u8]) -> Result<u64, PxeError> {
+    #[repr(C, packed)]
+    struct GetFileSize {
+        status: u16,
+        server_ip: [u8; 4],
+        gateway_ip: [u8; 4],
+        filename: [u8; 128],
+        file_size: u32,
+    }
 
-            if st.status != 0 || bytes_read > read_buf.len() {
-                return None;
-            }
+    let mut st = GetFileSize {
+        status: 0,
+        server_ip,
+        gateway_ip: [0; 4],
+        filename: [0; 128],
+        file_size: 0,
+    };
 
-            // Make suere we don't overflow our allocation. This can happen if the file has changed
-            // since we got the size. We'll just fail here rather than causing re-allocs which are
-            // not handled well with our high-fragmentation bootloader heap.
-            if download.len() + bytes_read > download.capacity() {
-                return None;
-            }
+    copy_filename(&mut st.filename, filename)?;
+    pxe.call(PXENV_TFTP_GET_FSIZE, &mut st)?;
 
-            download.extend_from_slice(&read_buf[..bytes_read]);
+    Ok(st.file_size as u64)
+}
 
-            if bytes_read < read_buf.len() {
-                break;
-            }
+/// Get `filename` as a string to print. PXE takes any bytes as a filename, so this falls back to
+/// a placeholder if it is not UTF-8.
+fn printable(filename: &[u8]) -> &str {
+    core::str::from_utf8(filename).unwrap_or("<non-UTF-8>")
+}
+
+/// Make one attempt at downloading `filename` from the TFTP server at `server_ip`, passing every
+/// block to `block`. The file is closed again if the download fails after opening it.
+fn download_attempt(pxe: Pxe, server_ip: [u8; 4], filename: &[u8],
+        block: &mut dyn FnMut(Block) -> Result<(), PxeError>) -> Result<u64, PxeError> {
+    // The size is only informational, so carry on without it if the server does not support
+    // `tsize`. The file not existing or the server not answering is reported right away though.
+    // Many PXE stacks report a rejected `tsize` as the generic `PXENV_STATUS_FAILURE`, so only a
+    // timeout counts as the server not answering.
+    let file_size = match tftp_file_size(pxe, server_ip, filename) {
+        Ok(size) => {
+            print!("Requested file \"{}\" is {} bytes\n", printable(filename), size);
+            Some(size)
         }
+        Err(err) if err.is_timeout() || err.is_file_not_found() => return Err(err),
+        Err(err) => {
+            print!("Size of \"{}\" is unknown: {}\n", printable(filename), err);
+            None
+        }
+    };
+
+    // Open the file with the largest packet size the PXE stack and the server agree on. If every
+    // size fails, report the error from the TFTP default, which is the most telling.
+    let start = cpu::rdtsc();
+    let mut result = Err(PxeError::BadPacketSize(0));
+    for &size in TFTP_PACKET_SIZES.iter() {
+        result = tftp_open(pxe, server_ip, filename, size);
+        if result.is_ok() {
+            break;
+        }
+    }
+    let packet_size = result? as usize;
+
+    print!("Opened file with {}-byte packets\n", packet_size);
+
+    let size = tftp_read(pxe, file_size, packet_size, block);
+
+    // Always close the file, but report a read error over a close error
+    let closed = tftp_close(pxe);
+    let size = size?;
+    closed?;
+
+    // Report the throughput, to spot a PXE stack or server which would not negotiate large packets
+    let elapsed_us = core::cmp::max(crate::time::elapsed_us(start), 1);
+    print!("Downloaded {} bytes in {} ms ({} KiB/s)\n",
+        size,
+        elapsed_us / 1000,
+        size * 1_000_000 / 1024 / elapsed_us,
+    );
+
+    Ok(size)
+}
+
+/// Open `filename` on the TFTP server at `server_ip`, requesting `packet_size` byte packets.
+/// Returns the packet size negotiated with the server.
+fn tftp_open(pxe: Pxe, server_ip: [u8; 4], filename: &[u8], packet_size: u16)
+        -> Result<u16, PxeError> {
+    #[repr(C)]
+    struct TftpOpen {
+        status: u16,
+        server_ip: [u8; 4],
+        gateway_ip: [u8; 4],
+        filename: [u8; 128],
+        tftp_port: u16,
+        packet_size: u16,
+    }
+
+    let mut st = TftpOpen {
+        status: 0,
+        server_ip,
+        gateway_ip: [0; 4],
+        filename: [0; 128],
+        tftp_port: 69_u16.to_be(),
+        packet_size,
+    };
+
+    copy_filename(&mut st.filename, filename)?;
+    pxe.call(PXENV_TFTP_OPEN, &mut st)?;
+
+    // The PXE stack reports the size which was negotiated with the server, which may be smaller
+    // than the one requested, but never larger or smaller than the TFTP default
+    if st.packet_size < 512 || st.packet_size > packet_size {
+        let _ = tftp_close(pxe);
+        return Err(PxeError::BadPacketSize(st.packet_size));
     }
 
-    print!("Downloaded {} bytes\n", download.len());
+    Ok(st.packet_size)
+}
+
+/// Read the open file, which was reported to be `file_size` bytes, in `packet_size` byte packets,
+/// passing every packet to `block`. Returns the size of the file.
+fn tftp_read(pxe: Pxe, file_size: Option<u64>, packet_size: usize,
+        block: &mut dyn FnMut(Block) -> Result<(), PxeError>) -> Result<u64, PxeError> {
+    #[repr(C)]
+    struct TftpRead {
+        status: u16,
+        packet_number: u16,
+        buffer_size: u16,
+        buffer_off: u16,
+        buffer_seg: u16,
+    }
 
-    // Close file
-    {
-        const PXE_OPCODE_TFTP_CLOSE: u16 = 0x21;
-        let mut status: u16 = 0;
+    // Enough room to hold the largest packet size we may have negotiated during open. It is passed
+    // to PXE as a segment and offset, so it must be in the first 1 MiB.
+    let mut read_buf = [0u8; TFTP_MAX_PACKET_SIZE];
+    let read_buf_addr = read_buf.as_mut_ptr() as usize;
+    assert!(read_buf_addr + read_buf.len() <= 0x10_0000, "TFTP buffer not in real mode memory");
+
+    // Create the read request
+    let mut st = TftpRead {
+        status: 0,
+        packet_number: 0,
+        buffer_size: 0,
+        buffer_off: (read_buf_addr & 0xf) as u16,
+        buffer_seg: (read_buf_addr >> 4) as u16,
+    };
 
+    let mut offset = 0;
+    loop {
         // Do the request
-        unsafe {
-            pxecall(ep_seg, ep_off, PXE_OPCODE_TFTP_CLOSE, 0, &mut status as *mut _ as u16);
-        }
+        pxe.call(PXENV_TFTP_READ, &mut st)?;
 
-        if status != 0 {
-            return None;
+        // The number of bytes we read in the request
+        let bytes_read = st.buffer_size as usize;
+
+        if bytes_read > packet_size {
+            return Err(PxeError::PacketTooLarge(bytes_read));
         }
 
-        print!("Closed file\n");
+        block(Block { offset, data: &read_buf[..bytes_read], file_size })?;
+        offset += bytes_read as u64;
+
+        // A short packet marks the end of the file
+        if bytes_read < packet_size {
+            break;
+        }
     }
 
-    Some(download)
+    Ok(offset)
+}
+
+/// Close the open TFTP file
+fn tftp_close(pxe: Pxe) -> Result<(), PxeError> {
+    let mut status: u16 = 0;
+    pxe.call(PXENV_TFTP_CLOSE, &mut status)
 }
@@ -24,3 +24,8 @@ extern {
     pub fn invoke_realmode(int_number: u8, regs: *mut RegisterState);
     pub fn pxecall(seg: u16, off: u16, pxe_call: u16, param_seg: u16, param_off: u16);
 }
+
+/// Convert a 16-bit `seg:off` pointer into a linear address
+pub fn segoff_to_linear(seg: u16, off: u16) -> usize {
+    ((seg as usize) << 4) + off as usize
+}
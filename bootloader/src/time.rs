@@ -0,0 +1,62 @@
+//! Time keeping with the TSC, calibrated against the PIT on the BSP
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Frequency of the PIT input clock in Hz
+const PIT_HZ: u64 = 1_193_182;
+
+/// Length of the TSC calibration in milliseconds
+const CALIBRATION_MS: u64 = 10;
+
+/// Number of TSC ticks per microsecond, zero until `calibrate` has run
+static TSC_MHZ: AtomicU64 = AtomicU64::new(0);
+
+/// Measure the TSC frequency by counting TSC ticks over a one shot countdown of PIT channel 2.
+/// Channel 2 is the speaker channel, its output can be polled without interrupts. Must only be
+/// called on the BSP, before other cores are running.
+pub fn calibrate() -> u64 {
+    let count = PIT_HZ * CALIBRATION_MS / 1000;
+
+    let ticks = unsafe {
+        // Enable the channel 2 gate, with the speaker disconnected
+        let ctrl = cpu::in8(0x61);
+        cpu::out8(0x61, (ctrl & !0x02) | 0x01);
+
+        // Channel 2, low byte then high byte, mode 0 (interrupt on terminal count), binary
+        cpu::out8(0x43, 0xb0);
+        cpu::out8(0x42, count as u8);
+        cpu::out8(0x42, (count >> 8) as u8);
+
+        // Wait for the output to go high, which happens when the count reaches zero
+        let start = cpu::rdtsc();
+        while cpu::in8(0x61) & 0x20 == 0 {}
+        let end = cpu::rdtsc();
+
+        cpu::out8(0x61, ctrl);
+        end - start
+    };
+
+    let mhz = core::cmp::max(ticks / (CALIBRATION_MS * 1000), 1);
+    TSC_MHZ.store(mhz, Ordering::SeqCst);
+    mhz
+}
+
+/// Number of TSC ticks per microsecond
+pub fn tsc_mhz() -> u64 {
+    let mhz = TSC_MHZ.load(Ordering::SeqCst);
+    assert!(mhz != 0, "TSC not calibrated yet");
+    mhz
+}
+
+/// Number of microseconds elapsed since the TSC read `start`
+pub fn elapsed_us(start: u64) -> u64 {
+    cpu::rdtsc().wrapping_sub(start) / tsc_mhz()
+}
+
+/// Busy wait for `us` microseconds
+pub fn sleep(us: u64) {
+    let start = cpu::rdtsc();
+    while elapsed_us(start) < us {
+        cpu::pause();
+    }
+}
@@ -0,0 +1,116 @@
+//! The kernel command line from `BootArgs`, a whitespace separated list of `key=value` flags:
+//!
+//! * `log=<error|warn|info|debug>` - the most verbose messages to print, `info` by default
+//! * `cores=<n>` - the number of cores to bring online, including the BSP, all of them by default
+//! * `selftest=<all|none|name,...>` - the selftests to run once `cores` cores are online, `none`
+//!   by default
+//! * `exit=<halt|qemu>` - how to report the exit status, after the selftests or a panic, `qemu`
+//!   reports it to QEMU's `isa-debug-exit` device, `halt` by default
+//!
+//! Unknown and malformed flags are warned about and ignored, such that a typo does not keep the
+//! kernel from booting.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use boot_args::{BootArgs, CommandLine};
+use lockcell::LockCell;
+
+/// Importance of a log message, from most to least important
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    /// All levels, indexed by their value
+    const LEVELS: [LogLevel; 4] = [LogLevel::Error, LogLevel::Warn, LogLevel::Info,
+        LogLevel::Debug];
+
+    /// Get the level named `name` on the command line
+    fn from_name(name: &str) -> Option<Self> {
+        Self::LEVELS.iter().copied().find(|level| level.name() == name)
+    }
+
+    /// Name of the level on the command line
+    fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        }
+    }
+}
+
+/// The most verbose level of messages to print, as a `LogLevel`
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+/// Maximum number of cores to bring online
+static MAX_CORES: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Whether to report the exit status to QEMU
+static QEMU_EXIT: AtomicBool = AtomicBool::new(false);
+
+/// Comma separated names of the selftests to run, the value of the `selftest` flag
+static SELFTESTS: LockCell<CommandLine> = LockCell::new(CommandLine::new());
+
+/// Parse the command line passed by the bootloader. Must be called on the BSP, before the other
+/// cores are brought up.
+pub fn init(boot_args: &BootArgs) {
+    let cmdline = *boot_args.cmdline.lock();
+
+    for flag in cmdline.as_str().split_whitespace() {
+        let (key, value) = match flag.split_once('=') {
+            Some(key_value) => key_value,
+            None => {
+                log!(Warn, "WARNING: Ignoring command line flag \"{}\" without a value\n", flag);
+                continue;
+            }
+        };
+
+        match key {
+            "log" => match LogLevel::from_name(value) {
+                Some(level) => LOG_LEVEL.store(level as u8, Ordering::SeqCst),
+                None => log!(Warn, "WARNING: Unknown log level \"{}\"\n", value),
+            },
+            "cores" => match value.parse::<usize>() {
+                Ok(cores) if cores > 0 => MAX_CORES.store(cores, Ordering::SeqCst),
+                _ => log!(Warn, "WARNING: Invalid core count \"{}\"\n", value),
+            },
+            "exit" => match value {
+                "halt" => QEMU_EXIT.store(false, Ordering::SeqCst),
+                "qemu" => QEMU_EXIT.store(true, Ordering::SeqCst),
+                _ => log!(Warn, "WARNING: Unknown exit method \"{}\"\n", value),
+            },
+            "selftest" => {
+                // The value is part of the command line, so it always fits
+                SELFTESTS.lock().set(value).unwrap();
+            }
+            _ => log!(Warn, "WARNING: Ignoring unknown command line flag \"{}\"\n", flag),
+        }
+    }
+
+    log!(Info, "Kernel command line: \"{}\"\n", cmdline.as_str());
+}
+
+/// The most verbose level of messages to print
+pub fn log_level() -> LogLevel {
+    LogLevel::LEVELS[LOG_LEVEL.load(Ordering::Relaxed) as usize]
+}
+
+/// Maximum number of cores to bring online, including the BSP
+pub fn max_cores() -> usize {
+    MAX_CORES.load(Ordering::SeqCst)
+}
+
+/// Whether to report the exit status to QEMU
+pub fn qemu_exit() -> bool {
+    QEMU_EXIT.load(Ordering::SeqCst)
+}
+
+/// Whether the selftest `name` was selected to run
+pub fn selftest_enabled(name: &str) -> bool {
+    SELFTESTS.lock().as_str().split(',').any(|test| test == "all" || test == name)
+}
@@ -2,8 +2,8 @@
 
 use core::sync::atomic::{AtomicUsize, Ordering};
 use boot_args::BootArgs;
-
-const _GS_BASE: u64 = 0xC000_0101;
+use rng::Rng;
+use crate::fpu::{self, ExtendedState, EXTENDED_STATE_ALIGN};
 
 /// A counter of all cores online
 static CORES_ONLINE: AtomicUsize = AtomicUsize::new(0);
@@ -21,6 +21,12 @@ pub struct CoreLocals {
 
     /// A reference to the bootloader arguments.
     pub boot_args: &'static BootArgs,
+
+    /// Area to save the FPU, SSE and AVX state of this core in
+    pub extended_state: ExtendedState,
+
+    /// Random number generator for this core, seeded with hardware entropy
+    pub rng: Rng,
 }
 
 /// Empty marker trait that requires `Sync`, such that we can compile-time assert that `CoreLocals`
@@ -60,11 +66,23 @@ pub fn init(boot_args: &'static BootArgs) {
         core::mem::align_of::<CoreLocals>() as u64,
     ).unwrap();
 
+    // Enable the FPU and vector extensions, and allocate room to save their state
+    let extended_state_size = fpu::init();
+    let extended_state_ptr = pmem.allocate(
+        extended_state_size as u64,
+        EXTENDED_STATE_ALIGN as u64,
+    ).unwrap();
+    let extended_state = unsafe {
+        ExtendedState::new(extended_state_ptr, extended_state_size)
+    };
+
     // Construct the core locals
     let core_locals = CoreLocals {
         address: core_locals_ptr,
         id: CORES_ONLINE.fetch_add(1, Ordering::SeqCst), 
         boot_args: boot_args,
+        extended_state,
+        rng: Rng::from_entropy(),
     };
 
     unsafe { 
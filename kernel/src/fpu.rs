@@ -0,0 +1,100 @@
+//! FPU, SSE and AVX initialization, and saving and restoring of the extended register state
+
+/// Default MXCSR, all SSE exceptions masked and round to nearest
+const MXCSR_DEFAULT: u32 = 0x1f80;
+
+/// Size of the legacy `fxsave` area
+const FXSAVE_SIZE: usize = 512;
+
+/// Alignment required by `xsave`, `fxsave` only needs 16
+pub const EXTENDED_STATE_ALIGN: usize = 64;
+
+/// Enable the FPU, SSE and, if the CPU supports them, `xsave`, AVX and AVX-512 on the current core.
+/// Returns the number of bytes needed to save the extended state of this core.
+pub fn init() -> usize {
+    let features = cpu::features();
+    assert!(features.feature.fpu && features.feature.fxsr && features.feature.sse2,
+        "CPU does not support the FPU, fxsave or SSE2");
+
+    unsafe {
+        // Use the FPU natively and report x87 errors as exceptions
+        let cr0 = cpu::read_cr0();
+        cpu::write_cr0((cr0 | cpu::CR0_MP | cpu::CR0_NE) & !(cpu::CR0_EM | cpu::CR0_TS));
+
+        let mut cr4 = cpu::read_cr4() | cpu::CR4_OSFXSR | cpu::CR4_OSXMMEXCPT;
+        if features.feature.xsave {
+            cr4 |= cpu::CR4_OSXSAVE;
+        }
+        cpu::write_cr4(cr4);
+
+        cpu::fninit();
+        cpu::ldmxcsr(MXCSR_DEFAULT);
+
+        if !features.feature.xsave {
+            return FXSAVE_SIZE;
+        }
+
+        // Enable every state component we know how to use which the CPU supports
+        let leaf = cpu::cpuid(0xd, 0);
+        let supported = leaf.eax as u64 | ((leaf.edx as u64) << 32);
+
+        let mut xcr0 = cpu::XCR0_X87 | cpu::XCR0_SSE;
+        if features.feature.avx {
+            xcr0 |= cpu::XCR0_AVX;
+        }
+        if features.feature.avx512f {
+            xcr0 |= cpu::XCR0_OPMASK | cpu::XCR0_ZMM_HI256 | cpu::XCR0_HI16_ZMM;
+        }
+        cpu::xsetbv(0, xcr0 & supported);
+
+        // Now that XCR0 is set, EBX reports the size of the area for the enabled components
+        cpu::cpuid(0xd, 0).ebx as usize
+    }
+}
+
+/// Area to save the extended register state of a core in, with `xsave` if the CPU supports it,
+/// or `fxsave` otherwise
+pub struct ExtendedState {
+    /// Address of the area, aligned to `EXTENDED_STATE_ALIGN`
+    address: usize,
+
+    /// Whether to use `xsave` rather than `fxsave`
+    xsave: bool,
+}
+
+impl ExtendedState {
+    /// Create an extended state area at `address`, of the size returned by `init`. The state of
+    /// the current core, as left by `init`, is saved to it such that restoring it before anything
+    /// else is saved gives a clean state.
+    pub unsafe fn new(address: usize, size: usize) -> Self {
+        assert!(address.is_multiple_of(EXTENDED_STATE_ALIGN), "Unaligned extended state area");
+
+        // `xsave` reads the header of the area, so it must start out zeroed
+        core::ptr::write_bytes(address as *mut u8, 0, size);
+
+        let state = ExtendedState {
+            address,
+            xsave: cpu::features().feature.xsave,
+        };
+        state.save();
+        state
+    }
+
+    /// Save the extended state of the current core to the area
+    pub unsafe fn save(&self) {
+        if self.xsave {
+            cpu::xsave(self.address as *mut u8, !0);
+        } else {
+            cpu::fxsave(self.address as *mut u8);
+        }
+    }
+
+    /// Restore the extended state of the current core from the area
+    pub unsafe fn restore(&self) {
+        if self.xsave {
+            cpu::xrstor(self.address as *const u8, !0);
+        } else {
+            cpu::fxrstor(self.address as *const u8);
+        }
+    }
+}
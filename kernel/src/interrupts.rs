@@ -0,0 +1,231 @@
+//! Exception handling. Every core gets an IDT with handlers for the 32 architectural exceptions.
+//! A #GP raised by an MSR probe is recovered from, everything else is fatal.
+
+use core::arch::global_asm;
+use boot_args::BootArgs;
+use cpu::DescriptorTablePointer;
+
+/// Number of architectural exception vectors
+const NUM_EXCEPTIONS: usize = 32;
+
+/// General protection fault vector
+const GP_VECTOR: u64 = 13;
+
+/// Code selector of the 64-bit GDT set up by the bootloader
+const KERNEL_CS: u16 = 0x08;
+
+/// A 64-bit interrupt gate descriptor
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct IdtEntry {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    type_attr: u8,
+    offset_mid: u16,
+    offset_high: u32,
+    reserved: u32,
+}
+
+impl IdtEntry {
+    /// Create a present, ring 0 interrupt gate to `handler`
+    fn new(handler: u64) -> Self {
+        IdtEntry {
+            offset_low: handler as u16,
+            selector: KERNEL_CS,
+            ist: 0,
+            type_attr: 0x8e,
+            offset_mid: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            reserved: 0,
+        }
+    }
+}
+
+/// State saved by the CPU and the exception stubs, as laid out on the stack
+#[repr(C)]
+#[derive(Debug)]
+pub struct InterruptFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    /// Exception vector, pushed by the stub
+    pub vector: u64,
+
+    /// Error code pushed by the CPU, or zero for exceptions without one
+    pub error_code: u64,
+
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Size every exception stub is padded to, the stub for a vector is at
+/// `exception_stubs + vector * STUB_SIZE`
+const STUB_SIZE: usize = 16;
+
+// The stubs push a dummy error code for exceptions without one, such that the frame is always the
+// same. The handler is called with the Windows calling convention, like the kernel entry point,
+// so it is the same regardless of the target we are built for. Only the general purpose registers
+// are saved on the stack, the extended state is saved to the core's area around the handler.
+global_asm!(r#"
+    .macro exception_stub vector, has_error
+        .balign {stub_size}
+        .if \has_error == 0
+        push 0
+        .endif
+        push \vector
+        jmp exception_common
+    .endm
+
+    exception_common:
+        push rax
+        push rbx
+        push rcx
+        push rdx
+        push rsi
+        push rdi
+        push rbp
+        push r8
+        push r9
+        push r10
+        push r11
+        push r12
+        push r13
+        push r14
+        push r15
+
+        mov rbp, rsp
+        and rsp, -16
+        sub rsp, 0x20
+        call {save_extended_state}
+        mov rcx, rbp
+        call {handler}
+        call {restore_extended_state}
+        mov rsp, rbp
+
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop r11
+        pop r10
+        pop r9
+        pop r8
+        pop rbp
+        pop rdi
+        pop rsi
+        pop rdx
+        pop rcx
+        pop rbx
+        pop rax
+
+        add rsp, 0x10
+        iretq
+
+    .balign {stub_size}
+    .global exception_stubs
+    exception_stubs:
+    exception_stub 0, 0
+    exception_stub 1, 0
+    exception_stub 2, 0
+    exception_stub 3, 0
+    exception_stub 4, 0
+    exception_stub 5, 0
+    exception_stub 6, 0
+    exception_stub 7, 0
+    exception_stub 8, 1
+    exception_stub 9, 0
+    exception_stub 10, 1
+    exception_stub 11, 1
+    exception_stub 12, 1
+    exception_stub 13, 1
+    exception_stub 14, 1
+    exception_stub 15, 0
+    exception_stub 16, 0
+    exception_stub 17, 1
+    exception_stub 18, 0
+    exception_stub 19, 0
+    exception_stub 20, 0
+    exception_stub 21, 1
+    exception_stub 22, 0
+    exception_stub 23, 0
+    exception_stub 24, 0
+    exception_stub 25, 0
+    exception_stub 26, 0
+    exception_stub 27, 0
+    exception_stub 28, 0
+    exception_stub 29, 1
+    exception_stub 30, 1
+    exception_stub 31, 0
+"#, handler = sym handle_exception, save_extended_state = sym save_extended_state,
+    restore_extended_state = sym restore_extended_state, stub_size = const STUB_SIZE);
+
+extern "C" {
+    /// Start of the exception stubs
+    fn exception_stubs();
+}
+
+/// Save the FPU, SSE and AVX state of the interrupted code to the core's area, such that the handler
+/// is free to use them. Exceptions do not nest: the only one we return from is a #GP raised by an
+/// MSR probe, and handlers do not probe MSRs, so one area per core is enough.
+extern "win64" fn save_extended_state() {
+    unsafe { core!().extended_state.save(); }
+}
+
+/// Restore the state saved by `save_extended_state` before returning to the interrupted code
+extern "win64" fn restore_extended_state() {
+    unsafe { core!().extended_state.restore(); }
+}
+
+/// Common handler for all exceptions
+extern "win64" fn handle_exception(frame: &mut InterruptFrame) {
+    // A faulting MSR probe resumes past the faulting instruction, and the probe reports the fault
+    if frame.vector == GP_VECTOR {
+        if let Some(resume) = cpu::msr::fixup_gp(frame.rip as usize) {
+            frame.rip = resume as u64;
+            return;
+        }
+    }
+
+    panic!("Unhandled exception {} at {:#x} | error code {:#x}\n{:#x?}",
+        frame.vector, frame.rip, frame.error_code, frame);
+}
+
+/// Set up and load the IDT for the current core
+pub fn init(boot_args: &BootArgs) {
+    // Get access to the physical memory allocator
+    let mut pmem = boot_args.free_memory.lock();
+    let pmem = pmem.as_mut().unwrap();
+
+    // Allocate the IDT, it lives for as long as the core
+    let size = core::mem::size_of::<[IdtEntry; NUM_EXCEPTIONS]>();
+    let idt = pmem.allocate(size as u64, 16).unwrap() as *mut IdtEntry;
+
+    unsafe {
+        for vector in 0..NUM_EXCEPTIONS {
+            let handler = exception_stubs as *const () as usize + vector * STUB_SIZE;
+            core::ptr::write(idt.add(vector), IdtEntry::new(handler as u64));
+        }
+
+        cpu::lidt(&DescriptorTablePointer {
+            limit: size as u16 - 1,
+            base: idt as usize,
+        });
+    }
+}
@@ -6,40 +6,113 @@
 extern crate core_reqs;
 #[macro_use] mod core_locals;
 #[macro_use] mod print;
+mod cmdline;
+mod fpu;
+mod memtype;
+mod mmio;
 mod panic;
+mod interrupts;
+mod protections;
+mod qemu;
+mod selftest;
 
+use core::sync::atomic::{AtomicUsize, Ordering};
 use boot_args::BootArgs;
+use cpu::msr::ApicBase;
+use lockcell::LockCell;
+use mmio::Mmio;
+
+/// Physical address of the local APIC registers
+const APIC_BASE: u64 = 0xfee0_0000;
+
+/// Offset of the low half of the interrupt command register in the local APIC
+const APIC_ICR_LOW: usize = 0x300;
+
+/// Number of cores which finished booting
+static CORES_BOOTED: AtomicUsize = AtomicUsize::new(0);
+
+/// Mapping of the local APIC registers, made on first use
+static APIC: LockCell<Option<Mmio>> = LockCell::new(None);
+
+/// Get the mapping of the local APIC registers. Every core sees its own local APIC at the same
+/// physical address, so all cores share one mapping. Returns `None` if it cannot be mapped.
+pub fn apic() -> Option<Mmio> {
+    let mut apic = APIC.lock();
+    if apic.is_none() {
+        *apic = Mmio::map(core!().boot_args, APIC_BASE, 4096);
+    }
+    *apic
+}
 
 /// Release the early boot stack such that other cores can use it by marking it as available
 fn release_early_stack() {
-    use core::sync::atomic::{AtomicU8, Ordering};
+    use core::sync::atomic::AtomicU8;
     unsafe {
         (*(0x7e00 as *const AtomicU8)).store(1, Ordering::SeqCst);
     }
 }
 
+/// Kernel entry point, called by the bootloader. The bootloader passes the argument in `rcx`, so
+/// use the Windows calling convention regardless of the target we are built for.
 #[no_mangle]
-pub extern fn entry(boot_args: &'static BootArgs) -> ! {
+pub extern "win64" fn entry(boot_args: &'static BootArgs) -> ! {
     // Release the early boot stack, now that we have our own stack
     release_early_stack();
 
     // Initialize the corelocals
     core_locals::init(boot_args);
 
+    if cpu::is_bsp() {
+        // Parse the command line first, it controls what the rest of the boot does
+        cmdline::init(boot_args);
+    } else if core!().id >= cmdline::max_cores() {
+        // Park the cores the command line does not want online
+        cpu::halt();
+    }
+
+    // Install the exception handlers
+    interrupts::init(boot_args);
+
+    // Program the memory types page mappings use
+    memtype::init();
+
+    // Enforce W^X and enable SMEP and SMAP on this core
+    protections::init(boot_args);
+
     if cpu::is_bsp() { 
         // One-time initialization for the whole kernel and all the cores
 
-        // Bring up all other cores
-        unsafe {
-            cpu::wrmsr(0x1b, 0xfee0_0000 | (1 << 11) |
-                ((cpu::is_bsp() as u64) << 8));
-            core::ptr::write_volatile(0xfee0_0300 as *mut u32, 0xc4500);
-            core::ptr::write_volatile(0xfee0_0300 as *mut u32, 0xc4608);
-            core::ptr::write_volatile(0xfee0_0300 as *mut u32, 0xc4608);
+        for module in boot_args.modules.lock().iter() {
+            log!(Info, "Module {} at {:#x} | {} bytes\n", module.name(), module.kernel_vaddr(),
+                module.size);
+        }
+
+        if let Some(dhcp) = boot_args.dhcp.lock().as_ref() {
+            log!(Info, "Network: IP {} | mask {} | router {} | server {}\n", dhcp.client_ip,
+                dhcp.subnet_mask, dhcp.router, dhcp.server_ip);
         }
+
+        // Bring up all other cores
+        let mut apic_base = ApicBase::default();
+        apic_base.set_base(APIC_BASE);
+        apic_base.set_enabled(true);
+        apic_base.set_bsp(cpu::is_bsp());
+        unsafe { apic_base.write(); }
+
+        let apic = apic().expect("Failed to map the APIC");
+        apic.write32(APIC_ICR_LOW, 0xc4500);
+        apic.write32(APIC_ICR_LOW, 0xc4608);
+        apic.write32(APIC_ICR_LOW, 0xc4608);
     }
 
     print!("Core ID {} online!\n", core!().id);
 
+    // The last core the command line asked for finishes the boot. Without a `cores` flag we do not
+    // know how many cores there are, so the boot never finishes.
+    if CORES_BOOTED.fetch_add(1, Ordering::SeqCst) + 1 == cmdline::max_cores() {
+        print!("All {} cores online!\n", cmdline::max_cores());
+        qemu::exit(if selftest::run() { qemu::EXIT_SUCCESS } else { qemu::EXIT_SELFTEST_FAILED });
+    }
+
     cpu::halt();
 }
@@ -0,0 +1,119 @@
+//! Memory types. The PAT is programmed such that the cache types page mappings are created with
+//! mean what `page_table` expects, and the MTRRs set up by the firmware are inspected to make sure
+//! they do not cache device memory.
+
+use cpu::msr::{self, Msr, Pat, MtrrCap, MtrrDefType};
+use page_table::{CacheType, PAT};
+
+/// Fixed range MTRRs, with the address of the first byte they cover and the size each of their
+/// eight entries covers
+const FIXED_MTRRS: [(Msr, u64, u64); 11] = [
+    (msr::IA32_MTRR_FIX64K_00000, 0x0_0000, 0x1_0000),
+    (msr::IA32_MTRR_FIX16K_80000, 0x8_0000, 0x4000),
+    (msr::IA32_MTRR_FIX16K_A0000, 0xa_0000, 0x4000),
+    (msr::IA32_MTRR_FIX4K_C0000, 0xc_0000, 0x1000),
+    (msr::IA32_MTRR_FIX4K_C8000, 0xc_8000, 0x1000),
+    (msr::IA32_MTRR_FIX4K_D0000, 0xd_0000, 0x1000),
+    (msr::IA32_MTRR_FIX4K_D8000, 0xd_8000, 0x1000),
+    (msr::IA32_MTRR_FIX4K_E0000, 0xe_0000, 0x1000),
+    (msr::IA32_MTRR_FIX4K_E8000, 0xe_8000, 0x1000),
+    (msr::IA32_MTRR_FIX4K_F0000, 0xf_0000, 0x1000),
+    (msr::IA32_MTRR_FIX4K_F8000, 0xf_8000, 0x1000),
+];
+
+/// Valid bit of a variable range MTRR mask
+const MTRR_MASK_VALID: u64 = 1 << 11;
+
+/// Program the PAT of the current core, and on the BSP check the MTRRs
+pub fn init() {
+    assert!(cpu::features().feature.pat, "CPU does not support the PAT");
+
+    // The caches must be flushed around changing the PAT, such that no line is cached with the
+    // old type, and the TLBs too, as they hold the memory types of the pages
+    unsafe {
+        let cr0 = cpu::read_cr0();
+        cpu::write_cr0(cr0 | cpu::CR0_CD);
+        cpu::wbinvd();
+
+        Pat(PAT).write();
+
+        cpu::wbinvd();
+        cpu::write_cr3(cpu::read_cr3());
+        cpu::write_cr0(cr0);
+    }
+
+    if cpu::is_bsp() {
+        check_mtrrs();
+    }
+}
+
+/// Get the memory type the MTRRs give the physical address `paddr`. Returns `None` if the CPU has
+/// no MTRRs, or the type is one we do not know.
+pub fn mtrr_type(paddr: u64) -> Option<CacheType> {
+    if !cpu::features().feature.mtrr {
+        return None;
+    }
+
+    let (cap, def_type) = unsafe { (MtrrCap::read(), MtrrDefType::read()) };
+    if !def_type.enabled() {
+        // With the MTRRs disabled all memory is uncacheable
+        return Some(CacheType::Uncacheable);
+    }
+
+    // The fixed range MTRRs take priority over the variable ones below 1 MiB
+    if paddr < 0x10_0000 && cap.fixed() && def_type.fixed_enabled() {
+        for &(msr, start, size) in FIXED_MTRRS.iter() {
+            if paddr >= start && paddr < start + size * 8 {
+                let types = unsafe { msr.read() };
+                let index = (paddr - start) / size;
+                return CacheType::from_memory_type((types >> (index * 8)) as u8);
+            }
+        }
+    }
+
+    // Find the types of all the variable ranges covering the address. If they overlap UC wins,
+    // and WT wins over WB.
+    let max_phys_mask = (1u64 << cpu::features().max_phys_addr_bits) - 1;
+    let mut found = None;
+    for index in 0..cap.variable_count() {
+        let (base, mask) = unsafe {
+            (Msr::mtrr_physbase(index).read(), Msr::mtrr_physmask(index).read())
+        };
+        if mask & MTRR_MASK_VALID == 0 {
+            continue;
+        }
+
+        let mask = mask & max_phys_mask & !0xfff;
+        if paddr & mask != base & mask {
+            continue;
+        }
+
+        let typ = CacheType::from_memory_type(base as u8)?;
+        found = Some(match (found, typ) {
+            (_, CacheType::Uncacheable) | (Some(CacheType::Uncacheable), _) =>
+                CacheType::Uncacheable,
+            (_, CacheType::WriteThrough) | (Some(CacheType::WriteThrough), _) =>
+                CacheType::WriteThrough,
+            _ => typ,
+        });
+    }
+
+    found.or_else(|| CacheType::from_memory_type(def_type.default_type()))
+}
+
+/// Default physical address of the I/O APIC
+const IOAPIC_BASE: u64 = 0xfec0_0000;
+
+/// Make sure the MTRRs do not make the local or I/O APIC cacheable. The page tables map device
+/// memory as uncacheable, which overrides the MTRRs, so this only warns about broken firmware.
+fn check_mtrrs() {
+    let apic_base = unsafe { msr::ApicBase::read() }.base();
+
+    for (name, paddr) in [("local APIC", apic_base), ("I/O APIC", IOAPIC_BASE)] {
+        match mtrr_type(paddr) {
+            Some(CacheType::Uncacheable) | None => {}
+            Some(typ) => log!(Warn, "WARNING: MTRRs map the {} at {:#x} as {:?}\n",
+                name, paddr, typ),
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Access to memory mapped device registers. Device memory is mapped uncacheable into its own
+//! virtual window, and accessed through bounds checked, volatile accessors.
+
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicU64, Ordering};
+use boot_args::{BootArgs, KERNEL_MMIO_WINDOW_BASE, KERNEL_MMIO_WINDOW_SIZE};
+use boot_args::KERNEL_PHYS_WINDOW_BASE;
+use page_table::{PhysMem, PhysAddr, VirtAddr, PageSize, CacheType, MapFlags};
+use rangeset::RangeSet;
+
+/// Next free virtual address in the MMIO window. Mappings are never removed, so this only grows.
+static NEXT_VADDR: AtomicU64 = AtomicU64::new(KERNEL_MMIO_WINDOW_BASE);
+
+/// Physical memory accessed through the physical window, allocating from the free memory
+struct PhysicalMemory<'a>(&'a mut RangeSet);
+
+impl<'a> PhysMem for PhysicalMemory<'a> {
+    unsafe fn translate(&mut self, paddr: PhysAddr, size: usize) -> Option<*mut u8> {
+        // Can't translate for a 0 size access
+        if size == 0 {
+            return None;
+        }
+
+        let vaddr = KERNEL_PHYS_WINDOW_BASE.checked_add(paddr.0)?;
+        vaddr.checked_add(size as u64 - 1)?;
+        Some(vaddr as *mut u8)
+    }
+
+    fn alloc_phys(&mut self, layout: Layout) -> Option<PhysAddr> {
+        self.0.allocate(layout.size() as u64, layout.align() as u64)
+            .map(|x| PhysAddr(x as u64))
+    }
+}
+
+/// A mapping of device registers. Mappings are never removed, so copies of it stay valid.
+#[derive(Clone, Copy)]
+pub struct Mmio {
+    /// Virtual address of the first register
+    vaddr: usize,
+
+    /// Size of the mapping in bytes
+    size: usize,
+}
+
+impl Mmio {
+    /// Map `size` bytes of device memory at physical address `paddr` as uncacheable. Returns
+    /// `None` if the MMIO window is full, or the page tables could not be updated.
+    pub fn map(boot_args: &BootArgs, paddr: u64, size: usize) -> Option<Self> {
+        if size == 0 {
+            return None;
+        }
+
+        // Map the whole pages the registers are in
+        let page_paddr = paddr & !0xfff;
+        let end = paddr.checked_add(size as u64 - 1)?;
+        let map_size = ((end | 0xfff) - page_paddr).checked_add(1)?;
+
+        // Leave an unmapped page after every mapping, such that running off of the end of one
+        // does not silently access another device
+        let vaddr = NEXT_VADDR.fetch_add(map_size + 4096, Ordering::SeqCst);
+        if vaddr + map_size > KERNEL_MMIO_WINDOW_BASE + KERNEL_MMIO_WINDOW_SIZE {
+            return None;
+        }
+
+        {
+            let mut page_table = boot_args.page_table.lock();
+            let page_table = page_table.as_mut()?;
+            let mut pmem = boot_args.free_memory.lock();
+            let mut pmem = PhysicalMemory(pmem.as_mut()?);
+
+            unsafe {
+                page_table.map_phys(&mut pmem, VirtAddr(vaddr), PageSize::Page4K,
+                    PhysAddr(page_paddr), map_size, MapFlags {
+                        read: true, write: true, exec: false, cache: CacheType::Uncacheable,
+                    })?;
+            }
+        }
+
+        Some(Mmio {
+            vaddr: (vaddr + (paddr - page_paddr)) as usize,
+            size,
+        })
+    }
+
+    /// Get a pointer to a `T` at `offset` bytes into the mapping. Panics if the access is not
+    /// naturally aligned or not entirely inside of the mapping.
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        let width = core::mem::size_of::<T>();
+        assert!(offset.is_multiple_of(width), "Unaligned MMIO access at {:#x}", offset);
+        assert!(offset.checked_add(width).is_some_and(|end| end <= self.size),
+            "MMIO access at {:#x} is outside of the {:#x} byte mapping", offset, self.size);
+
+        (self.vaddr + offset) as *mut T
+    }
+
+    /// Read the 32-bit register at `offset` bytes into the mapping
+    pub fn read32(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile(self.ptr(offset)) }
+    }
+
+    /// Write the 32-bit register at `offset` bytes into the mapping
+    pub fn write32(&self, offset: usize, val: u32) {
+        unsafe { core::ptr::write_volatile(self.ptr(offset), val) }
+    }
+}
@@ -13,5 +13,5 @@ fn panic(panic_info: &PanicInfo) -> ! {
     }
 
     print!("\n");
-    cpu::halt();
+    crate::qemu::exit(crate::qemu::EXIT_PANIC);
 }
@@ -1,11 +1,15 @@
-/// Dummy type to implement `core::fmt::Write` for `print` macros
-pub struct SerialWriter;
+/// Dummy type to implement `core::fmt::Write` for `print` macros. Output goes to both the serial
+/// ports and the screen console.
+pub struct Writer;
 
-impl core::fmt::Write for SerialWriter {
+impl core::fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         if let Some(serial) = core!().boot_args.serial.lock().as_mut() {
             serial.write(s.as_bytes());
         }
+        if let Some(console) = core!().boot_args.console.lock().as_mut() {
+            console.write(s.as_bytes());
+        }
         Ok(())
     }
 }
@@ -17,8 +21,18 @@ macro_rules! print {
     ($($arg:tt)*) => {{
         let _lock = core!().boot_args.print_lock.lock();
         let _ = core::fmt::Write::write_fmt(
-            &mut $crate::print::SerialWriter,
+            &mut $crate::print::Writer,
             format_args!($($arg)*)
         );
     }}
 }
+
+/// Print a message of importance `level`, a `LogLevel` variant, if the command line asks for it
+#[macro_export]
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {{
+        if $crate::cmdline::LogLevel::$level <= $crate::cmdline::log_level() {
+            print!($($arg)*);
+        }
+    }}
+}
@@ -0,0 +1,75 @@
+//! Memory protection features of the CPU. The bootloader enables NX and write protection before
+//! entering the kernel, here we make sure they stayed enabled, turn on SMEP and SMAP if the CPU
+//! supports them, and check the page table we were given never maps memory as both writable and
+//! executable.
+
+use core::alloc::Layout;
+use boot_args::{BootArgs, KERNEL_PHYS_WINDOW_BASE};
+use page_table::{PhysMem, PhysAddr, PAGE_WRITE, PAGE_NX};
+
+/// Access to physical memory through the physical window the bootloader mapped for us. This can
+/// only be used to read page tables, it cannot allocate.
+struct PhysWindow;
+
+impl PhysMem for PhysWindow {
+    unsafe fn translate(&mut self, paddr: PhysAddr, size: usize) -> Option<*mut u8> {
+        // Can't translate for a 0 size access
+        if size == 0 {
+            return None;
+        }
+
+        let vaddr = KERNEL_PHYS_WINDOW_BASE.checked_add(paddr.0)?;
+        vaddr.checked_add(size as u64 - 1)?;
+        Some(vaddr as *mut u8)
+    }
+
+    fn alloc_phys(&mut self, _layout: Layout) -> Option<PhysAddr> {
+        None
+    }
+}
+
+/// Enable the memory protection features for the current core. On the BSP also check that the
+/// kernel page table does not have any mapping which is both writable and executable. Panics if
+/// W^X cannot be enforced.
+pub fn init(boot_args: &BootArgs) {
+    // NX and write protection are set by the bootloader when entering long mode, without them
+    // the page table permissions mean nothing
+    if !cpu::read_efer().nxe() {
+        panic!("EFER.NXE is not set, cannot enforce W^X");
+    }
+    if cpu::read_cr0() & cpu::CR0_WP == 0 {
+        panic!("CR0.WP is not set, cannot enforce W^X");
+    }
+
+    // Enable SMEP and SMAP if the CPU supports them and the bootloader did not already
+    let mut cr4 = cpu::read_cr4();
+    if cpu::has_smep() { cr4 |= cpu::CR4_SMEP; }
+    if cpu::has_smap() { cr4 |= cpu::CR4_SMAP; }
+    if cr4 != cpu::read_cr4() {
+        unsafe { cpu::write_cr4(cr4); }
+    }
+
+    if cpu::is_bsp() {
+        check_page_table(boot_args);
+    }
+}
+
+/// Walk the kernel page table and panic if any page is mapped both writable and executable
+fn check_page_table(boot_args: &BootArgs) {
+    let page_table = boot_args.page_table.lock();
+    let page_table = page_table.as_ref().expect("Kernel page table not set up");
+
+    let mut wx_pages = 0u64;
+    unsafe {
+        page_table.for_each_mapping(&mut PhysWindow, |vaddr, size, entry| {
+            if entry & PAGE_WRITE != 0 && entry & PAGE_NX == 0 {
+                print!("W+X mapping at {:#x} for {:#x} bytes\n", vaddr.0, size as u64);
+                wx_pages += 1;
+            }
+        }).expect("Failed to walk the kernel page table");
+    }
+
+    if wx_pages != 0 {
+        panic!("Kernel page table has {} writable and executable mappings", wx_pages);
+    }
+}
@@ -0,0 +1,30 @@
+//! Reporting an exit status to the host when running under QEMU with `exit=qemu` on the command
+//! line, through the `isa-debug-exit` device the build tool adds to the machine. QEMU exits with
+//! `(value << 1) | 1` for a `value` written to the device.
+
+use crate::cmdline;
+
+/// I/O port of the `isa-debug-exit` device, must match the build tool
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Offset added to statuses written to the device, such that QEMU failing on its own, which exits
+/// with 1, is not mistaken for the kernel reporting success
+const EXIT_STATUS_BASE: u32 = 0x10;
+
+/// Every core came online and all selftests passed
+pub const EXIT_SUCCESS: u8 = 0;
+
+/// The kernel panicked
+pub const EXIT_PANIC: u8 = 1;
+
+/// A selftest failed
+pub const EXIT_SELFTEST_FAILED: u8 = 2;
+
+/// Report `status` to QEMU, if the command line asked for it, and halt. Status 0 is success.
+pub fn exit(status: u8) -> ! {
+    if cmdline::qemu_exit() {
+        unsafe { cpu::out32(DEBUG_EXIT_PORT, EXIT_STATUS_BASE + status as u32); }
+    }
+
+    cpu::halt();
+}
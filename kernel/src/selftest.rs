@@ -0,0 +1,82 @@
+//! Selftests run once every core is online, selected with the `selftest` command line flag. They
+//! check the kernel against the machine it booted on, for the smoke tests the build tool runs in
+//! QEMU.
+
+use cpu::msr::{self, Msr};
+use crate::cmdline;
+
+/// Offset of the local APIC ID register
+const APIC_ID: usize = 0x20;
+
+/// An MSR no CPU implements, accessing it raises a #GP
+const MISSING_MSR: Msr = Msr(0xdead_beef);
+
+/// A selftest, its name on the command line and the function which runs it
+type SelfTest = (&'static str, fn() -> Result<(), &'static str>);
+
+/// The selftests and their names on the command line
+const SELFTESTS: &[SelfTest] = &[
+    ("msr_probe", msr_probe),
+    ("rng", rng),
+    ("apic", apic),
+];
+
+/// Run the selftests enabled on the command line on the current core. Returns whether all of them
+/// passed.
+pub fn run() -> bool {
+    let mut failed = 0;
+    for &(name, test) in SELFTESTS {
+        if !cmdline::selftest_enabled(name) {
+            continue;
+        }
+
+        match test() {
+            Ok(()) => print!("Selftest {} passed\n", name),
+            Err(err) => {
+                print!("Selftest {} FAILED: {}\n", name, err);
+                failed += 1;
+            }
+        }
+    }
+
+    failed == 0
+}
+
+/// Probing MSRs recovers from the #GP of a missing one, and reads existing ones
+fn msr_probe() -> Result<(), &'static str> {
+    if MISSING_MSR.probe().is_ok() {
+        return Err("probing a missing MSR did not fault");
+    }
+
+    let efer = msr::IA32_EFER.probe().map_err(|_| "probing EFER faulted")?;
+    if efer != unsafe { msr::IA32_EFER.read() } {
+        return Err("probed EFER differs from EFER");
+    }
+
+    Ok(())
+}
+
+/// The core random number generator produces distinct numbers in range
+fn rng() -> Result<(), &'static str> {
+    let rng = &core!().rng;
+    if rng.next_u64() == rng.next_u64() {
+        return Err("generated the same number twice in a row");
+    }
+
+    if (0..1000).any(|_| rng.range(10) >= 10) {
+        return Err("generated a number out of range");
+    }
+
+    Ok(())
+}
+
+/// Device memory is mapped to the right place, the local APIC reports the ID of this core
+fn apic() -> Result<(), &'static str> {
+    let apic = crate::apic().ok_or("failed to map the APIC")?;
+
+    if apic.read32(APIC_ID) >> 24 != cpu::cpuid(1, 0).ebx >> 24 {
+        return Err("APIC ID does not match the initial APIC ID from CPUID");
+    }
+
+    Ok(())
+}
@@ -11,19 +11,279 @@ use core::sync::atomic::AtomicU64;
 use rangeset::RangeSet;
 use lockcell::LockCell;
 use serial::SerialPort;
+use console::Console;
 use page_table::PageTable;
 
 /// Size to allocate for kernel stacks
-const KERNEL_STACK_SIZE: u64 = 32 * 1024;
+pub const KERNEL_STACK_SIZE: u64 = 32 * 1024;
 
-/// Padding deadspace to add between kernel stacks
-const KERNEL_STACK_PAD: u64 = 32 * 1024;
+/// Padding deadspace to add between kernel stacks. This is never mapped and acts as a guard region
+/// below every stack, and above the stack before it, thus it must be at least a page.
+pub const KERNEL_STACK_PAD: u64 = 32 * 1024;
+
+/// Base of the virtual region kernel stacks are allocated from, "st" in ascii LE. The bootloader
+/// starts allocating at a random page in the lower half of the region.
+pub const KERNEL_STACK_WINDOW_BASE: u64 = 0x0000_7473_0000_0000;
+
+/// Size of the virtual region kernel stacks are allocated from
+pub const KERNEL_STACK_WINDOW_SIZE: u64 = 1024 * 1024 * 1024 * 1024;
+
+/// Maximum size of the random, unmapped gap left after every kernel stack, on top of the padding
+pub const KERNEL_STACK_MAX_GAP: u64 = 1024 * 1024;
 
 /// The virtual base in the kernel page table where physical memory is linearly mapped. Such that
 /// a dereference of `KERNEK_PHYS_WINDOW_BASE` in the kernel address space, will be accessing `0`
 /// in physical memory.
 pub const KERNEL_PHYS_WINDOW_BASE: u64 = 0xffff_cafe_0000_0000;
 
+/// Base of the virtual region the kernel maps device memory into
+pub const KERNEL_MMIO_WINDOW_BASE: u64 = 0xffff_d000_0000_0000;
+
+/// Size of the virtual region the kernel maps device memory into
+pub const KERNEL_MMIO_WINDOW_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
+/// Base of the virtual region the kernel image is loaded into. The bootloader picks a random,
+/// `KERNEL_IMAGE_ALIGN` aligned address in this region for every boot.
+pub const KERNEL_IMAGE_WINDOW_BASE: u64 = 0xffff_f000_0000_0000;
+
+/// Size of the virtual region the kernel image is loaded into, including the image itself
+pub const KERNEL_IMAGE_WINDOW_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+
+/// Alignment of the randomized kernel image base
+pub const KERNEL_IMAGE_ALIGN: u64 = 2 * 1024 * 1024;
+
+/// Size of the unmapped guard region kept after the kernel image in the image window. Nothing but
+/// the image is mapped in the window, so the space below the image is always unmapped.
+pub const KERNEL_IMAGE_GUARD: u64 = 4096;
+
+/// Maximum number of modules the bootloader can pass to the kernel
+pub const MAX_MODULES: usize = 32;
+
+/// Maximum length of a module name in bytes
+pub const MAX_MODULE_NAME: usize = 48;
+
+/// A blob the bootloader loaded into physical memory for the kernel, such as a corpus or a guest
+/// image. The memory it is in is not part of `free_memory`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct Module {
+    /// Name of the module, `name_len` bytes of UTF-8
+    name: [u8; MAX_MODULE_NAME],
+
+    /// Length of the name in bytes
+    name_len: u64,
+
+    /// Physical address the module is loaded at, page aligned
+    pub paddr: u64,
+
+    /// Size of the module in bytes
+    pub size: u64,
+}
+
+impl Module {
+    /// Name of the module
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap()
+    }
+
+    /// Virtual address of the module in the kernel, through the physical window
+    pub fn kernel_vaddr(&self) -> u64 {
+        KERNEL_PHYS_WINDOW_BASE + self.paddr
+    }
+}
+
+/// The modules loaded by the bootloader, in the order of the boot config
+#[repr(C)]
+pub struct Modules {
+    /// Storage for the modules, the first `len` are valid
+    entries: [Module; MAX_MODULES],
+
+    /// Number of modules
+    len: u64,
+}
+
+impl Modules {
+    /// Create an empty module list
+    pub const fn new() -> Self {
+        Modules {
+            entries: [Module { name: [0; MAX_MODULE_NAME], name_len: 0, paddr: 0, size: 0 };
+                MAX_MODULES],
+            len: 0,
+        }
+    }
+
+    /// Add a module named `name` of `size` bytes at `paddr`. Returns `None` if the list is full,
+    /// or the name is too long.
+    pub fn push(&mut self, name: &str, paddr: u64, size: u64) -> Option<()> {
+        if self.len as usize >= MAX_MODULES || name.len() > MAX_MODULE_NAME {
+            return None;
+        }
+
+        let mut module = Module { name: [0; MAX_MODULE_NAME], name_len: name.len() as u64,
+            paddr, size };
+        module.name[..name.len()].copy_from_slice(name.as_bytes());
+        self.entries[self.len as usize] = module;
+        self.len += 1;
+        Some(())
+    }
+
+    /// All modules
+    pub fn iter(&self) -> impl Iterator<Item = &Module> {
+        self.entries[..self.len as usize].iter()
+    }
+
+    /// Find the module named `name`
+    pub fn get(&self, name: &str) -> Option<&Module> {
+        self.iter().find(|module| module.name() == name)
+    }
+}
+
+impl Default for Modules {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Maximum length of the kernel command line in bytes
+pub const MAX_CMDLINE: usize = 256;
+
+/// The kernel command line, from the boot config or DHCP
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct CommandLine {
+    /// The command line, `len` bytes of UTF-8
+    bytes: [u8; MAX_CMDLINE],
+
+    /// Length of the command line in bytes
+    len: u64,
+}
+
+impl CommandLine {
+    /// Create an empty command line
+    pub const fn new() -> Self {
+        CommandLine { bytes: [0; MAX_CMDLINE], len: 0 }
+    }
+
+    /// Replace the command line with `cmdline`. Returns `None` if it is too long.
+    pub fn set(&mut self, cmdline: &str) -> Option<()> {
+        if cmdline.len() > MAX_CMDLINE {
+            return None;
+        }
+
+        self.bytes[..cmdline.len()].copy_from_slice(cmdline.as_bytes());
+        self.len = cmdline.len() as u64;
+        Some(())
+    }
+
+    /// Get the command line
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap()
+    }
+}
+
+impl Default for CommandLine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An IPv4 address, displayed in dotted decimal
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[repr(C)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl core::fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}.{}.{}.{}", self.0[0], self.0[1], self.0[2], self.0[3])
+    }
+}
+
+/// Maximum length of the boot file name, the size of the BOOTP `file` field
+pub const MAX_BOOT_FILE: usize = 128;
+
+/// Maximum length of the vendor specific options, the most a single DHCP option can hold
+pub const MAX_VENDOR_OPTIONS: usize = 255;
+
+/// The addressing the PXE stack got from DHCP, such that the kernel can use the network without
+/// running DHCP again. Addresses the server did not provide are `0.0.0.0`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct DhcpInfo {
+    /// Our address, `yiaddr`
+    pub client_ip: Ipv4Addr,
+
+    /// Address of the server we booted from, `siaddr`
+    pub server_ip: Ipv4Addr,
+
+    /// Address of the relay agent the DHCP messages went through, `giaddr`
+    pub relay_ip: Ipv4Addr,
+
+    /// Subnet mask, option 1
+    pub subnet_mask: Ipv4Addr,
+
+    /// Default gateway, the first router in option 3
+    pub router: Ipv4Addr,
+
+    /// Name of the boot file, `boot_file_len` bytes
+    boot_file: [u8; MAX_BOOT_FILE],
+
+    /// Length of the boot file name in bytes
+    boot_file_len: u64,
+
+    /// Vendor specific options, option 43, `vendor_options_len` bytes
+    vendor_options: [u8; MAX_VENDOR_OPTIONS],
+
+    /// Length of the vendor specific options in bytes
+    vendor_options_len: u64,
+}
+
+impl DhcpInfo {
+    /// Create an empty DHCP info, with all addresses `0.0.0.0`
+    pub const fn new() -> Self {
+        DhcpInfo {
+            client_ip: Ipv4Addr([0; 4]),
+            server_ip: Ipv4Addr([0; 4]),
+            relay_ip: Ipv4Addr([0; 4]),
+            subnet_mask: Ipv4Addr([0; 4]),
+            router: Ipv4Addr([0; 4]),
+            boot_file: [0; MAX_BOOT_FILE],
+            boot_file_len: 0,
+            vendor_options: [0; MAX_VENDOR_OPTIONS],
+            vendor_options_len: 0,
+        }
+    }
+
+    /// Name of the boot file. It is not necessarily UTF-8.
+    pub fn boot_file(&self) -> &[u8] {
+        &self.boot_file[..self.boot_file_len as usize]
+    }
+
+    /// Set the name of the boot file. Returns `None` if it is too long.
+    pub fn set_boot_file(&mut self, boot_file: &[u8]) -> Option<()> {
+        self.boot_file.get_mut(..boot_file.len())?.copy_from_slice(boot_file);
+        self.boot_file_len = boot_file.len() as u64;
+        Some(())
+    }
+
+    /// Vendor specific options, as sent by the server
+    pub fn vendor_options(&self) -> &[u8] {
+        &self.vendor_options[..self.vendor_options_len as usize]
+    }
+
+    /// Set the vendor specific options. Returns `None` if they are too long.
+    pub fn set_vendor_options(&mut self, vendor_options: &[u8]) -> Option<()> {
+        self.vendor_options.get_mut(..vendor_options.len())?.copy_from_slice(vendor_options);
+        self.vendor_options_len = vendor_options.len() as u64;
+        Some(())
+    }
+}
+
+impl Default for DhcpInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Structures to pass between both the 32-bit and 64-bit modes. This structure MUST be identical
 /// in both modes. Thus, no using pointers, references, or usizes. Also, make sure everything
 /// is marked #[repr(C)]., otherwirse the 32 and 64-bit variants may slightly be reordered as Rust
@@ -35,9 +295,16 @@ pub struct BootArgs {
     /// the same time by both the bootloader and the kernel.
     pub free_memory: LockCell<Option<RangeSet>>,
 
+    /// All memory the BIOS reported as usable RAM, whether it is free or not. Anything else in the
+    /// physical address space may be device memory.
+    pub ram: LockCell<Option<RangeSet>>,
+
     /// The serial driver
     pub serial: LockCell<Option<SerialPort>>,
 
+    /// The screen console, either VGA text mode or a VBE framebuffer
+    pub console: LockCell<Option<Console>>,
+
     /// The page table used for the kernel
     pub page_table: LockCell<Option<PageTable>>,
 
@@ -46,6 +313,15 @@ pub struct BootArgs {
     /// Address of the kernel entry point
     pub kernel_entry: LockCell<Option<u64>>,
 
+    /// Modules listed in the boot config, loaded into physical memory by the bootloader
+    pub modules: LockCell<Modules>,
+
+    /// The kernel command line
+    pub cmdline: LockCell<CommandLine>,
+
+    /// The addressing from the DHCP ACK the PXE stack booted us with
+    pub dhcp: LockCell<Option<DhcpInfo>>,
+
     /// The virtual address of the "next available stack". This is just used to give unique stack
     /// addresses to each core as they come online. This doesn't need to be honored if you have
     /// another method of creating unique non-overlapping stacks for cores.
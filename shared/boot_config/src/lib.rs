@@ -0,0 +1,242 @@
+//! The boot config, `sherlock.cfg`, which the bootloader downloads first to find out what else to
+//! download. It is a text file with one entry per line, a key followed by its values separated
+//! by whitespace. Empty lines and lines starting with `#` are ignored.
+//!
+//! ```text
+//! # The kernel image, exactly one is required
+//! kernel sherlock.kern
+//!
+//! # Any number of modules, each a name the kernel looks it up by and a file to download
+//! module corpus corpus.bin
+//! module symbols sherlock.sym
+//!
+//! # The kernel command line, optional, the rest of the line is used as is
+//! cmdline log=debug cores=4
+//! ```
+#![no_std]
+
+#[cfg(feature = "std")]
+extern crate std;
+
+/// Filename of the boot config on the TFTP server
+pub const FILENAME: &str = "sherlock.cfg";
+
+/// The config used when there is no boot config on the server
+pub const DEFAULT: &str = "kernel sherlock.kern\n";
+
+/// Reasons a boot config can be invalid. Line numbers start at 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The key on line `line` is not one we know
+    UnknownKey { line: usize },
+
+    /// The entry on line `line` has the wrong number of values
+    WrongValueCount { line: usize, expected: usize },
+
+    /// The kernel is given again on line `line`
+    DuplicateKernel { line: usize },
+
+    /// The module name on line `line` is used by an earlier module too
+    DuplicateModule { line: usize },
+
+    /// The command line is given again on line `line`
+    DuplicateCmdline { line: usize },
+
+    /// There is no kernel entry
+    MissingKernel,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            Error::UnknownKey { line } => write!(f, "unknown key on line {}", line),
+            Error::WrongValueCount { line, expected } =>
+                write!(f, "expected {} values on line {}", expected, line),
+            Error::DuplicateKernel { line } => write!(f, "second kernel on line {}", line),
+            Error::DuplicateModule { line } =>
+                write!(f, "module name on line {} is already used", line),
+            Error::DuplicateCmdline { line } =>
+                write!(f, "second command line on line {}", line),
+            Error::MissingKernel => write!(f, "no kernel given"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// An entry in the boot config
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Entry<'a> {
+    /// The file to download the kernel from
+    Kernel(&'a str),
+
+    /// A module the kernel finds by `name`, downloaded from `file`
+    Module { name: &'a str, file: &'a str },
+
+    /// The kernel command line
+    Cmdline(&'a str),
+}
+
+/// Parse the entry on a single line, numbered `line`. Returns `None` for empty and comment lines.
+fn parse_line(text: &str, line: usize) -> Result<Option<Entry<'_>>, Error> {
+    let text = text.trim();
+    if text.is_empty() || text.starts_with('#') {
+        return Ok(None);
+    }
+
+    let mut words = text.split_whitespace();
+    let key = words.next().unwrap();
+
+    // The command line is the rest of the line, whatever it contains
+    if key == "cmdline" {
+        return Ok(Some(Entry::Cmdline(text[key.len()..].trim())));
+    }
+
+    // Get exactly `expected` values for the key
+    let mut values = |expected: usize| -> Result<[&str; 2], Error> {
+        let mut vals = [""; 2];
+        for val in vals.iter_mut().take(expected) {
+            *val = words.next().ok_or(Error::WrongValueCount { line, expected })?;
+        }
+        if words.next().is_some() {
+            return Err(Error::WrongValueCount { line, expected });
+        }
+        Ok(vals)
+    };
+
+    match key {
+        "kernel" => {
+            let [file, _] = values(1)?;
+            Ok(Some(Entry::Kernel(file)))
+        }
+        "module" => {
+            let [name, file] = values(2)?;
+            Ok(Some(Entry::Module { name, file }))
+        }
+        _ => Err(Error::UnknownKey { line }),
+    }
+}
+
+/// A validated boot config
+#[derive(Clone, Copy, Debug)]
+pub struct Config<'a> {
+    /// The text of the config
+    text: &'a str,
+
+    /// The file to download the kernel from
+    kernel: &'a str,
+
+    /// The kernel command line
+    cmdline: Option<&'a str>,
+}
+
+impl<'a> Config<'a> {
+    /// Parse and validate the boot config in `text`
+    pub fn parse(text: &'a str) -> Result<Self, Error> {
+        let mut kernel = None;
+        let mut cmdline = None;
+
+        for (ii, line) in text.lines().enumerate() {
+            match parse_line(line, ii + 1)? {
+                Some(Entry::Kernel(file)) => {
+                    if kernel.is_some() {
+                        return Err(Error::DuplicateKernel { line: ii + 1 });
+                    }
+                    kernel = Some(file);
+                }
+                Some(Entry::Module { name, .. }) => {
+                    // Names must be unique for the kernel to find modules by them. Every line
+                    // before this one has been parsed successfully already.
+                    let mut earlier = text.lines().take(ii).enumerate()
+                        .filter_map(|(jj, line)| parse_line(line, jj + 1).ok().flatten());
+                    if earlier.any(|entry|
+                            matches!(entry, Entry::Module { name: other, .. } if other == name)) {
+                        return Err(Error::DuplicateModule { line: ii + 1 });
+                    }
+                }
+                Some(Entry::Cmdline(line)) => {
+                    if cmdline.is_some() {
+                        return Err(Error::DuplicateCmdline { line: ii + 1 });
+                    }
+                    cmdline = Some(line);
+                }
+                None => {}
+            }
+        }
+
+        Ok(Config { text, kernel: kernel.ok_or(Error::MissingKernel)?, cmdline })
+    }
+
+    /// All entries in the config, in the order they appear in
+    pub fn entries(&self) -> impl Iterator<Item = Entry<'a>> + 'a {
+        // The text has been validated, so every line parses
+        self.text.lines().enumerate()
+            .filter_map(|(ii, line)| parse_line(line, ii + 1).ok().flatten())
+    }
+
+    /// The file to download the kernel from
+    pub fn kernel(&self) -> &'a str {
+        self.kernel
+    }
+
+    /// The kernel command line, if the config has one
+    pub fn cmdline(&self) -> Option<&'a str> {
+        self.cmdline
+    }
+
+    /// The modules to load, as their names and the files to download them from
+    pub fn modules(&self) -> impl Iterator<Item = (&'a str, &'a str)> + 'a {
+        self.entries().filter_map(|entry| match entry {
+            Entry::Module { name, file } => Some((name, file)),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse("
+            # Comment
+            module corpus corpus.bin
+            kernel   other.kern
+
+            module symbols\tsherlock.sym
+            cmdline  log=debug   cores=2
+        ").unwrap();
+
+        assert_eq!(config.kernel(), "other.kern");
+        assert_eq!(config.modules().collect::<Vec<_>>(),
+            [("corpus", "corpus.bin"), ("symbols", "sherlock.sym")]);
+        assert_eq!(config.cmdline(), Some("log=debug   cores=2"));
+        assert_eq!(Config::parse("kernel a\ncmdline").unwrap().cmdline(), Some(""));
+
+        let config = Config::parse(DEFAULT).unwrap();
+        assert_eq!(config.kernel(), "sherlock.kern");
+        assert_eq!(config.cmdline(), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(Config::parse("").unwrap_err(), Error::MissingKernel);
+        assert_eq!(Config::parse("kernel a\nkernel b").unwrap_err(),
+            Error::DuplicateKernel { line: 2 });
+        assert_eq!(Config::parse("kernel a\nmodul b c").unwrap_err(),
+            Error::UnknownKey { line: 2 });
+        assert_eq!(Config::parse("kernel").unwrap_err(),
+            Error::WrongValueCount { line: 1, expected: 1 });
+        assert_eq!(Config::parse("kernel a\nmodule b c d").unwrap_err(),
+            Error::WrongValueCount { line: 2, expected: 2 });
+        assert_eq!(Config::parse("kernel a\nmodule b c\nmodule b d").unwrap_err(),
+            Error::DuplicateModule { line: 3 });
+        assert_eq!(Config::parse("cmdline a\nkernel a\ncmdline b").unwrap_err(),
+            Error::DuplicateCmdline { line: 3 });
+    }
+}
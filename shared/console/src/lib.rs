@@ -0,0 +1,303 @@
+//! Screen consoles for x86. This provides a VGA text mode console using the buffer at `0xb8000`,
+//! and a linear framebuffer console for graphics modes set up through VBE.
+//!
+//! Both consoles assume that the memory they draw to is identity mapped, which holds in the
+//! bootloader (no paging) and in the kernel (the low 4 GiB are identity mapped).
+#![no_std]
+
+/// Physical address of the VGA text mode buffer
+const VGA_TEXT_BUFFER: u64 = 0xb8000;
+
+/// Number of columns in the 80x25 VGA text mode
+const VGA_TEXT_COLUMNS: u32 = 80;
+
+/// Number of rows in the 80x25 VGA text mode
+const VGA_TEXT_ROWS: u32 = 25;
+
+/// Attribute used for all characters, light grey on black
+const VGA_TEXT_ATTRIBUTE: u16 = 0x07;
+
+/// CRT controller index and data ports, used to move the hardware cursor
+const VGA_CRTC_INDEX: u16 = 0x3d4;
+const VGA_CRTC_DATA: u16 = 0x3d5;
+
+/// Width of a glyph in pixels, the BIOS fonts are always 8 pixels wide, one byte per row
+const GLYPH_WIDTH: u32 = 8;
+
+/// Description of a linear framebuffer and the font used to draw to it. Every field is fixed
+/// width, such that this has the same shape in both 32-bit and 64-bit mode.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct FramebufferInfo {
+    /// Physical address of the linear framebuffer
+    pub base: u64,
+
+    /// Physical address of an 8-pixel wide bitmap font for all 256 characters, with
+    /// `font_height` bytes per character
+    pub font: u64,
+
+    /// Width of the screen in pixels
+    pub width: u32,
+
+    /// Height of the screen in pixels
+    pub height: u32,
+
+    /// Number of bytes between the start of two consecutive lines
+    pub pitch: u32,
+
+    /// Number of bytes per pixel, 2, 3 or 4
+    pub bytes_per_pixel: u32,
+
+    /// Height of a glyph in `font`, in pixels
+    pub font_height: u32,
+
+    /// Raw pixel value used for the text
+    pub foreground: u32,
+
+    /// Raw pixel value used for the background
+    pub background: u32,
+}
+
+/// A console which is being drawn to the screen
+#[repr(C)]
+pub enum Console {
+    /// The VGA 80x25 text mode buffer
+    Text {
+        /// Current cursor column
+        column: u32,
+
+        /// Current cursor row
+        row: u32,
+    },
+
+    /// A linear framebuffer, with text rendered with a bitmap font
+    Framebuffer {
+        /// The framebuffer we are drawing to
+        info: FramebufferInfo,
+
+        /// Current cursor column, in glyphs
+        column: u32,
+
+        /// Current cursor row, in glyphs
+        row: u32,
+    },
+}
+
+impl Console {
+    /// Create a new console using the VGA text mode buffer and clear the screen
+    ///
+    /// # Safety
+    ///
+    /// The display must be in the 80x25 text mode the BIOS leaves us in, and the text buffer at
+    /// `0xb8000` must be identity mapped and writable. The console takes exclusive ownership of
+    /// the buffer: nothing else may access it, including another `Console`, while this one lives.
+    pub unsafe fn text() -> Self {
+        let mut ret = Console::Text { column: 0, row: 0 };
+        ret.clear();
+        ret
+    }
+
+    /// Create a new console drawing to the linear framebuffer described by `info` and clear the
+    /// screen
+    ///
+    /// # Safety
+    ///
+    /// `info` must describe the framebuffer of the current video mode. The whole framebuffer must
+    /// be identity mapped and writable, and the font identity mapped and readable. The console
+    /// takes exclusive ownership of the framebuffer: nothing else may access it, including
+    /// another `Console`, while this one lives.
+    pub unsafe fn framebuffer(info: FramebufferInfo) -> Self {
+        let mut ret = Console::Framebuffer { info, column: 0, row: 0 };
+        ret.clear();
+        ret
+    }
+
+    /// Get the size of the console as (columns, rows) in characters
+    fn size(&self) -> (u32, u32) {
+        match self {
+            Console::Text { .. } => (VGA_TEXT_COLUMNS, VGA_TEXT_ROWS),
+            Console::Framebuffer { info, .. } => {
+                (info.width / GLYPH_WIDTH, info.height / info.font_height)
+            }
+        }
+    }
+
+    /// Get mutable references to the cursor (column, row)
+    fn cursor(&mut self) -> (&mut u32, &mut u32) {
+        match self {
+            Console::Text { column, row } => (column, row),
+            Console::Framebuffer { column, row, .. } => (column, row),
+        }
+    }
+
+    /// Blank the whole screen and move the cursor to the top left
+    pub fn clear(&mut self) {
+        let (_, rows) = self.size();
+        for row in 0..rows {
+            self.clear_row(row);
+        }
+
+        let (column, row) = self.cursor();
+        *column = 0;
+        *row = 0;
+        self.update_cursor();
+    }
+
+    /// Blank a single row of characters
+    fn clear_row(&mut self, row: u32) {
+        match self {
+            Console::Text { .. } => {
+                for column in 0..VGA_TEXT_COLUMNS {
+                    self.draw(column, row, b' ');
+                }
+            }
+            Console::Framebuffer { info, .. } => {
+                let line_bytes = info.width * info.bytes_per_pixel;
+                for y in row * info.font_height..(row + 1) * info.font_height {
+                    let line = (info.base + y as u64 * info.pitch as u64) as usize;
+                    for x in (0..line_bytes).step_by(info.bytes_per_pixel as usize) {
+                        unsafe {
+                            write_pixel((line + x as usize) as *mut u8,
+                                info.bytes_per_pixel, info.background);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Draw `byte` at the character cell (`column`, `row`)
+    fn draw(&mut self, column: u32, row: u32, byte: u8) {
+        match self {
+            Console::Text { .. } => {
+                let cell = VGA_TEXT_BUFFER as usize +
+                    ((row * VGA_TEXT_COLUMNS + column) * 2) as usize;
+                unsafe {
+                    core::ptr::write_volatile(cell as *mut u16,
+                        (VGA_TEXT_ATTRIBUTE << 8) | byte as u16);
+                }
+            }
+            Console::Framebuffer { info, .. } => {
+                let glyph = info.font as usize + byte as usize * info.font_height as usize;
+
+                for y in 0..info.font_height {
+                    let bits = unsafe { *((glyph + y as usize) as *const u8) };
+                    let line = info.base as usize +
+                        ((row * info.font_height + y) * info.pitch) as usize;
+
+                    for x in 0..GLYPH_WIDTH {
+                        let pixel = if bits & (0x80 >> x) != 0 {
+                            info.foreground
+                        } else {
+                            info.background
+                        };
+
+                        let addr = line +
+                            ((column * GLYPH_WIDTH + x) * info.bytes_per_pixel) as usize;
+                        unsafe {
+                            write_pixel(addr as *mut u8, info.bytes_per_pixel, pixel);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Move every row of the screen up by one and blank the last row
+    fn scroll(&mut self) {
+        let (_, rows) = self.size();
+
+        match self {
+            Console::Text { .. } => {
+                let row_bytes = (VGA_TEXT_COLUMNS * 2) as usize;
+                unsafe {
+                    core::ptr::copy(
+                        (VGA_TEXT_BUFFER as usize + row_bytes) as *const u8,
+                        VGA_TEXT_BUFFER as usize as *mut u8,
+                        row_bytes * (rows - 1) as usize,
+                    );
+                }
+            }
+            Console::Framebuffer { info, .. } => {
+                let row_bytes = (info.pitch * info.font_height) as usize;
+                unsafe {
+                    core::ptr::copy(
+                        (info.base as usize + row_bytes) as *const u8,
+                        info.base as usize as *mut u8,
+                        row_bytes * (rows - 1) as usize,
+                    );
+                }
+            }
+        }
+
+        self.clear_row(rows - 1);
+    }
+
+    /// Move the cursor to the start of the next line, scrolling if we are at the bottom
+    fn newline(&mut self) {
+        let (_, rows) = self.size();
+        let (column, row) = self.cursor();
+
+        *column = 0;
+        if *row + 1 < rows {
+            *row += 1;
+        } else {
+            self.scroll();
+        }
+    }
+
+    /// Update the blinking hardware cursor to match our cursor. This only exists in text mode.
+    fn update_cursor(&mut self) {
+        if let Console::Text { column, row } = *self {
+            let pos = (row * VGA_TEXT_COLUMNS + column) as u16;
+            unsafe {
+                cpu::out8(VGA_CRTC_INDEX, 0x0f);
+                cpu::out8(VGA_CRTC_DATA, pos as u8);
+                cpu::out8(VGA_CRTC_INDEX, 0x0e);
+                cpu::out8(VGA_CRTC_DATA, (pos >> 8) as u8);
+            }
+        }
+    }
+
+    /// Write a byte to the console
+    pub fn write_byte(&mut self, byte: u8) {
+        let (columns, _) = self.size();
+
+        match byte {
+            b'\n' => self.newline(),
+            b'\r' => *self.cursor().0 = 0,
+            _ => {
+                // Wrap lines which do not fit on the screen
+                if *self.cursor().0 >= columns {
+                    self.newline();
+                }
+
+                let (&mut column, &mut row) = self.cursor();
+                self.draw(column, row, byte);
+                *self.cursor().0 += 1;
+            }
+        }
+    }
+
+    /// Write bytes to the console
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_byte(byte);
+        }
+
+        self.update_cursor();
+    }
+}
+
+/// Write a raw `pixel` value of `bytes_per_pixel` bytes to `addr`
+unsafe fn write_pixel(addr: *mut u8, bytes_per_pixel: u32, pixel: u32) {
+    match bytes_per_pixel {
+        4 => core::ptr::write_volatile(addr as *mut u32, pixel),
+        2 => core::ptr::write_volatile(addr as *mut u16, pixel as u16),
+        _ => {
+            for (ii, &byte) in pixel.to_le_bytes()[..bytes_per_pixel as usize].iter().enumerate() {
+                core::ptr::write_volatile(addr.add(ii), byte);
+            }
+        }
+    }
+}
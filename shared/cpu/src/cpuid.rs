@@ -0,0 +1,399 @@
+//! CPUID based feature detection. The features are queried once and cached, such that they can be
+//! checked cheaply from anywhere. This works the same in the 32-bit bootloader and 64-bit kernel.
+
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// The registers returned by a `cpuid` instruction
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Execute `cpuid` with `leaf` in `eax` and `subleaf` in `ecx`
+#[inline]
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+
+    // `rbx` is reserved by LLVM in 64-bit mode, so we save it in a temporary register
+    unsafe {
+        #[cfg(target_arch = "x86_64")]
+        {
+            let tmp: u64;
+            asm!(
+                "mov {tmp:r}, rbx",
+                "cpuid",
+                "xchg {tmp:r}, rbx",
+                tmp = out(reg) tmp,
+                inout("eax") leaf => eax,
+                inout("ecx") subleaf => ecx,
+                out("edx") edx,
+            );
+            ebx = tmp as u32;
+        }
+
+        #[cfg(target_arch = "x86")]
+        asm!(
+            "mov {tmp:e}, ebx",
+            "cpuid",
+            "xchg {tmp:e}, ebx",
+            tmp = out(reg) ebx,
+            inout("eax") leaf => eax,
+            inout("ecx") subleaf => ecx,
+            out("edx") edx,
+        );
+    }
+
+    CpuidResult { eax, ebx, ecx, edx }
+}
+
+/// Manufacturer of the CPU, from the vendor string of leaf 0
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Vendor {
+    Intel,
+    Amd,
+
+    /// Any other vendor, with its raw 12 byte vendor string
+    Other([u8; 12]),
+}
+
+/// Features of the CPU we care about, each `true` if the CPU supports it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Feature {
+    /// Leaf 1 EDX
+    pub fpu: bool,
+    pub tsc: bool,
+    pub msr: bool,
+    pub pae: bool,
+    pub apic: bool,
+    pub mtrr: bool,
+    pub pge: bool,
+    pub pat: bool,
+    pub fxsr: bool,
+    pub sse: bool,
+    pub sse2: bool,
+
+    /// Leaf 1 ECX
+    pub sse3: bool,
+    pub vmx: bool,
+    pub ssse3: bool,
+    pub pcid: bool,
+    pub sse4_1: bool,
+    pub sse4_2: bool,
+    pub x2apic: bool,
+    pub tsc_deadline: bool,
+    pub xsave: bool,
+    pub osxsave: bool,
+    pub avx: bool,
+    pub rdrand: bool,
+    pub hypervisor: bool,
+
+    /// Leaf 7 subleaf 0
+    pub fsgsbase: bool,
+    pub smep: bool,
+    pub avx2: bool,
+    pub avx512f: bool,
+    pub rdseed: bool,
+    pub smap: bool,
+
+    /// Leaf 0x8000_0001
+    pub svm: bool,
+    pub nx: bool,
+    pub page1gb: bool,
+    pub rdtscp: bool,
+    pub long_mode: bool,
+
+    /// Leaf 0x8000_0007, the TSC ticks at a constant rate regardless of power states
+    pub invariant_tsc: bool,
+}
+
+/// Information about the CPU gathered from `cpuid`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuFeatures {
+    /// Highest basic leaf supported
+    pub max_leaf: u32,
+
+    /// Highest extended leaf supported
+    pub max_extended_leaf: u32,
+
+    /// CPU manufacturer
+    pub vendor: Vendor,
+
+    /// Display family, model and stepping, with the extended fields already folded in
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+
+    /// Raw processor brand string, NUL padded. Use `brand()` to get it as a `str`.
+    pub brand_string: [u8; 48],
+
+    /// Supported features
+    pub feature: Feature,
+
+    /// Number of physical address bits supported
+    pub max_phys_addr_bits: u8,
+
+    /// Number of linear address bits supported
+    pub max_virt_addr_bits: u8,
+}
+
+/// Returns true if `bit` is set in `val`
+#[inline]
+fn bit(val: u32, bit: u32) -> bool {
+    (val >> bit) & 1 != 0
+}
+
+impl CpuFeatures {
+    /// Query the features of the current CPU
+    pub fn detect() -> Self {
+        Self::from_cpuid(cpuid)
+    }
+
+    /// Decode the features from the leaves returned by `cpuid`. Leaves above the maximum
+    /// supported leaf are never queried.
+    pub fn from_cpuid<F: FnMut(u32, u32) -> CpuidResult>(mut cpuid: F) -> Self {
+        let leaf0 = cpuid(0, 0);
+        let max_leaf = leaf0.eax;
+
+        // The vendor string is stored in EBX, EDX, ECX order
+        let mut raw_vendor = [0u8; 12];
+        raw_vendor[0..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+        raw_vendor[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+        raw_vendor[8..12].copy_from_slice(&leaf0.ecx.to_le_bytes());
+        let vendor = match &raw_vendor {
+            b"GenuineIntel" => Vendor::Intel,
+            b"AuthenticAMD" => Vendor::Amd,
+            _ => Vendor::Other(raw_vendor),
+        };
+
+        let max_extended_leaf = cpuid(0x8000_0000, 0).eax;
+        let basic = |cpuid: &mut F, leaf: u32| {
+            if max_leaf >= leaf { cpuid(leaf, 0) } else { CpuidResult::default() }
+        };
+        let extended = |cpuid: &mut F, leaf: u32| {
+            if max_extended_leaf >= leaf { cpuid(leaf, 0) } else { CpuidResult::default() }
+        };
+
+        let leaf1 = basic(&mut cpuid, 1);
+        let leaf7 = basic(&mut cpuid, 7);
+        let ext1 = extended(&mut cpuid, 0x8000_0001);
+        let ext7 = extended(&mut cpuid, 0x8000_0007);
+        let ext8 = extended(&mut cpuid, 0x8000_0008);
+
+        // The extended family is only used for family 0xf, and the extended model for families
+        // 0x6 and 0xf
+        let mut family = (leaf1.eax >> 8) & 0xf;
+        let mut model = (leaf1.eax >> 4) & 0xf;
+        let stepping = leaf1.eax & 0xf;
+        if family == 0x6 || family == 0xf {
+            model += ((leaf1.eax >> 16) & 0xf) << 4;
+        }
+        if family == 0xf {
+            family += (leaf1.eax >> 20) & 0xff;
+        }
+
+        let mut brand_string = [0u8; 48];
+        if max_extended_leaf >= 0x8000_0004 {
+            for (ii, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+                let regs = cpuid(leaf, 0);
+                for (jj, reg) in [regs.eax, regs.ebx, regs.ecx, regs.edx].iter().enumerate() {
+                    let off = ii * 16 + jj * 4;
+                    brand_string[off..off + 4].copy_from_slice(&reg.to_le_bytes());
+                }
+            }
+        }
+
+        let feature = Feature {
+            fpu:  bit(leaf1.edx, 0),
+            tsc:  bit(leaf1.edx, 4),
+            msr:  bit(leaf1.edx, 5),
+            pae:  bit(leaf1.edx, 6),
+            apic: bit(leaf1.edx, 9),
+            mtrr: bit(leaf1.edx, 12),
+            pge:  bit(leaf1.edx, 13),
+            pat:  bit(leaf1.edx, 16),
+            fxsr: bit(leaf1.edx, 24),
+            sse:  bit(leaf1.edx, 25),
+            sse2: bit(leaf1.edx, 26),
+
+            sse3:         bit(leaf1.ecx, 0),
+            vmx:          bit(leaf1.ecx, 5),
+            ssse3:        bit(leaf1.ecx, 9),
+            pcid:         bit(leaf1.ecx, 17),
+            sse4_1:       bit(leaf1.ecx, 19),
+            sse4_2:       bit(leaf1.ecx, 20),
+            x2apic:       bit(leaf1.ecx, 21),
+            tsc_deadline: bit(leaf1.ecx, 24),
+            xsave:        bit(leaf1.ecx, 26),
+            osxsave:      bit(leaf1.ecx, 27),
+            avx:          bit(leaf1.ecx, 28),
+            rdrand:       bit(leaf1.ecx, 30),
+            hypervisor:   bit(leaf1.ecx, 31),
+
+            fsgsbase: bit(leaf7.ebx, 0),
+            smep:     bit(leaf7.ebx, 7),
+            avx2:     bit(leaf7.ebx, 5),
+            avx512f:  bit(leaf7.ebx, 16),
+            rdseed:   bit(leaf7.ebx, 18),
+            smap:     bit(leaf7.ebx, 20),
+
+            svm:       bit(ext1.ecx, 2),
+            nx:        bit(ext1.edx, 20),
+            page1gb:   bit(ext1.edx, 26),
+            rdtscp:    bit(ext1.edx, 27),
+            long_mode: bit(ext1.edx, 29),
+
+            invariant_tsc: bit(ext7.edx, 8),
+        };
+
+        // Without leaf 0x8000_0008 the physical address width is 36 bits with PAE and 32 without
+        let (max_phys_addr_bits, max_virt_addr_bits) = if max_extended_leaf >= 0x8000_0008 {
+            (ext8.eax as u8, (ext8.eax >> 8) as u8)
+        } else if feature.pae {
+            (36, 32)
+        } else {
+            (32, 32)
+        };
+
+        CpuFeatures {
+            max_leaf,
+            max_extended_leaf,
+            vendor,
+            family,
+            model,
+            stepping,
+            brand_string,
+            feature,
+            max_phys_addr_bits,
+            max_virt_addr_bits,
+        }
+    }
+
+    /// Get the processor brand string with the padding removed, if the CPU reports one
+    pub fn brand(&self) -> Option<&str> {
+        let len = self.brand_string.iter().position(|&x| x == 0)
+            .unwrap_or(self.brand_string.len());
+        let brand = core::str::from_utf8(&self.brand_string[..len]).ok()?.trim();
+
+        if brand.is_empty() { None } else { Some(brand) }
+    }
+}
+
+/// Cached features, filled in by the first call to `features()`
+struct FeatureCache(UnsafeCell<MaybeUninit<CpuFeatures>>);
+
+/// The cache is only written once, before `CACHE_STATE` becomes `CACHE_READY`
+unsafe impl Sync for FeatureCache {}
+
+static CACHE: FeatureCache = FeatureCache(UnsafeCell::new(MaybeUninit::uninit()));
+static CACHE_STATE: AtomicU8 = AtomicU8::new(CACHE_EMPTY);
+
+const CACHE_EMPTY: u8 = 0;
+const CACHE_FILLING: u8 = 1;
+const CACHE_READY: u8 = 2;
+
+/// Get the features of the CPU. They are detected on the first call and cached afterwards, all
+/// cores are assumed to support the same features.
+pub fn features() -> &'static CpuFeatures {
+    loop {
+        match CACHE_STATE.compare_exchange(CACHE_EMPTY, CACHE_FILLING, Ordering::Acquire,
+                Ordering::Acquire) {
+            Ok(_) => {
+                unsafe { (*CACHE.0.get()).write(CpuFeatures::detect()); }
+                CACHE_STATE.store(CACHE_READY, Ordering::Release);
+            }
+            Err(CACHE_READY) => return unsafe { (*CACHE.0.get()).assume_init_ref() },
+            Err(_) => core::hint::spin_loop(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+
+    /// Build a `cpuid` callback which answers from a table of (leaf, result) pairs and panics on
+    /// any other leaf
+    fn fake<'a>(leaves: &'a [(u32, CpuidResult)]) -> impl FnMut(u32, u32) -> CpuidResult + 'a {
+        move |leaf, _| {
+            leaves.iter().find(|(l, _)| *l == leaf)
+                .unwrap_or_else(|| panic!("Unexpected leaf {:#x}", leaf)).1
+        }
+    }
+
+    /// Pack 16 bytes of a brand string into registers
+    fn brand(bytes: &[u8; 16]) -> CpuidResult {
+        let reg = |ii: usize| u32::from_le_bytes(bytes[ii * 4..ii * 4 + 4].try_into().unwrap());
+        CpuidResult { eax: reg(0), ebx: reg(1), ecx: reg(2), edx: reg(3) }
+    }
+
+    #[test]
+    fn test_intel() {
+        let leaves = [
+            (0, CpuidResult { eax: 0xd, ebx: 0x756e_6547, ecx: 0x6c65_746e, edx: 0x4965_6e69 }),
+            (1, CpuidResult { eax: 0x0009_06ea, ebx: 0,
+                ecx: (1 << 21) | (1 << 26) | (1 << 30), edx: (1 << 6) | (1 << 25) }),
+            (7, CpuidResult { eax: 0, ebx: (1 << 7) | (1 << 20), ecx: 0, edx: 0 }),
+            (0x8000_0000, CpuidResult { eax: 0x8000_0008, ..Default::default() }),
+            (0x8000_0001, CpuidResult { edx: (1 << 20) | (1 << 26), ..Default::default() }),
+            (0x8000_0002, brand(b"  Fake(R) Core(T")),
+            (0x8000_0003, brand(b"M) CPU @ 3.00GHz")),
+            (0x8000_0004, brand(b"\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0")),
+            (0x8000_0007, CpuidResult { edx: 1 << 8, ..Default::default() }),
+            (0x8000_0008, CpuidResult { eax: 0x3027, ..Default::default() }),
+        ];
+
+        let cpu = CpuFeatures::from_cpuid(fake(&leaves));
+        assert_eq!(cpu.vendor, Vendor::Intel);
+        assert_eq!((cpu.family, cpu.model, cpu.stepping), (6, 0x9e, 0xa));
+        assert_eq!(cpu.brand(), Some("Fake(R) Core(TM) CPU @ 3.00GHz"));
+        assert_eq!((cpu.max_phys_addr_bits, cpu.max_virt_addr_bits), (39, 48));
+
+        let f = cpu.feature;
+        assert!(f.pae && f.sse && f.x2apic && f.xsave && f.rdrand);
+        assert!(f.smep && f.smap && f.nx && f.page1gb && f.invariant_tsc);
+        assert!(!f.vmx && !f.svm && !f.avx && !f.rdseed && !f.long_mode);
+    }
+
+    #[test]
+    fn test_old_cpu() {
+        // An AMD CPU with only leaf 1 and no extended leaves, none of the missing leaves may be
+        // queried
+        let leaves = [
+            (0, CpuidResult { eax: 1, ebx: 0x6874_7541, ecx: 0x444d_4163, edx: 0x6974_6e65 }),
+            (1, CpuidResult { eax: 0x0020_0f32, edx: 1 << 6, ..Default::default() }),
+            (0x8000_0000, CpuidResult::default()),
+        ];
+
+        let cpu = CpuFeatures::from_cpuid(fake(&leaves));
+        assert_eq!(cpu.vendor, Vendor::Amd);
+        assert_eq!((cpu.family, cpu.model, cpu.stepping), (0x11, 0x3, 0x2));
+        assert_eq!(cpu.brand(), None);
+        assert_eq!((cpu.max_phys_addr_bits, cpu.max_virt_addr_bits), (36, 32));
+        assert!(!cpu.feature.nx && !cpu.feature.smep);
+    }
+
+    #[test]
+    fn test_other_vendor() {
+        let leaves = [
+            (0, CpuidResult { eax: 0, ebx: 0x6f67_7948, ecx: 0x656e_6975, edx: 0x6e65_476e }),
+            (0x8000_0000, CpuidResult::default()),
+        ];
+
+        let cpu = CpuFeatures::from_cpuid(fake(&leaves));
+        assert_eq!(cpu.vendor, Vendor::Other(*b"HygonGenuine"));
+    }
+
+    #[test]
+    fn test_cached() {
+        assert!(core::ptr::eq(features(), features()));
+        assert_eq!(*features(), CpuFeatures::detect());
+    }
+}
@@ -2,13 +2,45 @@
 #![no_std]
 use core::arch::asm;
 
-const IA32_APIC_BASE: u32 = 0x1b;
-const IA32_GS_BASE: u32 = 0xc000_0101;
+pub mod cpuid;
+pub mod msr;
+
+pub use cpuid::{cpuid, features, CpuFeatures};
+
+/// CR0 monitor coprocessor, emulation, task switched and native x87 error bits
+pub const CR0_MP: usize = 1 << 1;
+pub const CR0_EM: usize = 1 << 2;
+pub const CR0_TS: usize = 1 << 3;
+pub const CR0_NE: usize = 1 << 5;
+
+/// CR0 write protect bit, makes read-only pages read-only for the kernel too
+pub const CR0_WP: usize = 1 << 16;
+
+/// CR0 not write-through and cache disable bits
+pub const CR0_NW: usize = 1 << 29;
+pub const CR0_CD: usize = 1 << 30;
+
+/// CR4 bits enabling `fxsave`, unmasked SSE exceptions and `xsave`
+pub const CR4_OSFXSR: usize = 1 << 9;
+pub const CR4_OSXMMEXCPT: usize = 1 << 10;
+pub const CR4_OSXSAVE: usize = 1 << 18;
+
+/// CR4 supervisor mode execution and access prevention bits
+pub const CR4_SMEP: usize = 1 << 20;
+pub const CR4_SMAP: usize = 1 << 21;
+
+/// XCR0 state components, x87, SSE, AVX and the three AVX-512 components
+pub const XCR0_X87: u64 = 1 << 0;
+pub const XCR0_SSE: u64 = 1 << 1;
+pub const XCR0_AVX: u64 = 1 << 2;
+pub const XCR0_OPMASK: u64 = 1 << 5;
+pub const XCR0_ZMM_HI256: u64 = 1 << 6;
+pub const XCR0_HI16_ZMM: u64 = 1 << 7;
 
 /// Returns true is the current CPU is the BSP, otherwise returns false
 #[inline]
 pub fn is_bsp() -> bool {
-    (unsafe { rdmsr(IA32_APIC_BASE) } & (1 << 8)) != 0
+    unsafe { msr::ApicBase::read() }.bsp()
 }
 
 /// Output `val` to I/O port `addr`
@@ -34,6 +66,52 @@ pub unsafe fn in8(addr: u16) -> u8 {
     val
 }
 
+/// Output `val` to I/O port `addr`
+#[inline]
+pub unsafe fn out16(addr: u16, val: u16) {
+    asm!(
+        r#"out dx, ax"#,
+        in("dx") addr,
+        in("ax") val,
+    );
+}
+
+/// Read a 16-bit value from I/O port `addr`
+#[inline]
+pub unsafe fn in16(addr: u16) -> u16 {
+    let val: u16;
+    asm!(
+        r#"in ax, dx"#,
+        out("ax") val,
+        in("dx") addr,
+    );
+
+    val
+}
+
+/// Output `val` to I/O port `addr`
+#[inline]
+pub unsafe fn out32(addr: u16, val: u32) {
+    asm!(
+        r#"out dx, eax"#,
+        in("dx") addr,
+        in("eax") val,
+    );
+}
+
+/// Read a 32-bit value from I/O port `addr`
+#[inline]
+pub unsafe fn in32(addr: u16) -> u32 {
+    let val: u32;
+    asm!(
+        r#"in eax, dx"#,
+        out("eax") val,
+        in("dx") addr,
+    );
+
+    val
+}
+
 /// Invalidate a page table entry
 #[inline]
 pub unsafe fn invlpg(addr: usize) {
@@ -43,6 +121,42 @@ pub unsafe fn invlpg(addr: usize) {
     );
 }
 
+/// Types of invalidation performed by `invpcid`
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvpcidType {
+    /// Invalidate the mapping of one address for one PCID
+    Address = 0,
+
+    /// Invalidate all mappings for one PCID, except global ones
+    SingleContext = 1,
+
+    /// Invalidate all mappings for all PCIDs, including global ones
+    AllContextGlobal = 2,
+
+    /// Invalidate all mappings for all PCIDs, except global ones
+    AllContext = 3,
+}
+
+/// Invalidate TLB entries tagged with `pcid`. `addr` is only used for `InvpcidType::Address`.
+/// The caller must make sure `invpcid` is supported.
+#[inline]
+pub unsafe fn invpcid(typ: InvpcidType, pcid: u16, addr: u64) {
+    // The descriptor is a 128-bit value with the PCID in the low 12 bits, followed by the address
+    let desc: [u64; 2] = [pcid as u64 & 0xfff, addr];
+    asm!(
+        r#"invpcid {}, [{}]"#,
+        in(reg) typ as usize,
+        in(reg) desc.as_ptr(),
+    );
+}
+
+/// Write back and invalidate all caches
+#[inline]
+pub unsafe fn wbinvd() {
+    asm!("wbinvd");
+}
+
 /// Write an MSR
 #[inline]
 pub unsafe fn wrmsr(msr: u32, val: u64) {
@@ -65,10 +179,348 @@ pub unsafe fn rdmsr(msr: u32) -> u64 {
     (val_lo as u64 | ((val_hi as u64) << 32)) as u64
 }
 
+/// Read the time stamp counter
+#[inline]
+pub fn rdtsc() -> u64 {
+    let val_lo: u32;
+    let val_hi: u32;
+    unsafe {
+        asm!("rdtsc", out("edx") val_hi, out("eax") val_lo);
+    }
+
+    val_lo as u64 | ((val_hi as u64) << 32)
+}
+
+/// Read the time stamp counter and the `IA32_TSC_AUX` of the current core, which is usually set
+/// to the core number. Unlike `rdtsc` this waits for all previous instructions to execute. The
+/// caller must make sure `rdtscp` is supported.
+#[inline]
+pub unsafe fn rdtscp() -> (u64, u32) {
+    let val_lo: u32;
+    let val_hi: u32;
+    let aux: u32;
+    asm!("rdtscp", out("edx") val_hi, out("eax") val_lo, out("ecx") aux);
+
+    (val_lo as u64 | ((val_hi as u64) << 32), aux)
+}
+
+/// Hint to the CPU that we are in a spin loop
+#[inline]
+pub fn pause() {
+    unsafe { asm!("pause"); }
+}
+
+/// Read an extended control register. The caller must make sure `CR4.OSXSAVE` is set.
+#[inline]
+pub unsafe fn xgetbv(xcr: u32) -> u64 {
+    let val_lo: u32;
+    let val_hi: u32;
+    asm!("xgetbv", in("ecx") xcr, out("edx") val_hi, out("eax") val_lo);
+
+    val_lo as u64 | ((val_hi as u64) << 32)
+}
+
+/// Write an extended control register. The caller must make sure `CR4.OSXSAVE` is set.
+#[inline]
+pub unsafe fn xsetbv(xcr: u32, val: u64) {
+    asm!("xsetbv", in("ecx") xcr, in("edx") (val >> 32) as u32, in("eax") val as u32);
+}
+
+/// Save the state components in `mask` to the 64-byte aligned XSAVE area at `area`. The caller
+/// must make sure `CR4.OSXSAVE` is set and the area is large enough for the enabled components.
+#[inline]
+pub unsafe fn xsave(area: *mut u8, mask: u64) {
+    #[cfg(target_arch = "x86_64")]
+    asm!("xsave64 [{}]", in(reg) area, in("edx") (mask >> 32) as u32, in("eax") mask as u32);
+
+    #[cfg(target_arch = "x86")]
+    asm!("xsave [{}]", in(reg) area, in("edx") (mask >> 32) as u32, in("eax") mask as u32);
+}
+
+/// Restore the state components in `mask` from the XSAVE area at `area`
+#[inline]
+pub unsafe fn xrstor(area: *const u8, mask: u64) {
+    #[cfg(target_arch = "x86_64")]
+    asm!("xrstor64 [{}]", in(reg) area, in("edx") (mask >> 32) as u32, in("eax") mask as u32);
+
+    #[cfg(target_arch = "x86")]
+    asm!("xrstor [{}]", in(reg) area, in("edx") (mask >> 32) as u32, in("eax") mask as u32);
+}
+
+/// Save the x87 and SSE state to the 16-byte aligned, 512 byte area at `area`
+#[inline]
+pub unsafe fn fxsave(area: *mut u8) {
+    #[cfg(target_arch = "x86_64")]
+    asm!("fxsave64 [{}]", in(reg) area);
+
+    #[cfg(target_arch = "x86")]
+    asm!("fxsave [{}]", in(reg) area);
+}
+
+/// Restore the x87 and SSE state from the area at `area`
+#[inline]
+pub unsafe fn fxrstor(area: *const u8) {
+    #[cfg(target_arch = "x86_64")]
+    asm!("fxrstor64 [{}]", in(reg) area);
+
+    #[cfg(target_arch = "x86")]
+    asm!("fxrstor [{}]", in(reg) area);
+}
+
+/// Reset the x87 FPU to its default state
+#[inline]
+pub unsafe fn fninit() {
+    asm!("fninit");
+}
+
+/// Load the SSE control and status register
+#[inline]
+pub unsafe fn ldmxcsr(val: u32) {
+    asm!("ldmxcsr [{}]", in(reg) &val);
+}
+
+/// Returns true if the CPU supports the `rdrand` instruction
+#[inline]
+pub fn has_rdrand() -> bool {
+    features().feature.rdrand
+}
+
+/// Get a 32-bit random number from the hardware random number generator. Returns `None` if the
+/// generator did not have entropy available. The caller must make sure `rdrand` is supported.
+#[inline]
+pub unsafe fn rdrand() -> Option<u32> {
+    let val: u32;
+    let ok: u8;
+    asm!(
+        "rdrand {val:e}",
+        "setc {ok}",
+        val = out(reg) val,
+        ok = out(reg_byte) ok,
+    );
+
+    if ok != 0 { Some(val) } else { None }
+}
+
+/// Returns true if the CPU supports the `rdseed` instruction
+#[inline]
+pub fn has_rdseed() -> bool {
+    features().feature.rdseed
+}
+
+/// Get a 32-bit random seed from the hardware entropy source. Returns `None` if the source did
+/// not have entropy available. The caller must make sure `rdseed` is supported.
+#[inline]
+pub unsafe fn rdseed() -> Option<u32> {
+    let val: u32;
+    let ok: u8;
+    asm!(
+        "rdseed {val:e}",
+        "setc {ok}",
+        val = out(reg) val,
+        ok = out(reg_byte) ok,
+    );
+
+    if ok != 0 { Some(val) } else { None }
+}
+
+/// Returns true if the CPU supports the no-execute page table bit
+#[inline]
+pub fn has_nx() -> bool {
+    features().feature.nx
+}
+
+/// Returns true if the CPU supports supervisor mode execution prevention
+#[inline]
+pub fn has_smep() -> bool {
+    features().feature.smep
+}
+
+/// Returns true if the CPU supports supervisor mode access prevention
+#[inline]
+pub fn has_smap() -> bool {
+    features().feature.smap
+}
+
+/// Read CR0
+#[inline]
+pub fn read_cr0() -> usize {
+    let val: usize;
+    unsafe { asm!("mov {}, cr0", out(reg) val); }
+    val
+}
+
+/// Write CR0
+#[inline]
+pub unsafe fn write_cr0(val: usize) {
+    asm!("mov cr0, {}", in(reg) val);
+}
+
+/// Read CR2, the address of the last page fault
+#[inline]
+pub fn read_cr2() -> usize {
+    let val: usize;
+    unsafe { asm!("mov {}, cr2", out(reg) val); }
+    val
+}
+
+/// Write CR2
+#[inline]
+pub unsafe fn write_cr2(val: usize) {
+    asm!("mov cr2, {}", in(reg) val);
+}
+
+/// Read CR3
+#[inline]
+pub fn read_cr3() -> usize {
+    let val: usize;
+    unsafe { asm!("mov {}, cr3", out(reg) val); }
+    val
+}
+
+/// Write CR3, switching to a new page table
+#[inline]
+pub unsafe fn write_cr3(val: usize) {
+    asm!("mov cr3, {}", in(reg) val);
+}
+
+/// Read CR4
+#[inline]
+pub fn read_cr4() -> usize {
+    let val: usize;
+    unsafe { asm!("mov {}, cr4", out(reg) val); }
+    val
+}
+
+/// Write CR4
+#[inline]
+pub unsafe fn write_cr4(val: usize) {
+    asm!("mov cr4, {}", in(reg) val);
+}
+
+/// Read the extended feature enable register
+#[inline]
+pub fn read_efer() -> msr::Efer {
+    unsafe { msr::Efer::read() }
+}
+
+/// Write the extended feature enable register
+#[inline]
+pub unsafe fn write_efer(val: msr::Efer) {
+    val.write();
+}
+
+/// RFLAGS interrupt enable flag
+pub const RFLAGS_IF: usize = 1 << 9;
+
+/// Read RFLAGS
+#[inline]
+pub fn read_rflags() -> usize {
+    let val: usize;
+    unsafe {
+        #[cfg(target_arch = "x86_64")]
+        asm!("pushfq", "pop {}", out(reg) val);
+
+        #[cfg(target_arch = "x86")]
+        asm!("pushfd", "pop {}", out(reg) val);
+    }
+    val
+}
+
+/// Returns true if interrupts are enabled on the current core
+#[inline]
+pub fn interrupts_enabled() -> bool {
+    read_rflags() & RFLAGS_IF != 0
+}
+
+/// Disable interrupts
+#[inline]
+pub unsafe fn cli() {
+    asm!("cli");
+}
+
+/// Enable interrupts
+#[inline]
+pub unsafe fn sti() {
+    asm!("sti");
+}
+
+/// Disable interrupts, returning the RFLAGS from before they were disabled. Pass them to
+/// `restore_interrupts` to go back to the previous state.
+#[inline]
+pub unsafe fn save_and_cli() -> usize {
+    let rflags = read_rflags();
+    cli();
+    rflags
+}
+
+/// Enable interrupts if they were enabled in the RFLAGS returned by `save_and_cli`
+#[inline]
+pub unsafe fn restore_interrupts(rflags: usize) {
+    if rflags & RFLAGS_IF != 0 {
+        sti();
+    }
+}
+
+/// Run `func` with interrupts disabled, restoring the interrupt state afterwards
+#[inline]
+pub fn without_interrupts<R, F: FnOnce() -> R>(func: F) -> R {
+    unsafe {
+        let rflags = save_and_cli();
+        let ret = func();
+        restore_interrupts(rflags);
+        ret
+    }
+}
+
+/// Pointer to a descriptor table, as used by `lgdt` and `lidt`
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DescriptorTablePointer {
+    /// Size of the table in bytes minus one
+    pub limit: u16,
+
+    /// Linear address of the table
+    pub base: usize,
+}
+
+/// Load the global descriptor table
+#[inline]
+pub unsafe fn lgdt(gdt: &DescriptorTablePointer) {
+    asm!("lgdt [{}]", in(reg) gdt);
+}
+
+/// Load the interrupt descriptor table
+#[inline]
+pub unsafe fn lidt(idt: &DescriptorTablePointer) {
+    asm!("lidt [{}]", in(reg) idt);
+}
+
+/// Get the current global descriptor table
+#[inline]
+pub fn sgdt() -> DescriptorTablePointer {
+    let mut gdt = DescriptorTablePointer::default();
+    unsafe { asm!("sgdt [{}]", in(reg) &mut gdt); }
+    gdt
+}
+
+/// Get the current interrupt descriptor table
+#[inline]
+pub fn sidt() -> DescriptorTablePointer {
+    let mut idt = DescriptorTablePointer::default();
+    unsafe { asm!("sidt [{}]", in(reg) &mut idt); }
+    idt
+}
+
+/// Load the task register with the TSS `selector` from the GDT
+#[inline]
+pub unsafe fn ltr(selector: u16) {
+    asm!("ltr {:x}", in(reg) selector);
+}
+
 /// Set the GS base
 #[inline]
 pub unsafe fn set_gs_base(base: u64) {
-    wrmsr(IA32_GS_BASE, base);
+    msr::IA32_GS_BASE.write(base);
 }
 
 /// Disable inrettupts and halt forever
@@ -0,0 +1,499 @@
+//! Model specific registers. Every MSR we use has a name here, and the ones with fields we care
+//! about have a typed wrapper with accessors for them.
+//!
+//! Accessing an MSR the CPU does not implement, or writing a reserved bit, raises a #GP. `probe`
+//! and `try_write` recover from that and return an error instead, as long as the #GP handler asks
+//! `fixup_gp` where to resume execution.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// An MSR address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Msr(pub u32);
+
+/// An MSR access raised a general protection fault
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GpFault {
+    /// The MSR which was accessed
+    pub msr: Msr,
+}
+
+pub const IA32_TSC: Msr = Msr(0x10);
+pub const IA32_APIC_BASE: Msr = Msr(0x1b);
+pub const IA32_FEATURE_CONTROL: Msr = Msr(0x3a);
+pub const IA32_MTRRCAP: Msr = Msr(0xfe);
+pub const IA32_MISC_ENABLE: Msr = Msr(0x1a0);
+pub const IA32_PAT: Msr = Msr(0x277);
+pub const IA32_MTRR_DEF_TYPE: Msr = Msr(0x2ff);
+pub const IA32_TSC_DEADLINE: Msr = Msr(0x6e0);
+
+/// Fixed range MTRRs, covering the first 1 MiB of memory
+pub const IA32_MTRR_FIX64K_00000: Msr = Msr(0x250);
+pub const IA32_MTRR_FIX16K_80000: Msr = Msr(0x258);
+pub const IA32_MTRR_FIX16K_A0000: Msr = Msr(0x259);
+pub const IA32_MTRR_FIX4K_C0000: Msr = Msr(0x268);
+pub const IA32_MTRR_FIX4K_C8000: Msr = Msr(0x269);
+pub const IA32_MTRR_FIX4K_D0000: Msr = Msr(0x26a);
+pub const IA32_MTRR_FIX4K_D8000: Msr = Msr(0x26b);
+pub const IA32_MTRR_FIX4K_E0000: Msr = Msr(0x26c);
+pub const IA32_MTRR_FIX4K_E8000: Msr = Msr(0x26d);
+pub const IA32_MTRR_FIX4K_F0000: Msr = Msr(0x26e);
+pub const IA32_MTRR_FIX4K_F8000: Msr = Msr(0x26f);
+
+/// VMX capability reporting MSRs
+pub const IA32_VMX_BASIC: Msr = Msr(0x480);
+pub const IA32_VMX_PINBASED_CTLS: Msr = Msr(0x481);
+pub const IA32_VMX_PROCBASED_CTLS: Msr = Msr(0x482);
+pub const IA32_VMX_EXIT_CTLS: Msr = Msr(0x483);
+pub const IA32_VMX_ENTRY_CTLS: Msr = Msr(0x484);
+pub const IA32_VMX_MISC: Msr = Msr(0x485);
+pub const IA32_VMX_CR0_FIXED0: Msr = Msr(0x486);
+pub const IA32_VMX_CR0_FIXED1: Msr = Msr(0x487);
+pub const IA32_VMX_CR4_FIXED0: Msr = Msr(0x488);
+pub const IA32_VMX_CR4_FIXED1: Msr = Msr(0x489);
+pub const IA32_VMX_VMCS_ENUM: Msr = Msr(0x48a);
+pub const IA32_VMX_PROCBASED_CTLS2: Msr = Msr(0x48b);
+pub const IA32_VMX_EPT_VPID_CAP: Msr = Msr(0x48c);
+pub const IA32_VMX_TRUE_PINBASED_CTLS: Msr = Msr(0x48d);
+pub const IA32_VMX_TRUE_PROCBASED_CTLS: Msr = Msr(0x48e);
+pub const IA32_VMX_TRUE_EXIT_CTLS: Msr = Msr(0x48f);
+pub const IA32_VMX_TRUE_ENTRY_CTLS: Msr = Msr(0x490);
+pub const IA32_VMX_VMFUNC: Msr = Msr(0x491);
+
+pub const IA32_EFER: Msr = Msr(0xc000_0080);
+pub const IA32_FS_BASE: Msr = Msr(0xc000_0100);
+pub const IA32_GS_BASE: Msr = Msr(0xc000_0101);
+pub const IA32_KERNEL_GS_BASE: Msr = Msr(0xc000_0102);
+pub const IA32_TSC_AUX: Msr = Msr(0xc000_0103);
+
+impl Msr {
+    /// Variable range MTRR base register `index`
+    pub const fn mtrr_physbase(index: u32) -> Msr {
+        Msr(0x200 + index * 2)
+    }
+
+    /// Variable range MTRR mask register `index`
+    pub const fn mtrr_physmask(index: u32) -> Msr {
+        Msr(0x201 + index * 2)
+    }
+
+    /// Read the MSR. The caller must make sure the MSR exists, otherwise this faults.
+    #[inline]
+    pub unsafe fn read(self) -> u64 {
+        crate::rdmsr(self.0)
+    }
+
+    /// Write the MSR. The caller must make sure the MSR exists and `val` is valid for it.
+    #[inline]
+    pub unsafe fn write(self, val: u64) {
+        crate::wrmsr(self.0, val)
+    }
+
+    /// Read the MSR, returning an error if the read faults
+    #[inline]
+    pub fn probe(self) -> Result<u64, GpFault> {
+        let (ok, val) = unsafe { probe_rdmsr(self.0) };
+        if ok { Ok(val) } else { Err(GpFault { msr: self }) }
+    }
+
+    /// Write the MSR, returning an error if the write faults. The caller must make sure that
+    /// writing `val` does not break any assumption made by the rest of the code.
+    #[inline]
+    pub unsafe fn try_write(self, val: u64) -> Result<(), GpFault> {
+        if probe_wrmsr(self.0, val) { Ok(()) } else { Err(GpFault { msr: self }) }
+    }
+}
+
+/// Addresses of the faulting instruction and where to resume after it, for the `rdmsr` and
+/// `wrmsr` in the probing routines. These are filled in by the routines themselves before they
+/// access the MSR.
+static PROBE_SITES: [[AtomicUsize; 2]; 2] = [
+    [AtomicUsize::new(0), AtomicUsize::new(0)],
+    [AtomicUsize::new(0), AtomicUsize::new(0)],
+];
+
+/// Get the address to resume execution at for a #GP at `rip`, if it was raised by `probe` or
+/// `try_write`. The #GP handler must call this and return to the address if there is one.
+pub fn fixup_gp(rip: usize) -> Option<usize> {
+    PROBE_SITES.iter().find_map(|[insn, resume]| {
+        let insn = insn.load(Ordering::Relaxed);
+        if insn != 0 && insn == rip { Some(resume.load(Ordering::Relaxed)) } else { None }
+    })
+}
+
+/// Size of a pointer, the stride of the entries in `PROBE_SITES`
+const PTR_SIZE: usize = core::mem::size_of::<usize>();
+
+/// Execute `rdmsr`, returning `false` if it faulted. This must never be inlined such that there
+/// is only one copy of the faulting instruction.
+#[inline(never)]
+unsafe fn probe_rdmsr(msr: u32) -> (bool, u64) {
+    let ok: usize;
+    let val_lo: u32;
+    let val_hi: u32;
+
+    // If the `rdmsr` faults the #GP handler resumes at `3:`, skipping setting `ok`
+    #[cfg(target_arch = "x86_64")]
+    asm!(
+        "lea {tmp}, [rip + 2f]",
+        "mov [{site}], {tmp}",
+        "lea {tmp}, [rip + 3f]",
+        "mov [{site} + {ptr}], {tmp}",
+        "xor {tmp:e}, {tmp:e}",
+        "xor eax, eax",
+        "xor edx, edx",
+        "2:",
+        "rdmsr",
+        "mov {tmp:e}, 1",
+        "3:",
+        site = in(reg) &PROBE_SITES[0],
+        ptr = const PTR_SIZE,
+        tmp = out(reg) ok,
+        in("ecx") msr,
+        out("eax") val_lo,
+        out("edx") val_hi,
+    );
+
+    #[cfg(target_arch = "x86")]
+    asm!(
+        "lea {tmp}, [2f]",
+        "mov [{site}], {tmp}",
+        "lea {tmp}, [3f]",
+        "mov [{site} + {ptr}], {tmp}",
+        "xor {tmp:e}, {tmp:e}",
+        "xor eax, eax",
+        "xor edx, edx",
+        "2:",
+        "rdmsr",
+        "mov {tmp:e}, 1",
+        "3:",
+        site = in(reg) &PROBE_SITES[0],
+        ptr = const PTR_SIZE,
+        tmp = out(reg) ok,
+        in("ecx") msr,
+        out("eax") val_lo,
+        out("edx") val_hi,
+    );
+
+    (ok != 0, val_lo as u64 | ((val_hi as u64) << 32))
+}
+
+/// Execute `wrmsr`, returning `false` if it faulted. This must never be inlined such that there
+/// is only one copy of the faulting instruction.
+#[inline(never)]
+unsafe fn probe_wrmsr(msr: u32, val: u64) -> bool {
+    let ok: usize;
+
+    // If the `wrmsr` faults the #GP handler resumes at `3:`, skipping setting `ok`
+    #[cfg(target_arch = "x86_64")]
+    asm!(
+        "lea {tmp}, [rip + 2f]",
+        "mov [{site}], {tmp}",
+        "lea {tmp}, [rip + 3f]",
+        "mov [{site} + {ptr}], {tmp}",
+        "xor {tmp:e}, {tmp:e}",
+        "2:",
+        "wrmsr",
+        "mov {tmp:e}, 1",
+        "3:",
+        site = in(reg) &PROBE_SITES[1],
+        ptr = const PTR_SIZE,
+        tmp = out(reg) ok,
+        in("ecx") msr,
+        in("eax") val as u32,
+        in("edx") (val >> 32) as u32,
+    );
+
+    #[cfg(target_arch = "x86")]
+    asm!(
+        "lea {tmp}, [2f]",
+        "mov [{site}], {tmp}",
+        "lea {tmp}, [3f]",
+        "mov [{site} + {ptr}], {tmp}",
+        "xor {tmp:e}, {tmp:e}",
+        "2:",
+        "wrmsr",
+        "mov {tmp:e}, 1",
+        "3:",
+        site = in(reg) &PROBE_SITES[1],
+        ptr = const PTR_SIZE,
+        tmp = out(reg) ok,
+        in("ecx") msr,
+        in("eax") val as u32,
+        in("edx") (val >> 32) as u32,
+    );
+
+    ok != 0
+}
+
+/// Define a typed wrapper around the value of an MSR, with a getter and setter for each of the
+/// single bit fields listed
+macro_rules! register {
+    (
+        $(#[$meta:meta])*
+        $name:ident = $msr:expr;
+        $($(#[$fmeta:meta])* $get:ident, $set:ident: $bit:expr;)*
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+        pub struct $name(pub u64);
+
+        impl $name {
+            /// The MSR this value is stored in
+            pub const MSR: Msr = $msr;
+
+            /// Read the MSR. The caller must make sure the MSR exists.
+            #[inline]
+            pub unsafe fn read() -> Self {
+                Self(Self::MSR.read())
+            }
+
+            /// Read the MSR, returning an error if the read faults
+            #[inline]
+            pub fn probe() -> Result<Self, GpFault> {
+                Self::MSR.probe().map(Self)
+            }
+
+            /// Write this value to the MSR
+            #[inline]
+            pub unsafe fn write(self) {
+                Self::MSR.write(self.0)
+            }
+
+            $(
+                $(#[$fmeta])*
+                #[inline]
+                pub fn $get(self) -> bool {
+                    self.0 & (1 << $bit) != 0
+                }
+
+                $(#[$fmeta])*
+                #[inline]
+                pub fn $set(&mut self, val: bool) {
+                    if val { self.0 |= 1 << $bit; } else { self.0 &= !(1 << $bit); }
+                }
+            )*
+        }
+    }
+}
+
+register! {
+    /// Local APIC base address and mode
+    ApicBase = IA32_APIC_BASE;
+
+    /// This core is the bootstrap processor
+    bsp, set_bsp: 8;
+
+    /// The APIC is in x2APIC mode
+    x2apic, set_x2apic: 10;
+
+    /// The APIC is globally enabled
+    enabled, set_enabled: 11;
+}
+
+impl ApicBase {
+    /// Mask of the physical base address of the APIC registers
+    const BASE_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+    /// Physical address of the APIC registers
+    pub fn base(self) -> u64 {
+        self.0 & Self::BASE_MASK
+    }
+
+    /// Set the physical address of the APIC registers, which must be page aligned
+    pub fn set_base(&mut self, base: u64) {
+        assert!(base & !Self::BASE_MASK == 0, "Invalid APIC base");
+        self.0 = (self.0 & !Self::BASE_MASK) | base;
+    }
+}
+
+register! {
+    /// Extended feature enable register
+    Efer = IA32_EFER;
+
+    /// `syscall` and `sysret` enable
+    sce, set_sce: 0;
+
+    /// Long mode enable
+    lme, set_lme: 8;
+
+    /// Long mode active, read-only
+    lma, set_lma: 10;
+
+    /// No-execute page table bit enable
+    nxe, set_nxe: 11;
+
+    /// AMD secure virtual machine enable
+    svme, set_svme: 12;
+}
+
+register! {
+    /// Miscellaneous feature enables, Intel only
+    MiscEnable = IA32_MISC_ENABLE;
+
+    /// Fast string operations enable
+    fast_strings, set_fast_strings: 0;
+
+    /// Performance monitoring available
+    perf_monitoring, set_perf_monitoring: 7;
+
+    /// Enhanced Intel SpeedStep enable
+    speedstep, set_speedstep: 16;
+
+    /// `monitor` and `mwait` enable
+    monitor, set_monitor: 18;
+
+    /// Limit the maximum basic `cpuid` leaf to 2
+    limit_cpuid, set_limit_cpuid: 22;
+
+    /// Disable the no-execute page table bit
+    xd_disable, set_xd_disable: 34;
+}
+
+register! {
+    /// Locks and enables of VMX and SMX
+    FeatureControl = IA32_FEATURE_CONTROL;
+
+    /// The register is locked until reset
+    locked, set_locked: 0;
+
+    /// VMX allowed inside of SMX operation
+    vmx_inside_smx, set_vmx_inside_smx: 1;
+
+    /// VMX allowed outside of SMX operation
+    vmx_outside_smx, set_vmx_outside_smx: 2;
+}
+
+register! {
+    /// Page attribute table, eight memory types selected by the PAT, PCD and PWT page table bits
+    Pat = IA32_PAT;
+}
+
+impl Pat {
+    /// Get the raw memory type of PAT entry `index`
+    pub fn entry(self, index: usize) -> u8 {
+        assert!(index < 8, "Invalid PAT index");
+        (self.0 >> (index * 8)) as u8 & 0x7
+    }
+
+    /// Set the raw memory type of PAT entry `index`
+    pub fn set_entry(&mut self, index: usize, typ: u8) {
+        assert!(index < 8 && typ & !0x7 == 0, "Invalid PAT entry");
+        self.0 = (self.0 & !(0xff << (index * 8))) | ((typ as u64) << (index * 8));
+    }
+}
+
+register! {
+    /// MTRR capabilities, read-only
+    MtrrCap = IA32_MTRRCAP;
+
+    /// Fixed range MTRRs are supported
+    fixed, set_fixed: 8;
+
+    /// The write-combining memory type is supported
+    write_combining, set_write_combining: 10;
+
+    /// System management range registers are supported
+    smrr, set_smrr: 11;
+}
+
+impl MtrrCap {
+    /// Number of variable range MTRRs
+    pub fn variable_count(self) -> u32 {
+        self.0 as u8 as u32
+    }
+}
+
+register! {
+    /// Default memory type and enables for the MTRRs
+    MtrrDefType = IA32_MTRR_DEF_TYPE;
+
+    /// Fixed range MTRRs enable
+    fixed_enabled, set_fixed_enabled: 10;
+
+    /// MTRRs enable
+    enabled, set_enabled: 11;
+}
+
+impl MtrrDefType {
+    /// Raw memory type of memory not covered by any MTRR
+    pub fn default_type(self) -> u8 {
+        self.0 as u8
+    }
+
+    /// Set the raw memory type of memory not covered by any MTRR
+    pub fn set_default_type(&mut self, typ: u8) {
+        self.0 = (self.0 & !0xff) | typ as u64;
+    }
+}
+
+register! {
+    /// Basic VMX information, read-only
+    VmxBasic = IA32_VMX_BASIC;
+
+    /// VM exits caused by `ins` and `outs` report the instruction information
+    ins_outs_reporting, set_ins_outs_reporting: 54;
+
+    /// The `IA32_VMX_TRUE_*` control MSRs are supported
+    true_controls, set_true_controls: 55;
+}
+
+impl VmxBasic {
+    /// Revision identifier to write to VMXON regions and VMCSs
+    pub fn revision_id(self) -> u32 {
+        self.0 as u32 & 0x7fff_ffff
+    }
+
+    /// Number of bytes to allocate for VMXON regions and VMCSs
+    pub fn vmcs_size(self) -> u32 {
+        (self.0 >> 32) as u32 & 0x1fff
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+
+    #[test]
+    fn test_fields() {
+        let mut apic = ApicBase(0xfee0_0900);
+        assert!(apic.bsp() && apic.enabled() && !apic.x2apic());
+        assert_eq!(apic.base(), 0xfee0_0000);
+
+        apic.set_bsp(false);
+        apic.set_x2apic(true);
+        apic.set_base(0x1234_5000);
+        assert_eq!(apic.0, 0x1234_5c00);
+
+        let mut pat = Pat(0x0007_0406_0007_0406);
+        assert_eq!((0..8).map(|ii| pat.entry(ii)).collect::<std::vec::Vec<_>>(),
+            [6, 4, 7, 0, 6, 4, 7, 0]);
+        pat.set_entry(3, 1);
+        assert_eq!(pat.0, 0x0007_0406_0107_0406);
+
+        assert_eq!(Msr::mtrr_physbase(3), Msr(0x206));
+        assert_eq!(Msr::mtrr_physmask(3), Msr(0x207));
+        assert_eq!(VmxBasic(0x00da_0400_0000_0004).vmcs_size(), 0x400);
+    }
+
+    #[test]
+    fn test_fixup() {
+        // Without having probed anything there is nothing to fix up
+        assert_eq!(fixup_gp(0), None);
+
+        // Record a probe site as the probes do, only its exact instruction address is fixed up.
+        // The empty site must not match a zero RIP either.
+        PROBE_SITES[1][0].store(0x1000, Ordering::Relaxed);
+        PROBE_SITES[1][1].store(0x1010, Ordering::Relaxed);
+        assert_eq!(fixup_gp(0x1000), Some(0x1010));
+        for &rip in &[0, 0xfff, 0x1001, 0x1010] {
+            assert_eq!(fixup_gp(rip), None);
+        }
+
+        PROBE_SITES[1][0].store(0, Ordering::Relaxed);
+        assert_eq!(fixup_gp(0x1000), None);
+    }
+}
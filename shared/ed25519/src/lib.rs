@@ -0,0 +1,536 @@
+//! Ed25519 signatures, as specified in RFC 8032. Verification is what the bootloader needs,
+//! signing is provided for the build tool. Nothing here runs in constant time, so it must not be
+//! used to sign on a machine where an attacker can measure how long signing takes.
+#![no_std]
+
+use core::convert::TryInto;
+use sha2::Sha512;
+
+/// Size of a public key in bytes
+pub const PUBLIC_KEY_SIZE: usize = 32;
+
+/// Size of a secret key (the seed the signing key is derived from) in bytes
+pub const SECRET_KEY_SIZE: usize = 32;
+
+/// Size of a signature in bytes
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// Mask of the 51 bits held by every limb of a field element
+const MASK51: u64 = (1 << 51) - 1;
+
+/// An element of the field of integers modulo `p = 2^255 - 19`, in 5 limbs of 51 bits. Limbs may
+/// exceed 51 bits by a little between carries.
+#[derive(Clone, Copy)]
+struct Fe([u64; 5]);
+
+/// The curve constant `d = -121665 / 121666`
+const D: Fe = Fe([929955233495203, 466365720129213, 1662059464998953, 2033849074728123,
+    1442794654840575]);
+
+/// `2 * d`
+const D2: Fe = Fe([1859910466990425, 932731440258426, 1072319116312658, 1815898335770999,
+    633789495995903]);
+
+/// A square root of -1
+const SQRT_M1: Fe = Fe([1718705420411056, 234908883556509, 2233514472574048, 2117202627021982,
+    765476049583133]);
+
+/// `p - 2`, the exponent which inverts a field element, little endian
+const P_MINUS_2: [u8; 32] = {
+    let mut exp = [0xff; 32];
+    exp[0] = 0xeb;
+    exp[31] = 0x7f;
+    exp
+};
+
+/// `(p - 5) / 8`, the exponent used to compute square roots, little endian
+const P_MINUS_5_DIV_8: [u8; 32] = {
+    let mut exp = [0xff; 32];
+    exp[0] = 0xfd;
+    exp[31] = 0x0f;
+    exp
+};
+
+impl Fe {
+    const ZERO: Fe = Fe([0; 5]);
+    const ONE: Fe = Fe([1, 0, 0, 0, 0]);
+
+    /// Load a field element from 32 little endian bytes, ignoring the top bit
+    fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let load = |offset: usize|
+            u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        Fe([
+            load(0) & MASK51,
+            (load(6) >> 3) & MASK51,
+            (load(12) >> 6) & MASK51,
+            (load(19) >> 1) & MASK51,
+            (load(24) >> 12) & MASK51,
+        ])
+    }
+
+    /// Get the canonical, fully reduced, little endian encoding of the element
+    fn to_bytes(self) -> [u8; 32] {
+        let mut limbs = self.carry().0;
+
+        // Find out whether the value is at least `p`, by checking if adding 19 carries out of bit
+        // 255, and if so subtract `p` by adding 19 and dropping bit 255
+        let mut q = (limbs[0] + 19) >> 51;
+        for limb in &limbs[1..] {
+            q = (limb + q) >> 51;
+        }
+        limbs[0] += 19 * q;
+        for ii in 0..4 {
+            limbs[ii + 1] += limbs[ii] >> 51;
+            limbs[ii] &= MASK51;
+        }
+        limbs[4] &= MASK51;
+
+        // Pack the 51-bit limbs into bytes
+        let mut bytes = [0u8; 32];
+        let mut acc: u128 = 0;
+        let mut acc_bits = 0;
+        let mut out = 0;
+        for limb in limbs {
+            acc |= (limb as u128) << acc_bits;
+            acc_bits += 51;
+            while acc_bits >= 8 && out < 32 {
+                bytes[out] = acc as u8;
+                acc >>= 8;
+                acc_bits -= 8;
+                out += 1;
+            }
+        }
+        if out < 32 {
+            bytes[out] = acc as u8;
+        }
+        bytes
+    }
+
+    /// Propagate the carries between limbs, such that every limb is below 2^52
+    fn carry(&self) -> Self {
+        let mut limbs = self.0;
+        for ii in 0..4 {
+            limbs[ii + 1] += limbs[ii] >> 51;
+            limbs[ii] &= MASK51;
+        }
+        limbs[0] += (limbs[4] >> 51) * 19;
+        limbs[4] &= MASK51;
+        limbs[1] += limbs[0] >> 51;
+        limbs[0] &= MASK51;
+        Fe(limbs)
+    }
+
+    fn add(&self, other: &Fe) -> Fe {
+        let mut limbs = self.0;
+        for (limb, other) in limbs.iter_mut().zip(other.0) {
+            *limb += other;
+        }
+        Fe(limbs).carry()
+    }
+
+    fn sub(&self, other: &Fe) -> Fe {
+        // Add `16 * p` first, such that the limbs do not underflow
+        const P16: [u64; 5] = [(MASK51 - 18) * 16, MASK51 * 16, MASK51 * 16, MASK51 * 16,
+            MASK51 * 16];
+        let mut limbs = self.0;
+        for ii in 0..5 {
+            limbs[ii] = limbs[ii] + P16[ii] - other.0[ii];
+        }
+        Fe(limbs).carry()
+    }
+
+    fn neg(&self) -> Fe {
+        Fe::ZERO.sub(self)
+    }
+
+    fn mul(&self, other: &Fe) -> Fe {
+        let a = self.0.map(|x| x as u128);
+        let b = other.0.map(|x| x as u128);
+
+        // Limbs which wrap around past 2^255 are multiplied by 19, as 2^255 = 19 mod p
+        let b1 = b[1] * 19;
+        let b2 = b[2] * 19;
+        let b3 = b[3] * 19;
+        let b4 = b[4] * 19;
+
+        let mut c = [
+            a[0] * b[0] + a[4] * b1 + a[3] * b2 + a[2] * b3 + a[1] * b4,
+            a[1] * b[0] + a[0] * b[1] + a[4] * b2 + a[3] * b3 + a[2] * b4,
+            a[2] * b[0] + a[1] * b[1] + a[0] * b[2] + a[4] * b3 + a[3] * b4,
+            a[3] * b[0] + a[2] * b[1] + a[1] * b[2] + a[0] * b[3] + a[4] * b4,
+            a[4] * b[0] + a[3] * b[1] + a[2] * b[2] + a[1] * b[3] + a[0] * b[4],
+        ];
+
+        for ii in 0..4 {
+            c[ii + 1] += c[ii] >> 51;
+            c[ii] &= MASK51 as u128;
+        }
+        c[0] += (c[4] >> 51) * 19;
+        c[4] &= MASK51 as u128;
+        c[1] += c[0] >> 51;
+        c[0] &= MASK51 as u128;
+
+        Fe(c.map(|x| x as u64))
+    }
+
+    fn square(&self) -> Fe {
+        self.mul(self)
+    }
+
+    /// Raise the element to the power `exp`, a little endian integer
+    fn pow(&self, exp: &[u8; 32]) -> Fe {
+        let mut result = Fe::ONE;
+        for bit in (0..256).rev() {
+            result = result.square();
+            if (exp[bit / 8] >> (bit % 8)) & 1 != 0 {
+                result = result.mul(self);
+            }
+        }
+        result
+    }
+
+    fn invert(&self) -> Fe {
+        self.pow(&P_MINUS_2)
+    }
+
+    /// Whether the canonical encoding of the element is odd, which RFC 8032 calls negative
+    fn is_negative(&self) -> bool {
+        self.to_bytes()[0] & 1 != 0
+    }
+
+    fn equals(&self, other: &Fe) -> bool {
+        self.to_bytes() == other.to_bytes()
+    }
+}
+
+/// A point on the curve in extended coordinates, `x = X / Z`, `y = Y / Z` and `x * y = T / Z`
+#[derive(Clone, Copy)]
+struct Point {
+    x: Fe,
+    y: Fe,
+    z: Fe,
+    t: Fe,
+}
+
+/// The base point of the curve
+const BASE: Point = Point {
+    x: Fe([1738742601995546, 1146398526822698, 2070867633025821, 562264141797630,
+        587772402128613]),
+    y: Fe([1801439850948184, 1351079888211148, 450359962737049, 900719925474099,
+        1801439850948198]),
+    z: Fe::ONE,
+    t: Fe([1841354044333475, 16398895984059, 755974180946558, 900171276175154,
+        1821297809914039]),
+};
+
+impl Point {
+    /// The neutral element
+    const IDENTITY: Point = Point { x: Fe::ZERO, y: Fe::ONE, z: Fe::ONE, t: Fe::ZERO };
+
+    /// Add two points. The formula is complete, so it also doubles a point added to itself.
+    fn add(&self, other: &Point) -> Point {
+        let a = self.y.sub(&self.x).mul(&other.y.sub(&other.x));
+        let b = self.y.add(&self.x).mul(&other.y.add(&other.x));
+        let c = self.t.mul(&D2).mul(&other.t);
+        let d = self.z.add(&self.z).mul(&other.z);
+        let e = b.sub(&a);
+        let f = d.sub(&c);
+        let g = d.add(&c);
+        let h = b.add(&a);
+
+        Point { x: e.mul(&f), y: g.mul(&h), z: f.mul(&g), t: e.mul(&h) }
+    }
+
+    fn neg(&self) -> Point {
+        Point { x: self.x.neg(), y: self.y, z: self.z, t: self.t.neg() }
+    }
+
+    /// Multiply the point by `scalar`, a little endian integer
+    fn mul(&self, scalar: &[u8; 32]) -> Point {
+        let mut result = Point::IDENTITY;
+        for bit in (0..256).rev() {
+            result = result.add(&result);
+            if (scalar[bit / 8] >> (bit % 8)) & 1 != 0 {
+                result = result.add(self);
+            }
+        }
+        result
+    }
+
+    /// Encode the point as the y coordinate, with the sign of x in the top bit
+    fn compress(&self) -> [u8; 32] {
+        let zinv = self.z.invert();
+        let x = self.x.mul(&zinv);
+        let y = self.y.mul(&zinv);
+
+        let mut bytes = y.to_bytes();
+        bytes[31] |= (x.is_negative() as u8) << 7;
+        bytes
+    }
+
+    /// Decode a point encoded with `compress`. Returns `None` if the encoding is not canonical or
+    /// not on the curve.
+    fn decompress(bytes: &[u8; 32]) -> Option<Point> {
+        let y = Fe::from_bytes(bytes);
+        let sign = bytes[31] >> 7 != 0;
+
+        // Reject a y coordinate which is not reduced
+        let mut canonical = *bytes;
+        canonical[31] &= 0x7f;
+        if y.to_bytes() != canonical {
+            return None;
+        }
+
+        // x^2 = (y^2 - 1) / (d * y^2 + 1), solved as x = u * v^3 * (u * v^7)^((p - 5) / 8)
+        let y2 = y.square();
+        let u = y2.sub(&Fe::ONE);
+        let v = D.mul(&y2).add(&Fe::ONE);
+        let v3 = v.square().mul(&v);
+        let v7 = v3.square().mul(&v);
+        let mut x = u.mul(&v3).mul(&u.mul(&v7).pow(&P_MINUS_5_DIV_8));
+
+        let vx2 = v.mul(&x.square());
+        if vx2.equals(&u) {
+            // x is the root
+        } else if vx2.equals(&u.neg()) {
+            x = x.mul(&SQRT_M1);
+        } else {
+            return None;
+        }
+
+        if x.equals(&Fe::ZERO) && sign {
+            return None;
+        }
+        if x.is_negative() != sign {
+            x = x.neg();
+        }
+
+        Some(Point { x, y, z: Fe::ONE, t: x.mul(&y) })
+    }
+}
+
+/// The order of the base point, `L = 2^252 + 27742317777372353535851937790883648493`, as little
+/// endian 64-bit words
+const L: [u64; 4] = [0x5812631a5cf5d3ed, 0x14def9dea2f79cd6, 0, 0x1000000000000000];
+
+/// Whether the 256-bit little endian integer `a` is at least `b`
+fn geq(a: &[u64; 4], b: &[u64; 4]) -> bool {
+    for ii in (0..4).rev() {
+        if a[ii] != b[ii] {
+            return a[ii] > b[ii];
+        }
+    }
+    true
+}
+
+/// Subtract `b` from `a` in place, `a` must be at least `b`
+fn sub_in_place(a: &mut [u64; 4], b: &[u64; 4]) {
+    let mut borrow = 0;
+    for ii in 0..4 {
+        let (val, b1) = a[ii].overflowing_sub(b[ii]);
+        let (val, b2) = val.overflowing_sub(borrow);
+        a[ii] = val;
+        borrow = (b1 | b2) as u64;
+    }
+}
+
+/// Reduce a 512-bit little endian integer modulo `L`, one bit at a time
+fn reduce(wide: &[u8; 64]) -> [u8; 32] {
+    let mut rem = [0u64; 4];
+    for bit in (0..512).rev() {
+        // rem < L < 2^253, so shifting in one more bit cannot overflow
+        for ii in (1..4).rev() {
+            rem[ii] = (rem[ii] << 1) | (rem[ii - 1] >> 63);
+        }
+        rem[0] = (rem[0] << 1) | ((wide[bit / 8] >> (bit % 8)) & 1) as u64;
+
+        if geq(&rem, &L) {
+            sub_in_place(&mut rem, &L);
+        }
+    }
+
+    let mut bytes = [0u8; 32];
+    for (out, word) in bytes.chunks_exact_mut(8).zip(rem) {
+        out.copy_from_slice(&word.to_le_bytes());
+    }
+    bytes
+}
+
+/// Compute `(a * b + c) mod L` for 256-bit little endian integers
+fn mul_add(a: &[u8; 32], b: &[u8; 32], c: &[u8; 32]) -> [u8; 32] {
+    let words = |x: &[u8; 32]| -> [u64; 4] {
+        core::array::from_fn(|ii| u64::from_le_bytes(x[ii * 8..ii * 8 + 8].try_into().unwrap()))
+    };
+    let (a, b, c) = (words(a), words(b), words(c));
+
+    // Schoolbook multiplication into 8 words, then add `c`
+    let mut wide = [0u64; 8];
+    for ii in 0..4 {
+        let mut carry: u128 = 0;
+        for jj in 0..4 {
+            let val = a[ii] as u128 * b[jj] as u128 + wide[ii + jj] as u128 + carry;
+            wide[ii + jj] = val as u64;
+            carry = val >> 64;
+        }
+        wide[ii + 4] = carry as u64;
+    }
+
+    let mut carry: u128 = 0;
+    for ii in 0..8 {
+        let val = wide[ii] as u128 + if ii < 4 { c[ii] as u128 } else { 0 } + carry;
+        wide[ii] = val as u64;
+        carry = val >> 64;
+    }
+
+    let mut bytes = [0u8; 64];
+    for (out, word) in bytes.chunks_exact_mut(8).zip(wide) {
+        out.copy_from_slice(&word.to_le_bytes());
+    }
+    reduce(&bytes)
+}
+
+/// Hash the concatenation of `parts` with SHA-512 and reduce the result modulo `L`
+fn hash_to_scalar(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    reduce(&hasher.finish())
+}
+
+/// Derive the secret scalar and the nonce prefix from a secret key
+fn expand(secret: &[u8; SECRET_KEY_SIZE]) -> ([u8; 32], [u8; 32]) {
+    let hash = sha2::sha512(secret);
+    let mut scalar: [u8; 32] = hash[..32].try_into().unwrap();
+    scalar[0] &= 248;
+    scalar[31] &= 127;
+    scalar[31] |= 64;
+    (scalar, hash[32..].try_into().unwrap())
+}
+
+/// Get the public key belonging to the secret key `secret`
+pub fn public_key(secret: &[u8; SECRET_KEY_SIZE]) -> [u8; PUBLIC_KEY_SIZE] {
+    let (scalar, _) = expand(secret);
+    BASE.mul(&scalar).compress()
+}
+
+/// Sign `message` with the secret key `secret`
+pub fn sign(secret: &[u8; SECRET_KEY_SIZE], message: &[u8]) -> [u8; SIGNATURE_SIZE] {
+    let (scalar, prefix) = expand(secret);
+    let public = BASE.mul(&scalar).compress();
+
+    let nonce = hash_to_scalar(&[&prefix, message]);
+    let r = BASE.mul(&nonce).compress();
+    let k = hash_to_scalar(&[&r, &public, message]);
+    let s = mul_add(&k, &scalar, &nonce);
+
+    let mut signature = [0u8; SIGNATURE_SIZE];
+    signature[..32].copy_from_slice(&r);
+    signature[32..].copy_from_slice(&s);
+    signature
+}
+
+/// Check that `signature` is a valid signature of `message` by the owner of `public_key`
+pub fn verify(public_key: &[u8; PUBLIC_KEY_SIZE], message: &[u8],
+        signature: &[u8; SIGNATURE_SIZE]) -> bool {
+    let a = match Point::decompress(public_key) {
+        Some(a) => a,
+        None => return false,
+    };
+
+    let r: [u8; 32] = signature[..32].try_into().unwrap();
+    let s: [u8; 32] = signature[32..].try_into().unwrap();
+
+    // Reject a non-canonical `s`, which would make signatures malleable
+    let s_words: [u64; 4] =
+        core::array::from_fn(|ii| u64::from_le_bytes(s[ii * 8..ii * 8 + 8].try_into().unwrap()));
+    if geq(&s_words, &L) {
+        return false;
+    }
+
+    // The signature is valid if [s]B = R + [k]A, checked as R = [s]B - [k]A
+    let k = hash_to_scalar(&[&r, public_key, message]);
+    BASE.mul(&s).add(&a.neg().mul(&k)).compress() == r
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    /// Decode a hex string into bytes
+    fn hex(string: &str) -> Vec<u8> {
+        (0..string.len()).step_by(2)
+            .map(|ii| u8::from_str_radix(&string[ii..ii + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Test vectors from RFC 8032 section 7.1, as secret key, public key, message and signature
+    const VECTORS: [(&str, &str, &str, &str); 3] = [
+        (
+            "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            "",
+            concat!("e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155",
+                "5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b"),
+        ),
+        (
+            "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+            "72",
+            concat!("92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da",
+                "085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00"),
+        ),
+        (
+            "c5aa8df43f9f837bedb7442f31dcb7b166d38535076f094b85ce3a2e0b4458f7",
+            "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+            "af82",
+            concat!("6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac",
+                "18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a"),
+        ),
+    ];
+
+    #[test]
+    fn test_vectors() {
+        for (secret, public, message, signature) in VECTORS {
+            let secret: [u8; 32] = hex(secret).try_into().unwrap();
+            let public: [u8; 32] = hex(public).try_into().unwrap();
+            let message = hex(message);
+            let signature: [u8; 64] = hex(signature).try_into().unwrap();
+
+            assert_eq!(public_key(&secret), public);
+            assert_eq!(sign(&secret, &message)[..], signature[..]);
+            assert!(verify(&public, &message, &signature));
+        }
+    }
+
+    #[test]
+    fn test_reject() {
+        let secret = [0x42; 32];
+        let public = public_key(&secret);
+        let signature = sign(&secret, b"sherlock.kern");
+        assert!(verify(&public, b"sherlock.kern", &signature));
+
+        // A different message, a corrupted signature, or another key must all fail
+        assert!(!verify(&public, b"sherlock.kerm", &signature));
+        for byte in [0, 31, 32, 63] {
+            let mut bad = signature;
+            bad[byte] ^= 1;
+            assert!(!verify(&public, b"sherlock.kern", &bad));
+        }
+        assert!(!verify(&public_key(&[0x43; 32]), b"sherlock.kern", &signature));
+
+        // So must a non-canonical `s`, which is the same scalar plus `L`
+        let mut bad = signature;
+        let mut carry = 0u16;
+        for ii in 0..32 {
+            let word = L[ii / 8].to_le_bytes()[ii % 8];
+            let val = bad[32 + ii] as u16 + word as u16 + carry;
+            bad[32 + ii] = val as u8;
+            carry = val >> 8;
+        }
+        assert!(!verify(&public, b"sherlock.kern", &bad));
+    }
+}
@@ -0,0 +1,250 @@
+//! Manifests describing a boot image, written by the build tool next to the image and checked by
+//! the bootloader before the image is used. A manifest holds the size and SHA-256 of the image,
+//! optionally followed by an Ed25519 signature of everything before it.
+//!
+//! The layout, all integers little endian:
+//!
+//! | Offset | Size | Field                                    |
+//! |--------|------|------------------------------------------|
+//! | 0x00   | 8    | `MAGIC`                                  |
+//! | 0x08   | 8    | Size of the image in bytes               |
+//! | 0x10   | 32   | SHA-256 of the image                     |
+//! | 0x30   | 64   | Signature of bytes 0x00-0x30, if signed  |
+#![no_std]
+
+#[cfg(feature = "std")]
+extern crate std;
+
+use core::convert::TryInto;
+use ed25519::{PUBLIC_KEY_SIZE, SECRET_KEY_SIZE, SIGNATURE_SIZE};
+
+/// Signature at the start of every manifest
+pub const MAGIC: [u8; 8] = *b"SHRLKMAN";
+
+/// Size of the signed part of a manifest
+pub const BODY_SIZE: usize = 0x30;
+
+/// Size of a signed manifest
+pub const MAX_SIZE: usize = BODY_SIZE + SIGNATURE_SIZE;
+
+/// Reasons a manifest can be malformed, or not match its image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The manifest does not start with `MAGIC`
+    BadMagic,
+
+    /// The manifest is neither the size of an unsigned nor of a signed manifest
+    BadLength(usize),
+
+    /// The image is `actual` bytes, but the manifest says it should be `expected` bytes
+    SizeMismatch { expected: u64, actual: u64 },
+
+    /// The SHA-256 of the image does not match the manifest
+    HashMismatch,
+
+    /// A signature is required, but the manifest is not signed
+    Unsigned,
+
+    /// The signature is not valid for the manifest and the public key
+    BadSignature,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            Error::BadMagic => write!(f, "missing manifest signature"),
+            Error::BadLength(len) => write!(f, "manifest of {} bytes has an invalid size", len),
+            Error::SizeMismatch { expected, actual } =>
+                write!(f, "image is {} bytes, the manifest expects {} bytes", actual, expected),
+            Error::HashMismatch => write!(f, "image SHA-256 does not match the manifest"),
+            Error::Unsigned => write!(f, "manifest is not signed"),
+            Error::BadSignature => write!(f, "manifest signature is invalid"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// A parsed manifest
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    /// Size of the image in bytes
+    pub size: u64,
+
+    /// SHA-256 of the image
+    pub sha256: [u8; 32],
+
+    /// Ed25519 signature of the manifest body, if it is signed
+    pub signature: Option<[u8; SIGNATURE_SIZE]>,
+}
+
+impl Manifest {
+    /// Create an unsigned manifest for `image`
+    pub fn new(image: &[u8]) -> Self {
+        Manifest {
+            size: image.len() as u64,
+            sha256: sha2::sha256(image),
+            signature: None,
+        }
+    }
+
+    /// Get the signed part of the manifest
+    pub fn body(&self) -> [u8; BODY_SIZE] {
+        let mut body = [0u8; BODY_SIZE];
+        body[0x00..0x08].copy_from_slice(&MAGIC);
+        body[0x08..0x10].copy_from_slice(&self.size.to_le_bytes());
+        body[0x10..0x30].copy_from_slice(&self.sha256);
+        body
+    }
+
+    /// Sign the manifest with the Ed25519 secret key `secret`
+    pub fn sign(&mut self, secret: &[u8; SECRET_KEY_SIZE]) {
+        self.signature = Some(ed25519::sign(secret, &self.body()));
+    }
+
+    /// Serialize the manifest into `buf`, returning the part of `buf` used
+    pub fn serialize<'a>(&self, buf: &'a mut [u8; MAX_SIZE]) -> &'a [u8] {
+        buf[..BODY_SIZE].copy_from_slice(&self.body());
+        match self.signature {
+            Some(signature) => {
+                buf[BODY_SIZE..].copy_from_slice(&signature);
+                &buf[..]
+            }
+            None => &buf[..BODY_SIZE],
+        }
+    }
+
+    /// Parse a serialized manifest
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() != BODY_SIZE && bytes.len() != MAX_SIZE {
+            return Err(Error::BadLength(bytes.len()));
+        }
+
+        if bytes[0x00..0x08] != MAGIC {
+            return Err(Error::BadMagic);
+        }
+
+        Ok(Manifest {
+            size: u64::from_le_bytes(bytes[0x08..0x10].try_into().unwrap()),
+            sha256: bytes[0x10..0x30].try_into().unwrap(),
+            signature: bytes.get(BODY_SIZE..MAX_SIZE).map(|x| x.try_into().unwrap()),
+        })
+    }
+
+    /// Check that `image` is the image the manifest describes
+    pub fn check(&self, image: &[u8]) -> Result<(), Error> {
+        self.check_digest(image.len() as u64, &sha2::sha256(image))
+    }
+
+    /// Check an image of `size` bytes with SHA-256 `sha256` against the manifest. This is for
+    /// images hashed as they arrive, which are never in one contiguous buffer.
+    pub fn check_digest(&self, size: u64, sha256: &[u8; 32]) -> Result<(), Error> {
+        if size != self.size {
+            return Err(Error::SizeMismatch { expected: self.size, actual: size });
+        }
+
+        if *sha256 != self.sha256 {
+            return Err(Error::HashMismatch);
+        }
+
+        Ok(())
+    }
+
+    /// Check that the manifest is signed by the owner of `public_key`
+    pub fn verify(&self, public_key: &[u8; PUBLIC_KEY_SIZE]) -> Result<(), Error> {
+        let signature = self.signature.as_ref().ok_or(Error::Unsigned)?;
+        if !ed25519::verify(public_key, &self.body(), signature) {
+            return Err(Error::BadSignature);
+        }
+
+        Ok(())
+    }
+}
+
+/// Decode the hex string `hex` into `N` bytes. This is a `const fn`, such that keys passed in
+/// through the environment at build time can be decoded at compile time. Panics if `hex` is not
+/// exactly `N` bytes of hex.
+pub const fn decode_hex<const N: usize>(hex: &str) -> [u8; N] {
+    const fn nibble(chr: u8) -> u8 {
+        match chr {
+            b'0'..=b'9' => chr - b'0',
+            b'a'..=b'f' => chr - b'a' + 10,
+            b'A'..=b'F' => chr - b'A' + 10,
+            _ => panic!("Invalid hex digit"),
+        }
+    }
+
+    let hex = hex.as_bytes();
+    assert!(hex.len() == N * 2, "Hex string has the wrong length");
+
+    let mut bytes = [0u8; N];
+    let mut ii = 0;
+    while ii < N {
+        bytes[ii] = (nibble(hex[ii * 2]) << 4) | nibble(hex[ii * 2 + 1]);
+        ii += 1;
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let image = b"\x7fELF not really a kernel";
+        let mut manifest = Manifest::new(image);
+        let mut buf = [0u8; MAX_SIZE];
+
+        let bytes = manifest.serialize(&mut buf).to_vec();
+        assert_eq!(bytes.len(), BODY_SIZE);
+        assert_eq!(Manifest::parse(&bytes), Ok(manifest));
+        assert_eq!(manifest.check(image), Ok(()));
+        assert_eq!(manifest.verify(&[0; 32]), Err(Error::Unsigned));
+
+        let secret = [7; 32];
+        manifest.sign(&secret);
+        let bytes = manifest.serialize(&mut buf).to_vec();
+        assert_eq!(bytes.len(), MAX_SIZE);
+        let parsed = Manifest::parse(&bytes).unwrap();
+        assert_eq!(parsed, manifest);
+        assert_eq!(parsed.verify(&ed25519::public_key(&secret)), Ok(()));
+        assert_eq!(parsed.verify(&ed25519::public_key(&[8; 32])), Err(Error::BadSignature));
+    }
+
+    #[test]
+    fn test_mismatch() {
+        let manifest = Manifest::new(b"kernel");
+        assert_eq!(manifest.check(b"kernal"), Err(Error::HashMismatch));
+        assert_eq!(manifest.check(b"kernel!"),
+            Err(Error::SizeMismatch { expected: 6, actual: 7 }));
+
+        // Hashing in pieces gives the same digest as hashing the whole image
+        let mut hasher = sha2::Sha256::default();
+        hasher.update(b"ker");
+        hasher.update(b"nel");
+        assert_eq!(manifest.check_digest(6, &hasher.finish()), Ok(()));
+        assert_eq!(manifest.check_digest(6, &[0; 32]), Err(Error::HashMismatch));
+
+        let mut buf = [0u8; MAX_SIZE];
+        let mut bytes = manifest.serialize(&mut buf).to_vec();
+        assert_eq!(Manifest::parse(&bytes[1..]), Err(Error::BadLength(BODY_SIZE - 1)));
+        bytes[0] ^= 1;
+        assert_eq!(Manifest::parse(&bytes), Err(Error::BadMagic));
+
+        // Tampering with a signed manifest breaks the signature
+        let mut manifest = Manifest::new(b"kernel");
+        manifest.sign(&[1; 32]);
+        manifest.size += 1;
+        assert_eq!(manifest.verify(&ed25519::public_key(&[1; 32])), Err(Error::BadSignature));
+    }
+
+    #[test]
+    fn test_decode_hex() {
+        const KEY: [u8; 4] = decode_hex("00ff7Fa0");
+        assert_eq!(KEY, [0x00, 0xff, 0x7f, 0xa0]);
+    }
+}
@@ -1,13 +1,125 @@
 #![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_main)]
 use core::alloc::Layout;
 use core::mem::size_of;
 
 pub const PAGE_PRESENT: u64 = 1 << 0;
 pub const PAGE_WRITE: u64 = 1 << 1;
 pub const PAGE_USER: u64 = 1 << 2;
+/// Page size (PS) bit, set on a PDPTE or PDE which maps a 1 GiB or 2 MiB page
+pub const PAGE_HUGE: u64 = 1 << 7;
 pub const PAGE_NX: u64 = 1 << 63;
 
+/// Write-through and cache disable bits. Together with the PAT bit they select the PAT entry
+/// which gives the memory type of a page.
+pub const PAGE_PWT: u64 = 1 << 3;
+pub const PAGE_PCD: u64 = 1 << 4;
+
+/// PAT bit of a 4 KiB page entry
+pub const PAGE_PAT_4K: u64 = 1 << 7;
+
+/// PAT bit of a 2 MiB or 1 GiB page entry, as bit 7 is the page size bit there
+pub const PAGE_PAT_HUGE: u64 = 1 << 12;
+
+/// Value `IA32_PAT` must be programmed with for `CacheType::page_bits` to be correct. Entries 0 to
+/// 6 are the power-on defaults, WB, WT, UC-, UC, WB, WT, UC-, such that pages mapped before the
+/// PAT is programmed keep their type. Entry 7 is changed from UC to WC.
+pub const PAT: u64 = 0x0107_0406_0007_0406;
+
+/// Memory types a page can be mapped with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheType {
+    /// Cached, writes are cached and written back later. This is what RAM should be mapped as.
+    WriteBack,
+
+    /// Cached, writes go to memory immediately
+    WriteThrough,
+
+    /// Not cached, and not speculatively accessed. This is what device memory should be mapped as.
+    Uncacheable,
+
+    /// Like `Uncacheable`, but an MTRR of type WC makes it write-combining
+    UncacheableMinus,
+
+    /// Not cached, writes are combined into bursts. Useful for framebuffers.
+    WriteCombining,
+}
+
+impl CacheType {
+    /// Get the raw memory type encoding used by the PAT and the MTRRs
+    pub fn memory_type(self) -> u8 {
+        match self {
+            CacheType::Uncacheable      => 0,
+            CacheType::WriteCombining   => 1,
+            CacheType::WriteThrough     => 4,
+            CacheType::WriteBack        => 6,
+            CacheType::UncacheableMinus => 7,
+        }
+    }
+
+    /// Get the cache type from a raw memory type encoding. Returns `None` for write-protected
+    /// memory, which we never use, and reserved encodings.
+    pub fn from_memory_type(typ: u8) -> Option<Self> {
+        Some(match typ {
+            0 => CacheType::Uncacheable,
+            1 => CacheType::WriteCombining,
+            4 => CacheType::WriteThrough,
+            6 => CacheType::WriteBack,
+            7 => CacheType::UncacheableMinus,
+            _ => return None,
+        })
+    }
+
+    /// Get the page table entry bits which select this type for a page of `page_type`, with the
+    /// PAT programmed to `PAT`
+    pub fn page_bits(self, page_type: PageSize) -> u64 {
+        let pat = match page_type {
+            PageSize::Page4K => PAGE_PAT_4K,
+            PageSize::Page2M | PageSize::Page1G => PAGE_PAT_HUGE,
+        };
+
+        match self {
+            CacheType::WriteBack        => 0,
+            CacheType::WriteThrough     => PAGE_PWT,
+            CacheType::UncacheableMinus => PAGE_PCD,
+            CacheType::Uncacheable      => PAGE_PCD | PAGE_PWT,
+            CacheType::WriteCombining   => pat | PAGE_PCD | PAGE_PWT,
+        }
+    }
+}
+
+/// Permissions and memory type of a mapping
+#[derive(Clone, Copy, Debug)]
+pub struct MapFlags {
+    /// Whether the pages can be read. x86 has no way to express a present page which is not
+    /// readable, so mappings without `read` fail.
+    pub read: bool,
+
+    /// Whether the pages can be written
+    pub write: bool,
+
+    /// Whether code can be executed from the pages
+    pub exec: bool,
+
+    /// Memory type of the pages
+    pub cache: CacheType,
+}
+
+impl MapFlags {
+    /// Get the page table entry bits, other than the address, for a page of `page_type` mapped
+    /// with these flags
+    fn entry_bits(self, page_type: PageSize) -> u64 {
+        let huge = match page_type {
+            PageSize::Page4K => 0,
+            PageSize::Page2M | PageSize::Page1G => PAGE_HUGE,
+        };
+
+        PAGE_PRESENT | huge | self.cache.page_bits(page_type) |
+            if self.write { PAGE_WRITE } else { 0 } |
+            if self.exec { 0 } else { PAGE_NX }
+    }
+}
+
 /// A strongly type physical address. This is effectively just and integer, but we have strongly
 /// types it to make code clarity a bit higher. This may represent a host physical address, or a
 /// guest physical address.
@@ -84,26 +196,56 @@ impl PageTable{
         self.table
     }
 
-    /// Create a page table entry initialized to `init` at `vaddr` using `page_type` as page size.
-    /// `read`, `write` and `exec` will be used as the permission bits.
-    ///
-    /// If `init` is `Some`, it will be invoked with the current offset into the mapping and the
-    /// return value from the closure will be used to initialize that byte.
+    /// Create a page table entry at `vaddr` using `page_type` as page size, with the permissions
+    /// and memory type in `flags`
     pub unsafe fn map<P: PhysMem>(
         &mut self,
         phys_mem: &mut P,
         vaddr: VirtAddr,
         page_type: PageSize,
         size: u64,
-        read: bool,
-        write: bool,
-        exec: bool,
+        flags: MapFlags,
     ) -> Option<()> {
-        self.map_init::<fn(u64) -> u8, P>(phys_mem, vaddr, page_type, size, read, write, exec, None)
+        self.map_init::<fn(u64) -> u8, P>(phys_mem, vaddr, page_type, size, flags, None)
     }
 
-    /// Create a page table entry initialized to `init` at `vaddr` using `page_type` as page size.
-    /// `read`, `write` and `exec` will be used as the permission bits.
+    /// Map `size` bytes of existing physical memory at `paddr` to `vaddr`, using `page_type` as
+    /// page size, with the permissions and memory type in `flags`. This is meant for device
+    /// memory, which should be `CacheType::Uncacheable`.
+    pub unsafe fn map_phys<P: PhysMem>(
+        &mut self,
+        phys_mem: &mut P,
+        vaddr: VirtAddr,
+        page_type: PageSize,
+        paddr: PhysAddr,
+        size: u64,
+        flags: MapFlags,
+    ) -> Option<()> {
+        let page_size = page_type as u64;
+        let page_mask = page_size - 1;
+
+        // Both addresses must be aligned to the page size
+        if size == 0 || (vaddr.0 & page_mask) != 0 || (paddr.0 & page_mask) != 0 || !flags.read {
+            return None;
+        }
+
+        let end_offset = size - 1;
+        vaddr.0.checked_add(end_offset)?;
+        paddr.0.checked_add(end_offset)?;
+
+        for offset in (0..=end_offset).step_by(page_size as usize) {
+            let ent = (paddr.0 + offset) | flags.entry_bits(page_type);
+
+            self.map_raw(phys_mem, VirtAddr(vaddr.0 + offset), page_type, ent, true, false,
+                false)?;
+        }
+
+        Some(())
+    }
+
+    /// Create a page table entry initialized to `init` at `vaddr` using `page_type` as page size,
+    /// with the permissions and memory type in `flags`. Mappings without `flags.read` fail, leave
+    /// a page unmapped instead to make it inaccessible.
     ///
     /// If `init` is `Some`, it will be invoked with the current offset into the mapping and the
     /// return value from the closure will be used to initialize that byte.
@@ -113,9 +255,7 @@ impl PageTable{
         vaddr: VirtAddr,
         page_type: PageSize,
         size: u64,
-        _read: bool,
-        write: bool,
-        exec: bool,
+        flags: MapFlags,
         init: Option<F>
     ) -> Option<()>  where F: Fn(u64) -> u8 {
         // Get the raw page size in bytes
@@ -127,7 +267,7 @@ impl PageTable{
         let orig_vaddr = vaddr;
 
         // Make sure that the virtual address is aligned to the page size request
-        if size <= 0 || (vaddr.0 & page_mask) != 0 {
+        if size == 0 || (vaddr.0 & page_mask) != 0 || !flags.read {
             return None;
         }
 
@@ -141,9 +281,7 @@ impl PageTable{
                 Layout::from_size_align(page_size as usize, page_size as usize).ok()?
             )?;
 
-            let ent = page.0 | PAGE_PRESENT |
-                if write { PAGE_WRITE } else { 0 } |
-                if exec { 0 } else { PAGE_NX };
+            let ent = page.0 | flags.entry_bits(page_type);
 
             if let Some(init) = &init {
                 // Translate the page
@@ -158,7 +296,7 @@ impl PageTable{
             }
 
             // Add this mapping to the page table
-            self.map_raw(phys_mem, VirtAddr(vaddr), page_type, ent, true, false, false);
+            self.map_raw(phys_mem, VirtAddr(vaddr), page_type, ent, true, false, false)?;
         }
 
         Some(())
@@ -272,4 +410,100 @@ impl PageTable{
 
         unreachable!();
     }
+
+    /// Invoke `func` for every page mapped in this table, with the virtual address, the size and
+    /// the leaf page table entry of the page. The permission bits of the entry passed to `func`
+    /// are the effective ones, `PAGE_WRITE` is only set if every level allows writes, and
+    /// `PAGE_NX` is set if any level forbids execution.
+    pub unsafe fn for_each_mapping<P: PhysMem, F: FnMut(VirtAddr, PageSize, u64)>(
+        &self,
+        phys_mem: &mut P,
+        mut func: F,
+    ) -> Option<()> {
+        Self::walk(phys_mem, self.table, 0, 0, PAGE_WRITE, &mut func)
+    }
+
+    /// Walk the table at `table`, which is at `depth` in the hierarchy and maps the memory at
+    /// `vaddr`. `inherited` holds the `PAGE_WRITE` and `PAGE_NX` bits from the levels above.
+    unsafe fn walk<P: PhysMem, F: FnMut(VirtAddr, PageSize, u64)>(
+        phys_mem: &mut P,
+        table: PhysAddr,
+        depth: u32,
+        vaddr: u64,
+        inherited: u64,
+        func: &mut F,
+    ) -> Option<()> {
+        let paddr_size = size_of::<u64>();
+        for index in 0..512u64 {
+            let vad = phys_mem.translate(PhysAddr(table.0 + index * paddr_size as u64),
+                paddr_size)?;
+            let ent = *(vad as *const u64);
+
+            if (ent & PAGE_PRESENT) == 0 {
+                continue;
+            }
+
+            // Compute the effective permissions up to this level
+            let ent = (ent & !(PAGE_WRITE | PAGE_NX)) |
+                (ent & inherited & PAGE_WRITE) |
+                ((ent | inherited) & PAGE_NX);
+
+            let vaddr = vaddr | (index << (39 - 9 * depth));
+
+            match depth {
+                3 => func(VirtAddr(cpu::canonicalize_address(vaddr)), PageSize::Page4K, ent),
+                1 | 2 if (ent & PAGE_HUGE) != 0 => {
+                    let size = if depth == 1 { PageSize::Page1G } else { PageSize::Page2M };
+                    func(VirtAddr(cpu::canonicalize_address(vaddr)), size, ent);
+                }
+                _ => {
+                    Self::walk(phys_mem, PhysAddr(ent & 0xf_ffff_ffff_f000), depth + 1, vaddr,
+                        ent, func)?;
+                }
+            }
+        }
+
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use crate::*;
+
+    #[test]
+    fn test_cache_types() {
+        // The PAT entry selected by the page bits must hold the memory type of the cache type
+        for cache in [CacheType::WriteBack, CacheType::WriteThrough, CacheType::Uncacheable,
+                CacheType::UncacheableMinus, CacheType::WriteCombining] {
+            assert_eq!(CacheType::from_memory_type(cache.memory_type()), Some(cache));
+
+            for (page_type, pat_bit) in [(PageSize::Page4K, PAGE_PAT_4K),
+                    (PageSize::Page2M, PAGE_PAT_HUGE), (PageSize::Page1G, PAGE_PAT_HUGE)] {
+                let bits = cache.page_bits(page_type);
+                assert_eq!(bits & !(PAGE_PWT | PAGE_PCD | pat_bit), 0);
+
+                let index = (bits & PAGE_PWT != 0) as u64 |
+                    (((bits & PAGE_PCD != 0) as u64) << 1) |
+                    (((bits & pat_bit != 0) as u64) << 2);
+                assert_eq!((PAT >> (index * 8)) as u8, cache.memory_type());
+            }
+        }
+
+        // Write-back must not need any bits, such that entries built without a cache type are
+        // cached
+        assert_eq!(CacheType::WriteBack.page_bits(PageSize::Page4K), 0);
+    }
+
+    #[test]
+    fn test_map_flags() {
+        let flags = MapFlags { read: true, write: false, exec: false, cache: CacheType::WriteBack };
+        assert_eq!(flags.entry_bits(PageSize::Page4K), PAGE_PRESENT | PAGE_NX);
+
+        let flags = MapFlags { write: true, exec: true, cache: CacheType::Uncacheable, ..flags };
+        assert_eq!(flags.entry_bits(PageSize::Page2M),
+            PAGE_PRESENT | PAGE_HUGE | PAGE_WRITE | PAGE_PCD | PAGE_PWT);
+    }
 }
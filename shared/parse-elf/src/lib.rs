@@ -0,0 +1,574 @@
+//! A minimal ELF64 parser for loading x86_64 kernel images, such as the ones built for the
+//! `x86_64-unknown-none` target. Only the program headers are used, the section headers are not
+//! needed to load an image.
+#![no_std]
+
+#[cfg(feature = "std")]
+extern crate std;
+
+use core::convert::TryInto;
+
+/// Size of the ELF64 file header
+const ELF_HEADER_SIZE: usize = 0x40;
+
+/// Size of an ELF64 program header
+const PROGRAM_HEADER_SIZE: usize = 0x38;
+
+/// Size of an entry in the dynamic table
+const DYNAMIC_SIZE: usize = 0x10;
+
+/// Size of an `Elf64_Rela` relocation
+const RELA_SIZE: usize = 0x18;
+
+/// `e_ident` values we support, 64-bit little endian
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+
+/// Object file types
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+
+/// Machine type for x86_64
+const EM_X86_64: u16 = 62;
+
+/// Program header types
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+
+/// Program header flags
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// Dynamic table tags
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+
+/// Relocation types
+pub const R_X86_64_NONE: u32 = 0;
+pub const R_X86_64_RELATIVE: u32 = 8;
+
+/// Granularity images are laid out at, the base of an image is rounded down to this
+const PAGE_SIZE: u64 = 4096;
+
+/// Reasons parsing or loading an ELF can fail. Offsets are file offsets, virtual addresses are
+/// the ones the image was linked at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The file does not start with the `\x7fELF` signature
+    BadMagic,
+
+    /// The file is not a 64-bit ELF
+    UnsupportedClass(u8),
+
+    /// The file is not little endian
+    UnsupportedEndianness(u8),
+
+    /// The machine type is not x86_64
+    UnsupportedMachine(u16),
+
+    /// The file is neither an executable nor a position independent executable
+    UnsupportedType(u16),
+
+    /// The header `what`, `size` bytes at `offset`, extends past the end of the file
+    Truncated { what: &'static str, offset: usize, size: usize },
+
+    /// The file contents of segment `segment`, `size` bytes at `offset`, extend past the end of
+    /// the file
+    SegmentOutOfBounds { segment: usize, offset: usize, size: usize },
+
+    /// Segment `segment` has more bytes in the file than in memory
+    BadSegment { segment: usize },
+
+    /// There are no `PT_LOAD` segments to load
+    NoLoadableSegments,
+
+    /// `what`, `size` bytes at `vaddr`, does not fit in the image passed to `load` or `relocate`
+    ImageOutOfBounds { what: &'static str, vaddr: u64, size: usize },
+
+    /// The relocation at `vaddr` has a type we cannot apply
+    UnsupportedRelocation { vaddr: u64, typ: u32 },
+
+    /// The dynamic table, or the relocations it points to, are malformed
+    BadDynamic,
+
+    /// An integer overflow occured while computing the location of `what`
+    Overflow { what: &'static str },
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            Error::BadMagic => write!(f, "missing ELF signature"),
+            Error::UnsupportedClass(class) => write!(f, "unsupported ELF class {}", class),
+            Error::UnsupportedEndianness(data) =>
+                write!(f, "unsupported ELF data encoding {}", data),
+            Error::UnsupportedMachine(machine) =>
+                write!(f, "unsupported machine type {:#x}", machine),
+            Error::UnsupportedType(typ) => write!(f, "unsupported ELF type {}", typ),
+            Error::Truncated { what, offset, size } =>
+                write!(f, "{} at offset {:#x} ({:#x} bytes) is truncated", what, offset, size),
+            Error::SegmentOutOfBounds { segment, offset, size } =>
+                write!(f, "segment {} data at offset {:#x} ({:#x} bytes) is out of bounds",
+                    segment, offset, size),
+            Error::BadSegment { segment } =>
+                write!(f, "segment {} is larger in the file than in memory", segment),
+            Error::NoLoadableSegments => write!(f, "no loadable segments"),
+            Error::ImageOutOfBounds { what, vaddr, size } =>
+                write!(f, "{} at {:#x} ({:#x} bytes) is outside of the image", what, vaddr, size),
+            Error::UnsupportedRelocation { vaddr, typ } =>
+                write!(f, "unsupported relocation type {} at {:#x}", typ, vaddr),
+            Error::BadDynamic => write!(f, "malformed dynamic table"),
+            Error::Overflow { what } => write!(f, "integer overflow computing {}", what),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// Result type for parsing and loading an ELF
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Get the `size` bytes at `offset` in `bytes`, failing with `Error::Truncated` describing `what`
+fn get_bytes<'a>(bytes: &'a [u8], offset: usize, size: usize, what: &'static str)
+        -> Result<&'a [u8]> {
+    offset.checked_add(size)
+        .and_then(|end| bytes.get(offset..end))
+        .ok_or(Error::Truncated { what, offset, size })
+}
+
+/// Read a little endian `u16` at `off` in `bytes`
+fn read_u16(bytes: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(off..off.checked_add(2)?)?.try_into().ok()?))
+}
+
+/// Read a little endian `u32` at `off` in `bytes`
+fn read_u32(bytes: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(off..off.checked_add(4)?)?.try_into().ok()?))
+}
+
+/// Read a little endian `u64` at `off` in `bytes`
+fn read_u64(bytes: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(off..off.checked_add(8)?)?.try_into().ok()?))
+}
+
+/// A program header from the program header table
+#[derive(Clone, Copy, Debug)]
+pub struct ProgramHeader {
+    /// One of the `PT_*` types
+    pub typ: u32,
+
+    /// `PF_*` permission flags
+    pub flags: u32,
+
+    /// Offset of the segment contents in the file
+    pub offset: u64,
+
+    /// Virtual address of the segment
+    pub vaddr: u64,
+
+    /// Number of bytes of the segment initialized by the file
+    pub filesz: u64,
+
+    /// Size of the segment in memory, the bytes past `filesz` are zero
+    pub memsz: u64,
+
+    /// Required alignment of the segment
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    /// Returns true if the segment is readable
+    pub fn read(&self) -> bool { (self.flags & PF_R) != 0 }
+
+    /// Returns true if the segment is writable
+    pub fn write(&self) -> bool { (self.flags & PF_W) != 0 }
+
+    /// Returns true if the segment is executable
+    pub fn execute(&self) -> bool { (self.flags & PF_X) != 0 }
+}
+
+pub struct ElfParser<'a> {
+    bytes: &'a [u8],
+    phoff: usize,
+    phnum: usize,
+    /// `ET_EXEC` or `ET_DYN`
+    pub elf_type: u16,
+    /// Machine type from the ELF header
+    pub machine: u16,
+    /// Virtual address of the entry point
+    pub entry_point: u64,
+    /// Page aligned virtual address of the lowest loadable segment
+    pub image_base: u64,
+    /// Number of bytes from `image_base` to the end of the highest loadable segment
+    pub image_size: u64,
+}
+
+impl<'a> ElfParser<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
+        // Check for the ELF signature
+        if bytes.get(0..4) != Some(b"\x7fELF") { return Err(Error::BadMagic); }
+
+        let header = get_bytes(bytes, 0, ELF_HEADER_SIZE, "ELF header")?;
+
+        // We only support 64-bit little endian x86_64 images
+        if header[4] != ELFCLASS64 { return Err(Error::UnsupportedClass(header[4])); }
+        if header[5] != ELFDATA2LSB { return Err(Error::UnsupportedEndianness(header[5])); }
+
+        let elf_type = read_u16(header, 0x10).unwrap();
+        if elf_type != ET_EXEC && elf_type != ET_DYN {
+            return Err(Error::UnsupportedType(elf_type));
+        }
+
+        let machine = read_u16(header, 0x12).unwrap();
+        if machine != EM_X86_64 { return Err(Error::UnsupportedMachine(machine)); }
+
+        let entry_point = read_u64(header, 0x18).unwrap();
+        let phoff: usize = read_u64(header, 0x20).unwrap().try_into()
+            .map_err(|_| Error::Overflow { what: "program header table" })?;
+        let phentsize = read_u16(header, 0x36).unwrap() as usize;
+        let phnum = read_u16(header, 0x38).unwrap() as usize;
+
+        if phnum != 0 && phentsize != PROGRAM_HEADER_SIZE {
+            return Err(Error::Truncated {
+                what: "program header", offset: phoff, size: PROGRAM_HEADER_SIZE
+            });
+        }
+
+        // Make sure the program header table is within bounds of our input
+        get_bytes(bytes, phoff, PROGRAM_HEADER_SIZE * phnum, "program header table")?;
+
+        let mut ret = ElfParser {
+            bytes,
+            phoff,
+            phnum,
+            elf_type,
+            machine,
+            entry_point,
+            image_base: 0,
+            image_size: 0,
+        };
+
+        // Compute the bounds of the loaded image
+        let mut bounds: Option<(u64, u64)> = None;
+        for (ii, phdr) in ret.loadable_segments() {
+            if phdr.filesz > phdr.memsz {
+                return Err(Error::BadSegment { segment: ii });
+            }
+
+            let end = phdr.vaddr.checked_add(phdr.memsz)
+                .ok_or(Error::Overflow { what: "segment end" })?;
+            bounds = Some(match bounds {
+                Some((start, cur_end)) =>
+                    (core::cmp::min(start, phdr.vaddr), core::cmp::max(cur_end, end)),
+                None => (phdr.vaddr, end),
+            });
+        }
+
+        let (start, end) = bounds.ok_or(Error::NoLoadableSegments)?;
+        ret.image_base = start & !(PAGE_SIZE - 1);
+        ret.image_size = end - ret.image_base;
+
+        Ok(ret)
+    }
+
+    /// Returns true if the image can be loaded at an address other than the one it was linked at
+    pub fn relocatable(&self) -> bool {
+        self.elf_type == ET_DYN
+    }
+
+    /// Get the program header at `index` in the program header table
+    pub fn program_header(&self, index: usize) -> Option<ProgramHeader> {
+        if index >= self.phnum {
+            return None;
+        }
+
+        // The whole table was bounds checked during `parse`
+        let off = self.phoff + index * PROGRAM_HEADER_SIZE;
+        let header = &self.bytes[off..off + PROGRAM_HEADER_SIZE];
+
+        Some(ProgramHeader {
+            typ:    read_u32(header, 0x0)?,
+            flags:  read_u32(header, 0x4)?,
+            offset: read_u64(header, 0x8)?,
+            vaddr:  read_u64(header, 0x10)?,
+            filesz: read_u64(header, 0x20)?,
+            memsz:  read_u64(header, 0x28)?,
+            align:  read_u64(header, 0x30)?,
+        })
+    }
+
+    /// Iterate over all the program headers
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.phnum).filter_map(move |ii| self.program_header(ii))
+    }
+
+    /// Iterate over the `PT_LOAD` program headers, with their index in the table
+    fn loadable_segments(&self) -> impl Iterator<Item = (usize, ProgramHeader)> + '_ {
+        self.program_headers().enumerate().filter(|(_, phdr)| phdr.typ == PT_LOAD)
+    }
+
+    /// Get the bytes of segment `index` initialized by the file
+    fn segment_raw(&self, index: usize, phdr: &ProgramHeader) -> Result<&'a [u8]> {
+        let err = Error::SegmentOutOfBounds {
+            segment: index, offset: phdr.offset as usize, size: phdr.filesz as usize
+        };
+
+        let offset: usize = phdr.offset.try_into().map_err(|_| err)?;
+        let size: usize = phdr.filesz.try_into().map_err(|_| err)?;
+        offset.checked_add(size)
+            .and_then(|end| self.bytes.get(offset..end))
+            .ok_or(err)
+    }
+
+    /// Invoke a closure with the format
+    /// (virtual_address, virtual_size, raw_bytes, read, write, execute) for each `PT_LOAD`
+    /// segment in the ELF file, the same shape as `PeParser::sections`. Iteration stops at the
+    /// first error, either from the ELF or returned by the closure.
+    pub fn segments<E, F>(&self, mut func: F) -> core::result::Result<(), E>
+            where E: From<Error>,
+                  F: FnMut(u64, u32, &[u8], bool, bool, bool) -> core::result::Result<(), E> {
+        for (ii, phdr) in self.loadable_segments() {
+            let memsz: u32 = phdr.memsz.try_into()
+                .map_err(|_| Error::Overflow { what: "segment size" })?;
+
+            func(
+                phdr.vaddr,
+                memsz,
+                self.segment_raw(ii, &phdr)?,
+                phdr.read(),
+                phdr.write(),
+                phdr.execute(),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the `size` bytes of `image` at linked virtual address `vaddr`
+    fn image_bytes<'b>(&self, image: &'b mut [u8], vaddr: u64, size: usize, what: &'static str)
+            -> Result<&'b mut [u8]> {
+        let err = Error::ImageOutOfBounds { what, vaddr, size };
+
+        let off: usize = vaddr.checked_sub(self.image_base)
+            .and_then(|off| off.try_into().ok())
+            .ok_or(err)?;
+        off.checked_add(size)
+            .and_then(|end| image.get_mut(off..end))
+            .ok_or(err)
+    }
+
+    /// Copy all loadable segments into `image`, laid out as they would be in memory, such that
+    /// `image[vaddr - image_base]` is the byte at virtual address `vaddr`. `image` must be at
+    /// least `image_size` bytes. Bytes which are not initialized by the file are zeroed.
+    pub fn load(&self, image: &mut [u8]) -> Result<()> {
+        image.iter_mut().for_each(|x| *x = 0);
+
+        for (ii, phdr) in self.loadable_segments() {
+            let raw = self.segment_raw(ii, &phdr)?;
+            self.image_bytes(image, phdr.vaddr, raw.len(), "segment")?
+                .copy_from_slice(raw);
+        }
+
+        Ok(())
+    }
+
+    /// Get the `(address, size)` of the `Elf64_Rela` table from the dynamic table, if the image
+    /// has one
+    fn rela_table(&self, image: &mut [u8]) -> Result<Option<(u64, usize)>> {
+        let dynamic = match self.program_headers().find(|phdr| phdr.typ == PT_DYNAMIC) {
+            Some(dynamic) => dynamic,
+            None => return Ok(None),
+        };
+
+        // Use the loaded image, the dynamic table is always in a loadable segment
+        let size: usize = dynamic.memsz.try_into().map_err(|_| Error::BadDynamic)?;
+        let table = self.image_bytes(image, dynamic.vaddr, size, "dynamic table")?;
+
+        let mut rela = None;
+        let mut relasz = 0;
+        let mut relaent = RELA_SIZE as u64;
+        for entry in table.chunks_exact(DYNAMIC_SIZE) {
+            let val = read_u64(entry, 8).unwrap();
+            match read_u64(entry, 0).unwrap() {
+                DT_NULL => break,
+                DT_RELA => rela = Some(val),
+                DT_RELASZ => relasz = val,
+                DT_RELAENT => relaent = val,
+                _ => {}
+            }
+        }
+
+        if relaent != RELA_SIZE as u64 {
+            return Err(Error::BadDynamic);
+        }
+
+        match rela {
+            Some(rela) => Ok(Some((rela, relasz.try_into().map_err(|_| Error::BadDynamic)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Apply the dynamic relocations to `image`, an image previously populated by `load`, such
+    /// that it can run with `image_base` at `new_base`. Only `R_X86_64_RELATIVE` relocations are
+    /// supported, which is all a statically linked position independent executable has.
+    pub fn relocate(&self, image: &mut [u8], new_base: u64) -> Result<()> {
+        let (rela, relasz) = match self.rela_table(image)? {
+            Some(table) => table,
+            None => return Ok(()),
+        };
+
+        // Compute the offset to add to all absolute addresses
+        let delta = new_base.wrapping_sub(self.image_base);
+
+        // Copy out the relocations, as applying them modifies the image they live in
+        let table = self.image_bytes(image, rela, relasz, "relocation table")?;
+        let off = table.as_ptr() as usize - image.as_ptr() as usize;
+
+        for ii in 0..relasz / RELA_SIZE {
+            let entry = &image[off + ii * RELA_SIZE..off + (ii + 1) * RELA_SIZE];
+            let vaddr = read_u64(entry, 0x0).unwrap();
+            let typ = read_u64(entry, 0x8).unwrap() as u32;
+            let addend = read_u64(entry, 0x10).unwrap();
+
+            match typ {
+                R_X86_64_NONE => {}
+                R_X86_64_RELATIVE => {
+                    let val = addend.wrapping_add(delta);
+                    self.image_bytes(image, vaddr, 8, "relocation target")?
+                        .copy_from_slice(&val.to_le_bytes());
+                }
+                typ => return Err(Error::UnsupportedRelocation { vaddr, typ }),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use std::vec::Vec;
+    use crate::*;
+
+    /// Build a position independent image linked at 0 with a text segment at 0x1000, and a data
+    /// segment at 0x2000 holding a dynamic table and a single relative relocation
+    fn build_image() -> Vec<u8> {
+        let mut elf = std::vec![0u8; 0x3000];
+
+        // ELF header
+        elf[..4].copy_from_slice(b"\x7fELF");
+        elf[4] = ELFCLASS64;
+        elf[5] = ELFDATA2LSB;
+        elf[0x10..0x12].copy_from_slice(&ET_DYN.to_le_bytes());
+        elf[0x12..0x14].copy_from_slice(&EM_X86_64.to_le_bytes());
+        elf[0x18..0x20].copy_from_slice(&0x1000u64.to_le_bytes());
+        elf[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+        elf[0x36..0x38].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        elf[0x38..0x3a].copy_from_slice(&3u16.to_le_bytes());
+
+        // Program headers as (type, flags, offset, vaddr, filesz, memsz)
+        let phdrs = [
+            (PT_LOAD, PF_R | PF_X, 0x1000u64, 0x1000u64, 0x10u64, 0x10u64),
+            (PT_LOAD, PF_R | PF_W, 0x2000, 0x2000, 0x100, 0x2000),
+            (PT_DYNAMIC, PF_R | PF_W, 0x2000, 0x2000, 0x40, 0x40),
+        ];
+        for (ii, &(typ, flags, offset, vaddr, filesz, memsz)) in phdrs.iter().enumerate() {
+            let phdr = &mut elf[0x40 + ii * PROGRAM_HEADER_SIZE..];
+            phdr[0x0..0x4].copy_from_slice(&typ.to_le_bytes());
+            phdr[0x4..0x8].copy_from_slice(&flags.to_le_bytes());
+            phdr[0x8..0x10].copy_from_slice(&offset.to_le_bytes());
+            phdr[0x10..0x18].copy_from_slice(&vaddr.to_le_bytes());
+            phdr[0x20..0x28].copy_from_slice(&filesz.to_le_bytes());
+            phdr[0x28..0x30].copy_from_slice(&memsz.to_le_bytes());
+            phdr[0x30..0x38].copy_from_slice(&0x1000u64.to_le_bytes());
+        }
+
+        // Code
+        elf[0x1000..0x1010].copy_from_slice(&[0x90; 0x10]);
+
+        // Dynamic table pointing to one relocation at 0x2040
+        let dynamic = [(DT_RELA, 0x2040u64), (DT_RELASZ, 0x18), (DT_RELAENT, 0x18), (DT_NULL, 0)];
+        for (ii, &(tag, val)) in dynamic.iter().enumerate() {
+            elf[0x2000 + ii * 16..0x2008 + ii * 16].copy_from_slice(&tag.to_le_bytes());
+            elf[0x2008 + ii * 16..0x2010 + ii * 16].copy_from_slice(&val.to_le_bytes());
+        }
+
+        // Relocate the pointer at 0x2080 to point at 0x1000
+        elf[0x2040..0x2048].copy_from_slice(&0x2080u64.to_le_bytes());
+        elf[0x2048..0x2050].copy_from_slice(&(R_X86_64_RELATIVE as u64).to_le_bytes());
+        elf[0x2050..0x2058].copy_from_slice(&0x1000u64.to_le_bytes());
+
+        elf
+    }
+
+    #[test]
+    fn test_parse() {
+        let elf = build_image();
+        let parser = ElfParser::parse(&elf).unwrap();
+
+        assert!(parser.relocatable());
+        assert_eq!(parser.entry_point, 0x1000);
+        assert_eq!(parser.image_base, 0x1000);
+        assert_eq!(parser.image_size, 0x3000);
+
+        let mut segments = Vec::new();
+        parser.segments(|vaddr, vsize, raw, r, w, x| {
+            segments.push((vaddr, vsize, raw.len(), r, w, x));
+            Ok::<(), Error>(())
+        }).unwrap();
+        assert_eq!(segments, [
+            (0x1000, 0x10, 0x10, true, false, true),
+            (0x2000, 0x2000, 0x100, true, true, false),
+        ]);
+    }
+
+    #[test]
+    fn test_load_relocate() {
+        let elf = build_image();
+        let parser = ElfParser::parse(&elf).unwrap();
+
+        let mut image = std::vec![0xccu8; parser.image_size as usize];
+        parser.load(&mut image).unwrap();
+        assert_eq!(&image[..0x10], &[0x90; 0x10]);
+        assert!(image[0x10..0x1000].iter().all(|&x| x == 0));
+        assert!(image[0x1100..].iter().all(|&x| x == 0));
+
+        parser.relocate(&mut image, 0xffff_f000_0000_0000).unwrap();
+        assert_eq!(&image[0x1080..0x1088], &0xffff_f000_0000_0000u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(ElfParser::parse(b"MZ").err(), Some(Error::BadMagic));
+
+        let mut elf = build_image();
+        elf[4] = 1;
+        assert_eq!(ElfParser::parse(&elf).err(), Some(Error::UnsupportedClass(1)));
+
+        let mut elf = build_image();
+        elf[0x38..0x3a].copy_from_slice(&0xffffu16.to_le_bytes());
+        assert!(matches!(ElfParser::parse(&elf),
+            Err(Error::Truncated { what: "program header table", .. })));
+
+        // Point the text segment past the end of the file
+        let mut elf = build_image();
+        elf[0x48..0x50].copy_from_slice(&0x10_0000u64.to_le_bytes());
+        let parser = ElfParser::parse(&elf).unwrap();
+        assert_eq!(parser.segments(|_, _, _, _, _, _| Ok::<(), Error>(())),
+            Err(Error::SegmentOutOfBounds { segment: 0, offset: 0x10_0000, size: 0x10 }));
+
+        // Every prefix must fail cleanly or parse
+        let elf = build_image();
+        for len in 0..elf.len() {
+            if let Ok(parser) = ElfParser::parse(&elf[..len]) {
+                let _ = parser.segments(|_, _, _, _, _, _| Ok::<(), Error>(()));
+            }
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Source of `fixture.dll`, a small relocatable PE32+ DLL with exports, unwind information, base
+//! relocations and a debug directory, used by the tests. Rebuild it with:
+//!
+//! ```text
+//! rustc +nightly --target x86_64-pc-windows-msvc --emit obj -C opt-level=0 -C panic=abort \
+//!     fixture.rs -o fixture.obj
+//! rust-lld -flavor link /dll /noentry /nodefaultlib /machine:x64 /opt:noicf /brepro /debug \
+//!     /pdbaltpath:fixture.pdb /export:first /export:second /export:TABLE,DATA \
+//!     /out:fixture.dll fixture.obj
+//! ```
+#![feature(no_core, lang_items, auto_traits)]
+#![no_core]
+#![crate_type = "lib"]
+
+#[lang = "pointee_sized"] trait PointeeSized {}
+#[lang = "meta_sized"] trait MetaSized: PointeeSized {}
+#[lang = "sized"] trait Sized: MetaSized {}
+#[lang = "copy"] trait Copy {}
+#[lang = "sync"] trait Sync {}
+#[lang = "freeze"] unsafe auto trait Freeze {}
+#[lang = "drop_glue"] unsafe fn drop_in_place<T>(_: *mut T) {}
+
+/// Exported functions, referenced by absolute address such that the DLL needs base relocations
+pub struct Table(extern "C" fn(u64) -> u64, extern "C" fn(u64, u64) -> u64);
+impl Sync for Table {}
+
+#[no_mangle]
+pub extern "C" fn first(x: u64) -> u64 { x }
+
+#[no_mangle]
+pub extern "C" fn second(_x: u64, y: u64) -> u64 { first(y) }
+
+#[no_mangle]
+pub static TABLE: Table = Table(first, second);
@@ -0,0 +1,28 @@
+//! Fuzz `PeParser` with arbitrary bytes, as could come from a corrupted TFTP download. Parsing
+//! and walking the sections must fail with an `Error` rather than panic.
+//!
+//! Run with `cargo fuzz run parse` from `shared/parse-pe`, seeding the corpus with
+//! `bootloader.exe` gives the fuzzer a valid starting point.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use parse_pe::{Error, PeParser};
+
+fuzz_target!(|data: &[u8]| {
+    let pe = match PeParser::parse(data) {
+        Ok(pe) => pe,
+        Err(_) => return,
+    };
+
+    let _ = pe.sections(|_, vsize, raw, _, _, _| {
+        assert!(raw.len() <= vsize as usize);
+        Ok::<(), Error>(())
+    });
+
+    // Cap the image size so lying headers do not make us allocate gigabytes
+    let size = core::cmp::min(pe.optional_header.size_of_image as usize, 16 * 1024 * 1024);
+    let mut image = vec![0u8; size];
+    if pe.load(&mut image).is_ok() {
+        let _ = pe.relocate(&mut image, 0xffff_f000_0000_0000);
+    }
+});
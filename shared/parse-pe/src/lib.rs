@@ -1,131 +1,1471 @@
 #![no_std]
 
+#[cfg(feature = "std")]
+extern crate std;
+
 use core::convert::TryInto;
 
 const IMAGE_FILE_MACHINE_I386: u16 = 0x014c;
 const IMAGE_FILE_MACHINE_X86_64: u16 = 0x8664;
 
+/// COFF characteristics flag set when the image has no base relocations
+const IMAGE_FILE_RELOCS_STRIPPED: u16 = 0x0001;
+
+const IMAGE_NT_OPTIONAL_HDR32_MAGIC: u16 = 0x10b;
+const IMAGE_NT_OPTIONAL_HDR64_MAGIC: u16 = 0x20b;
+
 const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
 const IMAGE_SCN_MEM_READ: u32 = 0x4000_0000;
 const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
 
+/// Indicies into the data directory table
+pub const IMAGE_DIRECTORY_ENTRY_EXPORT: usize = 0;
+pub const IMAGE_DIRECTORY_ENTRY_IMPORT: usize = 1;
+pub const IMAGE_DIRECTORY_ENTRY_RESOURCE: usize = 2;
+pub const IMAGE_DIRECTORY_ENTRY_EXCEPTION: usize = 3;
+pub const IMAGE_DIRECTORY_ENTRY_SECURITY: usize = 4;
+pub const IMAGE_DIRECTORY_ENTRY_BASERELOC: usize = 5;
+pub const IMAGE_DIRECTORY_ENTRY_DEBUG: usize = 6;
+pub const IMAGE_DIRECTORY_ENTRY_TLS: usize = 9;
+pub const IMAGE_DIRECTORY_ENTRY_IAT: usize = 12;
+
+/// Base relocation types
+pub const IMAGE_REL_BASED_ABSOLUTE: u8 = 0;
+pub const IMAGE_REL_BASED_HIGHLOW: u8 = 3;
+pub const IMAGE_REL_BASED_DIR64: u8 = 10;
+
+/// Debug directory types
+pub const IMAGE_DEBUG_TYPE_CODEVIEW: u32 = 2;
+
+/// Size of a section header
+const SECTION_HEADER_SIZE: usize = 0x28;
+
+/// Size of an import descriptor
+const IMPORT_DESCRIPTOR_SIZE: usize = 0x14;
+
+/// Size of a `RUNTIME_FUNCTION` entry in the exception directory
+const RUNTIME_FUNCTION_SIZE: usize = 0xc;
+
+/// Size of a debug directory entry
+const DEBUG_DIRECTORY_SIZE: usize = 0x1c;
+
+/// Maximum length we accept for NUL terminated strings, such as DLL and function names
+const MAX_STRING_LEN: usize = 4096;
+
+/// Size of the COFF file header, including the `PE\0\0` signature
+const COFF_HEADER_SIZE: usize = 0x18;
+
+/// Reasons parsing or loading a PE can fail. Offsets are file offsets, RVAs are relative to the
+/// image base.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The file does not start with the `MZ` DOS header signature
+    BadMzSignature,
+
+    /// There is no `PE\0\0` signature at `offset`, the offset given by the DOS header
+    BadPeSignature { offset: usize },
+
+    /// The COFF header machine type is neither x86 nor x86_64
+    UnsupportedMachine(u16),
+
+    /// The optional header magic is neither PE32 nor PE32+
+    BadOptionalHeaderMagic(u16),
+
+    /// The optional header format does not match the machine type
+    MachineMismatch { machine: u16, magic: u16 },
+
+    /// The header `what`, `size` bytes at `offset`, extends past the end of the file
+    Truncated { what: &'static str, offset: usize, size: usize },
+
+    /// The raw data of section `section`, `size` bytes at `offset`, extends past the end of the
+    /// file
+    SectionOutOfBounds { section: usize, offset: usize, size: usize },
+
+    /// `what`, `size` bytes at `rva`, does not fit in the image passed to `load` or `relocate`
+    ImageOutOfBounds { what: &'static str, rva: u32, size: usize },
+
+    /// The base relocation at `rva` has a type we cannot apply
+    UnsupportedRelocation { rva: u32, typ: u8 },
+
+    /// The base relocation directory could not be read, or is malformed
+    BadRelocations,
+
+    /// The 32-bit base relocation at `rva` cannot address its target when the image is
+    /// relocated to `new_base`
+    RelocationOverflow { rva: u32, new_base: u64 },
+
+    /// An integer overflow occured while computing the location of `what`
+    Overflow { what: &'static str },
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            Error::BadMzSignature => write!(f, "missing MZ signature"),
+            Error::BadPeSignature { offset } =>
+                write!(f, "missing PE signature at offset {:#x}", offset),
+            Error::UnsupportedMachine(machine) =>
+                write!(f, "unsupported machine type {:#x}", machine),
+            Error::BadOptionalHeaderMagic(magic) =>
+                write!(f, "unknown optional header magic {:#x}", magic),
+            Error::MachineMismatch { machine, magic } =>
+                write!(f, "optional header magic {:#x} does not match machine {:#x}",
+                    magic, machine),
+            Error::Truncated { what, offset, size } =>
+                write!(f, "{} at offset {:#x} ({:#x} bytes) is truncated", what, offset, size),
+            Error::SectionOutOfBounds { section, offset, size } =>
+                write!(f, "section {} raw data at offset {:#x} ({:#x} bytes) is out of bounds",
+                    section, offset, size),
+            Error::ImageOutOfBounds { what, rva, size } =>
+                write!(f, "{} at RVA {:#x} ({:#x} bytes) is outside of the image",
+                    what, rva, size),
+            Error::UnsupportedRelocation { rva, typ } =>
+                write!(f, "unsupported relocation type {} at RVA {:#x}", typ, rva),
+            Error::BadRelocations => write!(f, "malformed base relocation directory"),
+            Error::RelocationOverflow { rva, new_base } =>
+                write!(f, "32-bit relocation at RVA {:#x} overflows at base {:#x}",
+                    rva, new_base),
+            Error::Overflow { what } => write!(f, "integer overflow computing {}", what),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// Result type for parsing and loading a PE
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Get the `size` bytes at `offset` in `bytes`, failing with `Error::Truncated` describing `what`
+fn get_bytes<'a>(bytes: &'a [u8], offset: usize, size: usize, what: &'static str)
+        -> Result<&'a [u8]> {
+    offset.checked_add(size)
+        .and_then(|end| bytes.get(offset..end))
+        .ok_or(Error::Truncated { what, offset, size })
+}
+
+/// Read a little endian `u16` at `off` in `bytes`
+fn read_u16(bytes: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(off..off.checked_add(2)?)?.try_into().ok()?))
+}
+
+/// Read a little endian `u32` at `off` in `bytes`
+fn read_u32(bytes: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(off..off.checked_add(4)?)?.try_into().ok()?))
+}
+
+/// Read a little endian `u64` at `off` in `bytes`
+fn read_u64(bytes: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(off..off.checked_add(8)?)?.try_into().ok()?))
+}
+
+/// Get the NUL terminated string starting at the beginning of `bytes`, without the terminator
+fn read_cstr(bytes: &[u8]) -> Option<&[u8]> {
+    let bytes = &bytes[..core::cmp::min(bytes.len(), MAX_STRING_LEN)];
+    let len = bytes.iter().position(|&x| x == 0)?;
+    Some(&bytes[..len])
+}
+
+/// The decoded optional header. Fields which only exist as 32-bit values in PE32 are widened
+/// such that PE32 and PE32+ files share one representation.
+#[derive(Clone, Copy, Debug)]
+pub struct OptionalHeader {
+    /// `IMAGE_NT_OPTIONAL_HDR32_MAGIC` or `IMAGE_NT_OPTIONAL_HDR64_MAGIC`
+    pub magic: u16,
+
+    /// Size of all the code sections
+    pub size_of_code: u32,
+
+    /// Relative virtual address of the entry point
+    pub address_of_entry_point: u32,
+
+    /// Preferred virtual address of the first byte of the image
+    pub image_base: u64,
+
+    /// Alignment of sections when loaded in memory
+    pub section_alignment: u32,
+
+    /// Alignment of the raw section data in the file
+    pub file_alignment: u32,
+
+    /// Size of the image when loaded in memory, including all headers
+    pub size_of_image: u32,
+
+    /// Size of all the headers, rounded up to `file_alignment`
+    pub size_of_headers: u32,
+
+    /// Image checksum, usually 0 for anything that is not a driver
+    pub checksum: u32,
+
+    /// Subsystem required to run the image
+    pub subsystem: u16,
+
+    /// `IMAGE_DLLCHARACTERISTICS_*` flags
+    pub dll_characteristics: u16,
+
+    /// Stack and heap sizes to reserve and commit
+    pub size_of_stack_reserve: u64,
+    pub size_of_stack_commit: u64,
+    pub size_of_heap_reserve: u64,
+    pub size_of_heap_commit: u64,
+
+    /// Number of entries in the data directory table
+    pub number_of_rva_and_sizes: u32,
+}
+
+/// An entry in the data directory table
+#[derive(Clone, Copy, Debug)]
+pub struct DataDirectory {
+    /// Relative virtual address of the table
+    pub rva: u32,
+
+    /// Size of the table in bytes
+    pub size: u32,
+}
+
+/// A section header from the section table
+#[derive(Clone, Copy, Debug)]
+pub struct SectionHeader<'a> {
+    /// Name of the section, without the NUL padding
+    pub name: &'a [u8],
+
+    /// Size of the section when loaded in memory
+    pub virtual_size: u32,
+
+    /// Relative virtual address of the section
+    pub virtual_address: u32,
+
+    /// Size of the initialized data in the file
+    pub raw_size: u32,
+
+    /// Offset of the initialized data in the file
+    pub raw_offset: u32,
+
+    /// `IMAGE_SCN_*` flags
+    pub characteristics: u32,
+}
+
+impl<'a> SectionHeader<'a> {
+    /// Returns true if the section is readable
+    pub fn read(&self) -> bool { (self.characteristics & IMAGE_SCN_MEM_READ) != 0 }
+
+    /// Returns true if the section is writable
+    pub fn write(&self) -> bool { (self.characteristics & IMAGE_SCN_MEM_WRITE) != 0 }
+
+    /// Returns true if the section is executable
+    pub fn execute(&self) -> bool { (self.characteristics & IMAGE_SCN_MEM_EXECUTE) != 0 }
+}
+
 pub struct PeParser<'a> {
     bytes: &'a [u8],
     image_base: u64,
     num_sections: usize,
     section_off: usize,
+    data_dir_off: usize,
+    /// Machine type from the COFF header
+    pub machine: u16,
+    /// `IMAGE_FILE_*` flags from the COFF header
+    pub characteristics: u16,
+    /// The decoded optional header
+    pub optional_header: OptionalHeader,
     /// Virtual Address of the entry point
     pub entry_point: u64,
 }
 
 impl<'a> PeParser<'a> {
-    pub fn parse(bytes: &'a [u8]) -> Option<Self> {
-        // Get a reference to bytes
-        let bytes: &[u8] = bytes.as_ref();
-
+    /// Parse and validate the headers of the PE in `bytes`. Only the DOS, COFF and optional
+    /// headers and the bounds of the section table are checked here, sections and data
+    /// directories are checked when they are accessed.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
         // Check for an MZ header
-        if bytes.get(0..2) != Some(b"MZ") { return None; }
+        if bytes.get(0..2) != Some(b"MZ") { return Err(Error::BadMzSignature); }
 
         // Get the PE offset
-        let pe_offset: usize =
-            u32::from_le_bytes(bytes.get(0x3c..0x40)?.try_into().ok()?).try_into().ok()?;
+        let pe_offset = read_u32(bytes, 0x3c)
+            .ok_or(Error::Truncated { what: "DOS header", offset: 0, size: 0x40 })? as usize;
 
         // Check for the PE signature
-        if bytes.get(pe_offset..pe_offset.checked_add(4)?) != Some(b"PE\0\0") {
-            return None;
+        if pe_offset.checked_add(4).and_then(|end| bytes.get(pe_offset..end)) !=
+                Some(b"PE\0\0") {
+            return Err(Error::BadPeSignature { offset: pe_offset });
         }
 
         // Make sure the COFF header is within bounds of our input
-        if pe_offset.checked_add(0x18)? > bytes.len() {
-            return None;
-        }
+        let coff = get_bytes(bytes, pe_offset, COFF_HEADER_SIZE, "COFF header")?;
 
         // Get machine field and check it belongs to x86 or x86_64
-        let machine: u16 = u16::from_le_bytes(bytes[pe_offset + 4..pe_offset + 6]
-            .try_into().ok()?);
+        let machine = read_u16(coff, 4).unwrap();
 
         if machine != IMAGE_FILE_MACHINE_I386 && machine != IMAGE_FILE_MACHINE_X86_64 {
-            return None;
+            return Err(Error::UnsupportedMachine(machine));
         }
 
         // Get number of sections
-        let num_sections: usize = u16::from_le_bytes(bytes[pe_offset + 6..pe_offset + 8]
-            .try_into().ok()?).try_into().ok()?;
+        let num_sections: usize = read_u16(coff, 6).unwrap().into();
 
         // Get optional header size
-        let opt_header_size: usize = u16::from_le_bytes(bytes[pe_offset + 0x14..pe_offset + 0x16]
-            .try_into().ok()?).into();
+        let opt_header_size: usize = read_u16(coff, 0x14).unwrap().into();
+
+        // Get the image characteristics
+        let characteristics = read_u16(coff, 0x16).unwrap();
 
+        // Decode the optional header, which directly follows the COFF header
+        let opt_off = pe_offset + COFF_HEADER_SIZE;
+        let opt = get_bytes(bytes, opt_off, opt_header_size, "optional header")?;
+        let (optional_header, data_dir_off) = Self::parse_optional_header(opt, opt_off)?;
+
+        // The optional header format must match the machine
+        if (machine == IMAGE_FILE_MACHINE_I386 &&
+                optional_header.magic != IMAGE_NT_OPTIONAL_HDR32_MAGIC) ||
+            (machine == IMAGE_FILE_MACHINE_X86_64 &&
+                optional_header.magic != IMAGE_NT_OPTIONAL_HDR64_MAGIC) {
+            return Err(Error::MachineMismatch { machine, magic: optional_header.magic });
+        }
 
         // Get the base for the program
-        let image_base = if machine == IMAGE_FILE_MACHINE_I386 {
-            u32::from_le_bytes(
-                bytes.get(pe_offset + 0x34..pe_offset + 0x38)?.try_into().ok()?
-            ) as u64
-        } else if machine == IMAGE_FILE_MACHINE_X86_64 {
-            u64::from_le_bytes(
-                bytes.get(pe_offset + 0x30..pe_offset + 0x38)?.try_into().ok()?
-            )
+        let image_base = optional_header.image_base;
+
+        let entry_point = image_base
+            .checked_add(optional_header.address_of_entry_point as u64)
+            .ok_or(Error::Overflow { what: "entry point" })?;
+
+        // Make sure the section table is within bounds of our input
+        let section_off = opt_off + opt_header_size;
+        get_bytes(bytes, section_off, SECTION_HEADER_SIZE * num_sections, "section table")?;
+
+        Ok(PeParser {
+            bytes,
+            image_base,
+            num_sections,
+            entry_point,
+            machine,
+            characteristics,
+            optional_header,
+            data_dir_off: opt_off + data_dir_off,
+            section_off,
+        })
+    }
+
+    /// Decode the optional header in `opt`, which is at `opt_off` in the file, returning the
+    /// header and the offset of the data directory table in `opt`
+    fn parse_optional_header(opt: &[u8], opt_off: usize) -> Result<(OptionalHeader, usize)> {
+        let magic = read_u16(opt, 0)
+            .ok_or(Error::Truncated { what: "optional header", offset: opt_off, size: 2 })?;
+
+        // Size of the fixed part of the header, which ends with the number of data directories
+        let fixed_size = match magic {
+            IMAGE_NT_OPTIONAL_HDR32_MAGIC => 0x60,
+            IMAGE_NT_OPTIONAL_HDR64_MAGIC => 0x70,
+            _ => return Err(Error::BadOptionalHeaderMagic(magic)),
+        };
+
+        get_bytes(opt, 0, fixed_size, "optional header")
+            .map_err(|_| Error::Truncated {
+                what: "optional header", offset: opt_off, size: fixed_size
+            })?;
+
+        // All reads of the fixed part are now in bounds
+        let r16 = |off: usize| read_u16(opt, off).unwrap();
+        let r32 = |off: usize| read_u32(opt, off).unwrap();
+        let r64 = |off: usize| read_u64(opt, off).unwrap();
+
+        // Offsets of the fields which differ between PE32 and PE32+
+        let (image_base, sizes) = if magic == IMAGE_NT_OPTIONAL_HDR32_MAGIC {
+            (r32(0x1c) as u64,
+                [r32(0x48) as u64, r32(0x4c) as u64, r32(0x50) as u64, r32(0x54) as u64])
         } else {
-            unreachable!();
+            (r64(0x18), [r64(0x48), r64(0x50), r64(0x58), r64(0x60)])
         };
 
-        let entry_point: u64 = u32::from_le_bytes(
-                bytes.get(pe_offset + 0x28..pe_offset + 0x2c)?.try_into().ok()?
-            ) as u64;
-        let entry_point = image_base.checked_add(entry_point)?;
+        // Make sure all the data directories claimed are in the optional header
+        let num_rvas = r32(fixed_size - 4);
+        let data_dir_size = (num_rvas as usize).checked_mul(8)
+            .ok_or(Error::Overflow { what: "data directory table" })?;
+        get_bytes(opt, fixed_size, data_dir_size, "data directory table")
+            .map_err(|_| Error::Truncated {
+                what: "data directory table", offset: opt_off + fixed_size, size: data_dir_size
+            })?;
+
+        Ok((OptionalHeader {
+            magic,
+            size_of_code:            r32(0x04),
+            address_of_entry_point:  r32(0x10),
+            image_base,
+            section_alignment:       r32(0x20),
+            file_alignment:          r32(0x24),
+            size_of_image:           r32(0x38),
+            size_of_headers:         r32(0x3c),
+            checksum:                r32(0x40),
+            subsystem:               r16(0x44),
+            dll_characteristics:     r16(0x46),
+            size_of_stack_reserve:   sizes[0],
+            size_of_stack_commit:    sizes[1],
+            size_of_heap_reserve:    sizes[2],
+            size_of_heap_commit:     sizes[3],
+            number_of_rva_and_sizes: num_rvas,
+        }, fixed_size))
+    }
+
+    /// Returns true if this is a PE32+ (64-bit) image
+    pub fn is_64bit(&self) -> bool {
+        self.optional_header.magic == IMAGE_NT_OPTIONAL_HDR64_MAGIC
+    }
 
-        // Compute the size of all headers, including sections
-        let header_size = pe_offset.checked_add(0x18)?
-            .checked_add(opt_header_size as usize)?
-            .checked_add(0x28usize.checked_mul(num_sections)?)?;
+    /// Returns true if the image can be loaded at an address other than its preferred image base
+    pub fn relocatable(&self) -> bool {
+        (self.characteristics & IMAGE_FILE_RELOCS_STRIPPED) == 0 &&
+            self.data_directory(IMAGE_DIRECTORY_ENTRY_BASERELOC).is_some()
+    }
 
-        if header_size > bytes.len() {
+    /// Get the data directory at `index` (one of the `IMAGE_DIRECTORY_ENTRY_*` constants). Returns
+    /// `None` if the image does not have this directory or it is empty.
+    pub fn data_directory(&self, index: usize) -> Option<DataDirectory> {
+        if index >= self.optional_header.number_of_rva_and_sizes as usize {
             return None;
         }
 
-        Some(PeParser {
-            bytes,
-            image_base,
-            num_sections,
-            entry_point,
-            section_off: pe_offset + 0x18 + opt_header_size,
+        let off = self.data_dir_off + index * 8;
+        let dir = DataDirectory {
+            rva: read_u32(self.bytes, off)?,
+            size: read_u32(self.bytes, off + 4)?,
+        };
+
+        if dir.rva == 0 || dir.size == 0 {
+            return None;
+        }
+
+        Some(dir)
+    }
+
+    /// Get the section header at `index` in the section table
+    pub fn section_header(&self, index: usize) -> Option<SectionHeader<'a>> {
+        if index >= self.num_sections {
+            return None;
+        }
+
+        let off = self.section_off.checked_add(index.checked_mul(SECTION_HEADER_SIZE)?)?;
+        let header = self.bytes.get(off..off.checked_add(SECTION_HEADER_SIZE)?)?;
+
+        // Strip the NUL padding from the name
+        let name = &header[..8];
+        let name = &name[..name.iter().position(|&x| x == 0).unwrap_or(name.len())];
+
+        Some(SectionHeader {
+            name,
+            virtual_size:    read_u32(header, 0x8)?,
+            virtual_address: read_u32(header, 0xc)?,
+            raw_size:        read_u32(header, 0x10)?,
+            raw_offset:      read_u32(header, 0x14)?,
+            characteristics: read_u32(header, 0x24)?,
         })
     }
 
+    /// Iterate over all the section headers. The section table was bounds checked by `parse`, so
+    /// every header can be read.
+    pub fn section_headers(&self) -> impl Iterator<Item = SectionHeader<'a>> + '_ {
+        (0..self.num_sections).filter_map(move |ii| self.section_header(ii))
+    }
+
+    /// Get the raw bytes backing section `index` in the file, clamped to the virtual size
+    fn section_raw(&self, index: usize, section: &SectionHeader) -> Result<&'a [u8]> {
+        let offset = section.raw_offset as usize;
+        let size = core::cmp::min(section.raw_size, section.virtual_size) as usize;
+
+        offset.checked_add(size)
+            .and_then(|end| self.bytes.get(offset..end))
+            .ok_or(Error::SectionOutOfBounds { section: index, offset, size })
+    }
+
     /// Invoke a closue with the format
     /// (virtual_address, virtual_size, raw_bytes, read, write, execture) for each section in the
-    /// PE file
-    pub fn sections<F: FnMut(u64, u32, &[u8], bool, bool, bool) -> Option<()>>(
-        &self,
-        mut func: F
-    ) -> Option<()> {
+    /// PE file. Iteration stops at the first error, either from the PE or returned by the closure.
+    pub fn sections<E, F>(&self, mut func: F) -> core::result::Result<(), E>
+            where E: From<Error>,
+                  F: FnMut(u64, u32, &[u8], bool, bool, bool) -> core::result::Result<(), E> {
+        for (ii, section) in self.section_headers().enumerate() {
+            let vaddr = self.image_base.checked_add(section.virtual_address as u64)
+                .ok_or(Error::Overflow { what: "section virtual address" })?;
+
+            func(
+                vaddr,
+                section.virtual_size,
+                self.section_raw(ii, &section)?,
+                section.read(),
+                section.write(),
+                section.execute(),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Copy the headers and all sections into `image`, laid out as they would be in memory, such
+    /// that `image[rva]` is the byte at relative virtual address `rva`. `image` must be at least
+    /// `size_of_image` bytes. Bytes of sections which are not initialized by the file are zeroed.
+    pub fn load(&self, image: &mut [u8]) -> Result<()> {
+        // Copy the headers
+        let headers = core::cmp::min(self.optional_header.size_of_headers as usize,
+            self.bytes.len());
+        image.get_mut(..headers)
+            .ok_or(Error::ImageOutOfBounds { what: "headers", rva: 0, size: headers })?
+            .copy_from_slice(&self.bytes[..headers]);
+
+        for (ii, section) in self.section_headers().enumerate() {
+            let raw = self.section_raw(ii, &section)?;
+
+            let vaddr = section.virtual_address as usize;
+            let vsize = section.virtual_size as usize;
+            let dest = vaddr.checked_add(vsize)
+                .and_then(|vend| image.get_mut(vaddr..vend))
+                .ok_or(Error::ImageOutOfBounds {
+                    what: "section", rva: section.virtual_address, size: vsize
+                })?;
+
+            let (init, uninit) = dest.split_at_mut(raw.len());
+            init.copy_from_slice(raw);
+            uninit.iter_mut().for_each(|x| *x = 0);
+        }
+
+        Ok(())
+    }
+
+    /// Apply the base relocations to `image`, an image previously populated by `load`, such that it
+    /// can run at `new_base` rather than its preferred image base
+    pub fn relocate(&self, image: &mut [u8], new_base: u64) -> Result<()> {
+        // Compute the offset to add to all absolute addresses
+        let delta = new_base.wrapping_sub(self.optional_header.image_base);
+
+        // A relocation directory we cannot read would silently leave the image unrelocated
+        if let Some(dir) = self.data_directory(IMAGE_DIRECTORY_ENTRY_BASERELOC) {
+            self.rva_bytes(dir.rva, dir.size as usize).ok_or(Error::BadRelocations)?;
+        }
+
+        for reloc in self.relocations() {
+            let reloc = reloc?;
+            let off = reloc.rva as usize;
+            let size = match reloc.typ {
+                IMAGE_REL_BASED_DIR64 => 8,
+                IMAGE_REL_BASED_HIGHLOW => 4,
+                typ => return Err(Error::UnsupportedRelocation { rva: reloc.rva, typ }),
+            };
+
+            let target = off.checked_add(size)
+                .and_then(|end| image.get_mut(off..end))
+                .ok_or(Error::ImageOutOfBounds {
+                    what: "relocation target", rva: reloc.rva, size
+                })?;
+
+            if size == 8 {
+                let val = read_u64(target, 0).unwrap().wrapping_add(delta);
+                target.copy_from_slice(&val.to_le_bytes());
+            } else {
+                // The relocated address must still be reachable with 32 bits
+                let val = (read_u32(target, 0).unwrap() as u64).wrapping_add(delta);
+                let val: u32 = val.try_into()
+                    .map_err(|_| Error::RelocationOverflow { rva: reloc.rva, new_base })?;
+                target.copy_from_slice(&val.to_le_bytes());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the `size` bytes at relative virtual address `rva`, as initialized by the file. Fails if
+    /// the range is not entirely backed by raw bytes of a single section or the headers.
+    pub fn rva_bytes(&self, rva: u32, size: usize) -> Option<&'a [u8]> {
+        self.rva_regions(rva).find_map(|region| region.get(..size))
+    }
+
+    /// Get the NUL terminated string at relative virtual address `rva`. The string must end within
+    /// the section, or the headers, it starts in.
+    fn rva_cstr(&self, rva: u32) -> Option<&'a [u8]> {
+        self.rva_regions(rva).find_map(read_cstr)
+    }
+
+    /// Iterate over the raw bytes from relative virtual address `rva` to the end of every region
+    /// of the file containing it, first the headers and then each section. Regions which end at
+    /// `rva` give an empty slice.
+    fn rva_regions(&self, rva: u32) -> impl Iterator<Item = &'a [u8]> + '_ {
+        let rva = rva as usize;
         let bytes = self.bytes;
 
-        for section in 0..self.num_sections {
-            let off = self.section_off + section * 0x28;
+        // The headers are mapped at RVA 0
+        let headers = (rva <= self.optional_header.size_of_headers as usize)
+            .then(|| bytes.get(rva..core::cmp::min(bytes.len(),
+                self.optional_header.size_of_headers as usize)))
+            .flatten();
 
-            // Get the virtual raw sizes and offsets
-            let virt_size = u32::from_le_bytes(bytes[off + 0x8..off + 0xc].try_into().ok()?);
-            let virt_addr = u32::from_le_bytes(bytes[off + 0xc..off + 0x10].try_into().ok()?);
-            let raw_size = u32::from_le_bytes(bytes[off + 0x10..off + 0x14].try_into().ok()?).try_into().ok()?;
-            let raw_off: usize = u32::from_le_bytes(bytes[off + 0x14..off + 0x18].try_into().ok()?).try_into().ok()?;
+        let sections = self.section_headers().filter_map(move |section| {
+            let start = section.virtual_address as usize;
+            let raw_size = core::cmp::min(section.raw_size, section.virtual_size) as usize;
 
-            // Get the section characteristics
-            let characteristics: u32 = u32::from_le_bytes(bytes[off + 0x24..off + 0x28].try_into().ok()?);
+            if rva < start || rva > start.checked_add(raw_size)? {
+                return None;
+            }
 
-            let raw_size: usize = core::cmp::min(raw_size, virt_size as usize).try_into().ok()?;
+            let off = (section.raw_offset as usize).checked_add(rva - start)?;
+            let end = (section.raw_offset as usize).checked_add(raw_size)?;
+            bytes.get(off..end)
+        });
 
-            func(
-                self.image_base.checked_add(virt_addr as u64)?,
-                virt_size,
-                bytes.get(raw_off..raw_off.checked_add(raw_size)?)?,
-                (characteristics & IMAGE_SCN_MEM_READ) != 0,
-                (characteristics & IMAGE_SCN_MEM_WRITE) != 0,
-                (characteristics & IMAGE_SCN_MEM_EXECUTE) != 0,
-            )?;
+        headers.into_iter().chain(sections)
+    }
+
+    /// Get the bytes of the data directory at `index`
+    fn directory_bytes(&self, index: usize) -> Option<(DataDirectory, &'a [u8])> {
+        let dir = self.data_directory(index)?;
+        Some((dir, self.rva_bytes(dir.rva, dir.size as usize)?))
+    }
+
+    /// Iterate over all the base relocations in the image. `IMAGE_REL_BASED_ABSOLUTE` entries,
+    /// which are only used as padding, are skipped. A malformed block yields
+    /// `Error::BadRelocations` and ends the iteration.
+    pub fn relocations(&self) -> Relocations<'a> {
+        Relocations {
+            bytes: self.directory_bytes(IMAGE_DIRECTORY_ENTRY_BASERELOC)
+                .map(|(_, bytes)| bytes).unwrap_or(&[]),
+            block_off: 0,
+            entry: 0,
+        }
+    }
+
+    /// Iterate over all the DLLs in the import directory
+    pub fn imports(&self) -> Imports<'_, 'a> {
+        Imports {
+            pe: self,
+            bytes: self.directory_bytes(IMAGE_DIRECTORY_ENTRY_IMPORT)
+                .map(|(_, bytes)| bytes).unwrap_or(&[]),
+            index: 0,
+        }
+    }
+
+    /// Get the export directory, if the image has one
+    pub fn exports(&self) -> Option<Exports<'_, 'a>> {
+        let (dir, bytes) = self.directory_bytes(IMAGE_DIRECTORY_ENTRY_EXPORT)?;
+
+        let num_functions = read_u32(bytes, 0x14)?;
+        let num_names = read_u32(bytes, 0x18)?;
+
+        // Get the three tables, making sure they are all in bounds
+        let functions = self.rva_bytes(read_u32(bytes, 0x1c)?,
+            (num_functions as usize).checked_mul(4)?)?;
+        let names = self.rva_bytes(read_u32(bytes, 0x20)?,
+            (num_names as usize).checked_mul(4)?)?;
+        let ordinals = self.rva_bytes(read_u32(bytes, 0x24)?,
+            (num_names as usize).checked_mul(2)?)?;
+
+        Some(Exports {
+            pe: self,
+            dir,
+            name: self.rva_cstr(read_u32(bytes, 0xc)?)?,
+            ordinal_base: read_u32(bytes, 0x10)?,
+            functions,
+            names,
+            ordinals,
+            index: 0,
+        })
+    }
+
+    /// Iterate over all the `RUNTIME_FUNCTION` entries in the exception (`.pdata`) directory
+    pub fn runtime_functions(&self) -> impl Iterator<Item = RuntimeFunction> + 'a {
+        let bytes = self.directory_bytes(IMAGE_DIRECTORY_ENTRY_EXCEPTION)
+            .map(|(_, bytes)| bytes).unwrap_or(&[]);
+
+        bytes.chunks_exact(RUNTIME_FUNCTION_SIZE).map(|entry| {
+            RuntimeFunction {
+                begin:       u32::from_le_bytes(entry[0x0..0x4].try_into().unwrap()),
+                end:         u32::from_le_bytes(entry[0x4..0x8].try_into().unwrap()),
+                unwind_info: u32::from_le_bytes(entry[0x8..0xc].try_into().unwrap()),
+            }
+        })
+    }
+
+    /// Iterate over all the entries in the debug directory
+    pub fn debug_directories(&self) -> impl Iterator<Item = DebugDirectory<'a>> + 'a {
+        let bytes = self.directory_bytes(IMAGE_DIRECTORY_ENTRY_DEBUG)
+            .map(|(_, bytes)| bytes).unwrap_or(&[]);
+        let file = self.bytes;
+
+        bytes.chunks_exact(DEBUG_DIRECTORY_SIZE).map(move |entry| {
+            let size = u32::from_le_bytes(entry[0x10..0x14].try_into().unwrap());
+            let raw_off = u32::from_le_bytes(entry[0x18..0x1c].try_into().unwrap()) as usize;
+
+            DebugDirectory {
+                typ: u32::from_le_bytes(entry[0xc..0x10].try_into().unwrap()),
+                rva: u32::from_le_bytes(entry[0x14..0x18].try_into().unwrap()),
+                data: raw_off.checked_add(size as usize)
+                    .and_then(|end| file.get(raw_off..end)),
+            }
+        })
+    }
+}
+
+/// A single base relocation
+#[derive(Clone, Copy, Debug)]
+pub struct Relocation {
+    /// Relative virtual address of the value to relocate
+    pub rva: u32,
+
+    /// One of the `IMAGE_REL_BASED_*` types
+    pub typ: u8,
+}
+
+/// Iterator over the base relocation blocks of an image
+pub struct Relocations<'a> {
+    /// Raw bytes of the base relocation directory
+    bytes: &'a [u8],
+
+    /// Offset of the current block in `bytes`
+    block_off: usize,
+
+    /// Index of the next entry in the current block
+    entry: usize,
+}
+
+impl<'a> Relocations<'a> {
+    /// Get the next relocation, or `None` once all blocks have been consumed
+    fn next_relocation(&mut self) -> Result<Option<Relocation>> {
+        loop {
+            if self.block_off >= self.bytes.len() {
+                return Ok(None);
+            }
+
+            // Get the current block header, a block has the page RVA, the size of the block
+            // including the header, and then 16-bit entries. The whole block must be in the
+            // directory.
+            let block = read_u32(self.bytes, self.block_off + 4)
+                .map(|size| size as usize)
+                .filter(|&size| size >= 8 && size % 2 == 0)
+                .and_then(|size| self.bytes.get(self.block_off..self.block_off.checked_add(size)?))
+                .ok_or(Error::BadRelocations)?;
+            let page_rva = read_u32(block, 0).unwrap();
+            let num_entries = (block.len() - 8) / 2;
+
+            if self.entry >= num_entries {
+                // Move to the next block, blocks are always 32-bit aligned
+                self.block_off += (block.len() + 3) & !3;
+                self.entry = 0;
+                continue;
+            }
+
+            let entry = read_u16(block, 8 + self.entry * 2).unwrap();
+            self.entry += 1;
+
+            let typ = (entry >> 12) as u8;
+            if typ == IMAGE_REL_BASED_ABSOLUTE {
+                continue;
+            }
+
+            return Ok(Some(Relocation {
+                rva: page_rva.checked_add((entry & 0xfff) as u32)
+                    .ok_or(Error::BadRelocations)?,
+                typ,
+            }));
+        }
+    }
+}
+
+impl<'a> Iterator for Relocations<'a> {
+    type Item = Result<Relocation>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let reloc = self.next_relocation();
+
+        // Stop at the first malformed block, we cannot know where the next one starts
+        if reloc.is_err() {
+            self.block_off = self.bytes.len();
+        }
+
+        reloc.transpose()
+    }
+}
+
+/// Iterator over the import descriptors of an image
+pub struct Imports<'p, 'a> {
+    pe: &'p PeParser<'a>,
+
+    /// Raw bytes of the import directory
+    bytes: &'a [u8],
+
+    /// Index of the next import descriptor
+    index: usize,
+}
+
+impl<'p, 'a> Iterator for Imports<'p, 'a> {
+    type Item = Import<'p, 'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let off = self.index.checked_mul(IMPORT_DESCRIPTOR_SIZE)?;
+        let desc = self.bytes.get(off..off.checked_add(IMPORT_DESCRIPTOR_SIZE)?)?;
+        self.index += 1;
+
+        let lookup_rva = read_u32(desc, 0x0)?;
+        let name_rva = read_u32(desc, 0xc)?;
+        let iat_rva = read_u32(desc, 0x10)?;
+
+        // The table is terminated by an all zero descriptor
+        if name_rva == 0 && iat_rva == 0 {
+            return None;
         }
 
-        Some(())
+        Some(Import {
+            pe: self.pe,
+            dll: self.pe.rva_cstr(name_rva)?,
+            // Some linkers do not emit a lookup table, the IAT has the same contents on disk
+            lookup_rva: if lookup_rva != 0 { lookup_rva } else { iat_rva },
+            iat_rva,
+        })
+    }
+}
+
+/// A DLL imported by the image
+#[derive(Clone, Copy)]
+pub struct Import<'p, 'a> {
+    pe: &'p PeParser<'a>,
+
+    /// Name of the DLL
+    pub dll: &'a [u8],
+
+    /// Relative virtual address of the import lookup table
+    pub lookup_rva: u32,
+
+    /// Relative virtual address of the import address table, which the loader fills in
+    pub iat_rva: u32,
+}
+
+/// A function imported from a DLL
+#[derive(Clone, Copy, Debug)]
+pub enum ImportedFunction<'a> {
+    /// Imported by ordinal
+    Ordinal(u16),
+
+    /// Imported by name, with a hint into the exporting DLL's name table
+    Name { hint: u16, name: &'a [u8] },
+}
+
+impl<'p, 'a> Import<'p, 'a> {
+    /// Iterate over the functions imported from this DLL, as (IAT entry RVA, function)
+    pub fn functions(&self) -> impl Iterator<Item = Option<(u32, ImportedFunction<'a>)>> + 'p {
+        let pe = self.pe;
+        let lookup_rva = self.lookup_rva;
+        let iat_rva = self.iat_rva;
+        let thunk_size: u32 = if pe.is_64bit() { 8 } else { 4 };
+
+        (0u32..).map(move |ii| {
+            let off = ii.checked_mul(thunk_size)?;
+            let thunk = pe.rva_bytes(lookup_rva.checked_add(off)?, thunk_size as usize)?;
+
+            let (thunk, by_ordinal) = if thunk_size == 8 {
+                let thunk = read_u64(thunk, 0)?;
+                (thunk & !(1 << 63), (thunk >> 63) != 0)
+            } else {
+                let thunk = read_u32(thunk, 0)? as u64;
+                (thunk & !(1 << 31), (thunk >> 31) != 0)
+            };
+
+            Some((thunk, by_ordinal, iat_rva.checked_add(off)?))
+        })
+        .take_while(|x| !matches!(x, Some((0, false, _))))
+        .map(move |x| {
+            let (thunk, by_ordinal, iat) = x?;
+
+            if by_ordinal {
+                return Some((iat, ImportedFunction::Ordinal(thunk as u16)));
+            }
+
+            // By name, the thunk is the RVA of a hint followed by a NUL terminated name
+            let rva: u32 = thunk.try_into().ok()?;
+            let hint = read_u16(pe.rva_bytes(rva, 2)?, 0)?;
+            let name = pe.rva_cstr(rva.checked_add(2)?)?;
+
+            Some((iat, ImportedFunction::Name { hint, name }))
+        })
+        .scan(false, |failed, x| {
+            // Stop after yielding the first error, the rest of the table cannot be trusted
+            if *failed { return None; }
+            *failed = x.is_none();
+            Some(x)
+        })
+    }
+}
+
+/// The export directory of an image
+pub struct Exports<'p, 'a> {
+    pe: &'p PeParser<'a>,
+
+    /// The export data directory, used to detect forwarded exports
+    dir: DataDirectory,
+
+    /// Name of the image as recorded at link time
+    pub name: &'a [u8],
+
+    /// Ordinal of the first entry in the export address table
+    pub ordinal_base: u32,
+
+    /// Export address table
+    functions: &'a [u8],
+
+    /// Export name pointer table
+    names: &'a [u8],
+
+    /// Export ordinal table, parallel to `names`
+    ordinals: &'a [u8],
+
+    /// Index of the next entry in the export address table
+    index: usize,
+}
+
+/// Where an exported symbol lives
+#[derive(Clone, Copy, Debug)]
+pub enum ExportTarget<'a> {
+    /// Relative virtual address of the symbol in this image
+    Rva(u32),
+
+    /// The export is forwarded to another DLL, as a `"DLL.Symbol"` string
+    Forwarder(&'a [u8]),
+}
+
+/// A symbol exported by the image
+#[derive(Clone, Copy, Debug)]
+pub struct Export<'a> {
+    /// Ordinal of the export
+    pub ordinal: u32,
+
+    /// Name of the export, if it is exported by name
+    pub name: Option<&'a [u8]>,
+
+    /// Location of the exported symbol
+    pub target: ExportTarget<'a>,
+}
+
+impl<'p, 'a> Iterator for Exports<'p, 'a> {
+    type Item = Option<Export<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Skip unused entries in the export address table
+        let (index, rva) = loop {
+            let index = self.index;
+            let rva = read_u32(self.functions, index.checked_mul(4)?)?;
+            self.index += 1;
+
+            if rva != 0 {
+                break (index, rva);
+            }
+        };
+
+        Some((|| {
+            // Find the name for this export, if there is one
+            let mut name = None;
+            for (ii, ordinal) in self.ordinals.chunks_exact(2).enumerate() {
+                if u16::from_le_bytes([ordinal[0], ordinal[1]]) as usize == index {
+                    name = Some(self.pe.rva_cstr(read_u32(self.names, ii * 4)?)?);
+                    break;
+                }
+            }
+
+            // Exports pointing into the export directory are forwarders
+            let dir_end = self.dir.rva.checked_add(self.dir.size)?;
+            let target = if rva >= self.dir.rva && rva < dir_end {
+                ExportTarget::Forwarder(self.pe.rva_cstr(rva)?)
+            } else {
+                ExportTarget::Rva(rva)
+            };
+
+            Some(Export {
+                ordinal: self.ordinal_base.checked_add(index.try_into().ok()?)?,
+                name,
+                target,
+            })
+        })())
+    }
+}
+
+/// An entry in the exception directory, describing the unwind information for a function
+#[derive(Clone, Copy, Debug)]
+pub struct RuntimeFunction {
+    /// Relative virtual address of the start of the function
+    pub begin: u32,
+
+    /// Relative virtual address of the end of the function
+    pub end: u32,
+
+    /// Relative virtual address of the unwind information
+    pub unwind_info: u32,
+}
+
+/// An entry in the debug directory
+#[derive(Clone, Copy, Debug)]
+pub struct DebugDirectory<'a> {
+    /// One of the `IMAGE_DEBUG_TYPE_*` types
+    pub typ: u32,
+
+    /// Relative virtual address of the debug data, 0 if it is not mapped
+    pub rva: u32,
+
+    /// The raw debug data from the file, `None` if it is out of bounds
+    pub data: Option<&'a [u8]>,
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use std::vec::Vec;
+    use crate::*;
+
+    /// The bootloader, as built for the i586 target, used as a known good PE32 image
+    static BOOTLOADER: &[u8] = include_bytes!("../bootloader.exe");
+
+    /// A relocatable PE32+ DLL with exports, `.pdata` and a debug directory, built from
+    /// `fixture.rs`
+    static FIXTURE: &[u8] = include_bytes!("../fixture.dll");
+
+    /// Get a copy of the bootloader and the file offset of its PE signature
+    fn bootloader() -> (Vec<u8>, usize) {
+        let pe_offset = read_u32(BOOTLOADER, 0x3c).unwrap() as usize;
+        (BOOTLOADER.to_vec(), pe_offset)
+    }
+
+    /// Build a relocatable PE32+ image based at 0x400000 with a `.data` section at 0x1000, which
+    /// holds a 64-bit pointer at 0x1000, a 32-bit pointer at 0x1010 and another 64-bit pointer at
+    /// 0x1020, and a `.reloc` section at 0x2000 holding `relocs` as the base relocation directory
+    fn build_relocatable(relocs: &[u8]) -> Vec<u8> {
+        let mut pe = std::vec![0u8; 0x600];
+
+        // DOS header, COFF header and optional header with all 16 data directories
+        pe[..2].copy_from_slice(b"MZ");
+        pe[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        pe[0x40..0x44].copy_from_slice(b"PE\0\0");
+        pe[0x44..0x46].copy_from_slice(&IMAGE_FILE_MACHINE_X86_64.to_le_bytes());
+        pe[0x46..0x48].copy_from_slice(&2u16.to_le_bytes());
+        pe[0x54..0x56].copy_from_slice(&0xf0u16.to_le_bytes());
+        pe[0x56..0x58].copy_from_slice(&0x2022u16.to_le_bytes());
+        pe[0x58..0x5a].copy_from_slice(&IMAGE_NT_OPTIONAL_HDR64_MAGIC.to_le_bytes());
+        pe[0x70..0x78].copy_from_slice(&0x40_0000u64.to_le_bytes());
+        pe[0x90..0x94].copy_from_slice(&0x3000u32.to_le_bytes());
+        pe[0x94..0x98].copy_from_slice(&0x200u32.to_le_bytes());
+        pe[0xc4..0xc8].copy_from_slice(&16u32.to_le_bytes());
+
+        let dir = 0xc8 + IMAGE_DIRECTORY_ENTRY_BASERELOC * 8;
+        pe[dir..dir + 4].copy_from_slice(&0x2000u32.to_le_bytes());
+        pe[dir + 4..dir + 8].copy_from_slice(&(relocs.len() as u32).to_le_bytes());
+
+        // Section headers as (name, vaddr, vsize, raw offset)
+        let sections = [
+            (b".data\0\0\0", 0x1000u32, 0x100u32, 0x200u32),
+            (b".reloc\0\0", 0x2000, relocs.len() as u32, 0x400),
+        ];
+        for (ii, &(name, vaddr, vsize, raw_offset)) in sections.iter().enumerate() {
+            let header = &mut pe[0x148 + ii * SECTION_HEADER_SIZE..];
+            header[0x0..0x8].copy_from_slice(name);
+            header[0x8..0xc].copy_from_slice(&vsize.to_le_bytes());
+            header[0xc..0x10].copy_from_slice(&vaddr.to_le_bytes());
+            header[0x10..0x14].copy_from_slice(&0x200u32.to_le_bytes());
+            header[0x14..0x18].copy_from_slice(&raw_offset.to_le_bytes());
+            header[0x24..0x28].copy_from_slice(&(IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE)
+                .to_le_bytes());
+        }
+
+        // Pointers into the image, relative to the preferred base
+        pe[0x200..0x208].copy_from_slice(&0x40_1000u64.to_le_bytes());
+        pe[0x210..0x214].copy_from_slice(&0x40_1010u32.to_le_bytes());
+        pe[0x220..0x228].copy_from_slice(&0x40_1020u64.to_le_bytes());
+
+        pe[0x400..0x400 + relocs.len()].copy_from_slice(relocs);
+        pe
+    }
+
+    /// Build a base relocation block for the page at `page_rva`, claiming to be `size` bytes
+    fn reloc_block(page_rva: u32, size: u32, entries: &[u16]) -> Vec<u8> {
+        let mut block = Vec::new();
+        block.extend_from_slice(&page_rva.to_le_bytes());
+        block.extend_from_slice(&size.to_le_bytes());
+        entries.iter().for_each(|entry| block.extend_from_slice(&entry.to_le_bytes()));
+        block
+    }
+
+    /// A block relocating the 64-bit pointer at 0x1000 and the 32-bit one at 0x1010, with an
+    /// `IMAGE_REL_BASED_ABSOLUTE` entry pointing at the pointer at 0x1020 and one as padding
+    fn good_relocs() -> Vec<u8> {
+        reloc_block(0x1000, 0x10, &[0xa000, 0x3010, 0x0020, 0x0000])
+    }
+
+    /// Exercise every public API on `bytes`, which must never panic regardless of the input
+    fn exercise(bytes: &[u8]) {
+        let pe = match PeParser::parse(bytes) {
+            Ok(pe) => pe,
+            Err(_) => return,
+        };
+
+        let _ = pe.sections(|_, _, _, _, _, _| Ok::<(), Error>(()));
+
+        // Cap the image size so corrupted headers do not make us allocate gigabytes
+        let size = core::cmp::min(pe.optional_header.size_of_image as usize, 1024 * 1024);
+        let mut image = std::vec![0u8; size];
+        if pe.load(&mut image).is_ok() {
+            let _ = pe.relocate(&mut image, 0xffff_f000_0000_0000);
+        }
+
+        for import in pe.imports().take(64) {
+            for _ in import.functions().take(64) {}
+        }
+        if let Some(exports) = pe.exports() {
+            for _ in exports.take(64) {}
+        }
+        for _ in pe.runtime_functions().take(64) {}
+        for _ in pe.debug_directories().take(64) {}
+    }
+
+    #[test]
+    fn test_bootloader_headers() {
+        let pe = PeParser::parse(BOOTLOADER).unwrap();
+
+        assert_eq!(pe.machine, IMAGE_FILE_MACHINE_I386);
+        assert!(!pe.is_64bit());
+        assert!(!pe.relocatable());
+        assert_eq!(pe.optional_header.magic, IMAGE_NT_OPTIONAL_HDR32_MAGIC);
+        assert_eq!(pe.optional_header.image_base, 0x6e00);
+        assert_eq!(pe.optional_header.address_of_entry_point, 0x1000);
+        assert_eq!(pe.optional_header.size_of_image, 0x1047);
+        assert_eq!(pe.optional_header.size_of_headers, 0x1000);
+        assert_eq!(pe.optional_header.number_of_rva_and_sizes, 16);
+        assert_eq!(pe.entry_point, 0x7e00);
+    }
+
+    #[test]
+    fn test_bootloader_sections() {
+        let pe = PeParser::parse(BOOTLOADER).unwrap();
+
+        let headers: Vec<_> = pe.section_headers().collect();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[0].name, b".text");
+        assert_eq!(headers[0].virtual_address, 0x1000);
+        assert!(headers[0].read() && !headers[0].write() && headers[0].execute());
+        assert_eq!(headers[1].name, b".rdata");
+        assert_eq!(headers[1].virtual_address, 0x1012);
+        assert!(headers[1].read() && !headers[1].write() && !headers[1].execute());
+
+        let mut sections = Vec::new();
+        pe.sections(|vaddr, vsize, raw, r, w, x| {
+            sections.push((vaddr, vsize, raw.len(), r, w, x));
+            Ok::<(), Error>(())
+        }).unwrap();
+        assert_eq!(sections, [
+            (0x7e00, 0x12, 0x12, true, false, true),
+            (0x7e12, 0x35, 0x35, true, false, false),
+        ]);
+
+        // Errors from the closure are passed through
+        assert_eq!(pe.sections(|_, _, _, _, _, _| Err(Error::BadRelocations)),
+            Err(Error::BadRelocations));
+    }
+
+    #[test]
+    fn test_bootloader_directories() {
+        let pe = PeParser::parse(BOOTLOADER).unwrap();
+
+        assert_eq!(pe.relocations().count(), 0);
+        assert_eq!(pe.imports().count(), 0);
+        assert!(pe.exports().is_none());
+        assert_eq!(pe.runtime_functions().count(), 0);
+
+        let debug: Vec<_> = pe.debug_directories().collect();
+        assert_eq!(debug.len(), 1);
+        assert_eq!(debug[0].typ, IMAGE_DEBUG_TYPE_CODEVIEW);
+        assert_eq!(debug[0].rva, 0x102e);
+        assert_eq!(debug[0].data.map(|x| x.len()), Some(25));
+    }
+
+    #[test]
+    fn test_bootloader_load() {
+        let pe = PeParser::parse(BOOTLOADER).unwrap();
+
+        let mut image = std::vec![0xccu8; pe.optional_header.size_of_image as usize];
+        pe.load(&mut image).unwrap();
+
+        // Headers and sections are at their RVAs
+        assert_eq!(&image[..0x400], &BOOTLOADER[..0x400]);
+        assert_eq!(&image[0x1000..0x1012], &BOOTLOADER[0x1000..0x1012]);
+        assert_eq!(&image[0x1012..0x1047], &BOOTLOADER[0x2000..0x2035]);
+
+        // Without relocations, relocating does not change anything
+        let loaded = image.clone();
+        pe.relocate(&mut image, 0x10000).unwrap();
+        assert_eq!(image, loaded);
+
+        // The image must be large enough for all sections
+        assert_eq!(pe.load(&mut image[..0x1040]), Err(Error::ImageOutOfBounds {
+            what: "section", rva: 0x1012, size: 0x35,
+        }));
+    }
+
+    #[test]
+    fn test_fixture_headers() {
+        let pe = PeParser::parse(FIXTURE).unwrap();
+
+        assert_eq!(pe.machine, IMAGE_FILE_MACHINE_X86_64);
+        assert!(pe.is_64bit());
+        assert!(pe.relocatable());
+        assert_eq!(pe.optional_header.magic, IMAGE_NT_OPTIONAL_HDR64_MAGIC);
+        assert_eq!(pe.optional_header.image_base, 0x1_8000_0000);
+        assert_eq!(pe.optional_header.size_of_image, 0x6000);
+        assert_eq!(pe.optional_header.size_of_headers, 0x400);
+
+        let names: Vec<_> = pe.section_headers().map(|section| section.name).collect();
+        assert_eq!(names, [&b".text"[..], b".rdata", b".data", b".pdata", b".reloc"]);
+    }
+
+    #[test]
+    fn test_fixture_directories() {
+        let pe = PeParser::parse(FIXTURE).unwrap();
+
+        let relocs: Vec<_> = pe.relocations().map(|reloc| {
+            let reloc = reloc.unwrap();
+            (reloc.rva, reloc.typ)
+        }).collect();
+        assert_eq!(relocs, [
+            (0x2000, IMAGE_REL_BASED_DIR64),
+            (0x2008, IMAGE_REL_BASED_DIR64),
+            (0x3000, IMAGE_REL_BASED_DIR64),
+        ]);
+
+        assert_eq!(pe.imports().count(), 0);
+
+        let exports = pe.exports().unwrap();
+        assert_eq!(exports.name, b"fixture.dll");
+        assert_eq!(exports.ordinal_base, 1);
+        let exports: Vec<_> = exports.map(|export| {
+            let export = export.unwrap();
+            let rva = match export.target {
+                ExportTarget::Rva(rva) => rva,
+                ExportTarget::Forwarder(_) => panic!("unexpected forwarder"),
+            };
+            (export.ordinal, export.name.unwrap(), rva)
+        }).collect();
+        assert_eq!(exports, [
+            (1, &b"TABLE"[..], 0x2000),
+            (2, b"first", 0x1000),
+            (3, b"second", 0x1010),
+        ]);
+
+        // Only `second` has a stack frame, and thus unwind information
+        let functions: Vec<_> = pe.runtime_functions()
+            .map(|func| (func.begin, func.end, func.unwind_info)).collect();
+        assert_eq!(functions, [(0x1010, 0x1022, 0x20d4)]);
+
+        let debug: Vec<_> = pe.debug_directories().collect();
+        assert_eq!(debug.len(), 2);
+        assert_eq!(debug[0].typ, IMAGE_DEBUG_TYPE_CODEVIEW);
+        assert_eq!(debug[0].rva, 0x2048);
+        let codeview = debug[0].data.unwrap();
+        assert_eq!(&codeview[..4], b"RSDS");
+        assert_eq!(&codeview[0x18..], b"fixture.pdb\0");
+    }
+
+    #[test]
+    fn test_fixture_names() {
+        // Remove the terminator of the name of the last export, "second" at 0x20ca, and fill the
+        // rest of `.rdata` up to its virtual size. The file has zero padding after that, but the
+        // name must not be read past the section.
+        let mut bytes = FIXTURE.to_vec();
+        let pe = PeParser::parse(FIXTURE).unwrap();
+        let rdata = pe.section_headers().nth(1).unwrap();
+        let raw_offset = rdata.raw_offset as usize;
+        let name = raw_offset + 0xca;
+        assert_eq!(&bytes[name..name + 7], b"second\0");
+        bytes[name + 6..raw_offset + rdata.virtual_size as usize].fill(b'x');
+
+        let pe = PeParser::parse(&bytes).unwrap();
+        let names: Vec<_> = pe.exports().unwrap()
+            .map(|export| export.map(|export| export.name.unwrap())).collect();
+        assert_eq!(names, [Some(&b"TABLE"[..]), Some(b"first"), None]);
+    }
+
+    #[test]
+    fn test_fixture_relocate() {
+        let pe = PeParser::parse(FIXTURE).unwrap();
+
+        let mut image = std::vec![0xccu8; pe.optional_header.size_of_image as usize];
+        pe.load(&mut image).unwrap();
+        assert_eq!(&image[0x1000..0x1004], &FIXTURE[0x400..0x404]);
+        assert_eq!(read_u64(&image, 0x2000), Some(0x1_8000_1000));
+        assert_eq!(read_u64(&image, 0x3000), Some(0x1_8000_2000));
+
+        // `TABLE` points at `first` and `second`, and `.data` points at `TABLE`
+        pe.relocate(&mut image, 0xffff_f000_0000_0000).unwrap();
+        assert_eq!(read_u64(&image, 0x2000), Some(0xffff_f000_0000_1000));
+        assert_eq!(read_u64(&image, 0x2008), Some(0xffff_f000_0000_1010));
+        assert_eq!(read_u64(&image, 0x3000), Some(0xffff_f000_0000_2000));
+    }
+
+    #[test]
+    fn test_relocate() {
+        let bytes = build_relocatable(&good_relocs());
+        let pe = PeParser::parse(&bytes).unwrap();
+        assert!(pe.relocatable());
+
+        let relocs: Vec<_> = pe.relocations().map(|reloc| {
+            let reloc = reloc.unwrap();
+            (reloc.rva, reloc.typ)
+        }).collect();
+        assert_eq!(relocs, [(0x1000, IMAGE_REL_BASED_DIR64), (0x1010, IMAGE_REL_BASED_HIGHLOW)]);
+
+        let mut image = std::vec![0xccu8; pe.optional_header.size_of_image as usize];
+        pe.load(&mut image).unwrap();
+
+        // Relocate up, then back down below the preferred base
+        for &base in &[0x80_0000u64, 0x10_0000] {
+            let mut image = image.clone();
+            pe.relocate(&mut image, base).unwrap();
+            assert_eq!(&image[0x1000..0x1008], &(base + 0x1000).to_le_bytes());
+            assert_eq!(&image[0x1010..0x1014], &(base as u32 + 0x1010).to_le_bytes());
+            assert_eq!(&image[0x1020..0x1028], &0x40_1020u64.to_le_bytes());
+        }
+
+        // The 32-bit pointer cannot reach an image above 4 GiB
+        assert_eq!(pe.relocate(&mut image, 0xffff_f000_0000_0000),
+            Err(Error::RelocationOverflow { rva: 0x1010, new_base: 0xffff_f000_0000_0000 }));
+    }
+
+    #[test]
+    fn test_bad_relocations() {
+        // Returns the first relocation and the result of relocating the image built from `relocs`
+        let relocate = |relocs: &[u8]| {
+            let bytes = build_relocatable(relocs);
+            let pe = PeParser::parse(&bytes).unwrap();
+            let mut image = std::vec![0u8; pe.optional_header.size_of_image as usize];
+            pe.load(&mut image).unwrap();
+            (pe.relocations().next().map(|reloc| reloc.map(|reloc| reloc.rva)),
+                pe.relocate(&mut image, 0x80_0000))
+        };
+
+        // Block smaller than its own header
+        assert_eq!(relocate(&reloc_block(0x1000, 4, &[0xa000, 0])),
+            (Some(Err(Error::BadRelocations)), Err(Error::BadRelocations)));
+
+        // Block running past the end of the directory
+        assert_eq!(relocate(&reloc_block(0x1000, 0x20, &[0xa000, 0])),
+            (Some(Err(Error::BadRelocations)), Err(Error::BadRelocations)));
+
+        // Truncated entry, and a directory too small for a block header
+        assert_eq!(relocate(&reloc_block(0x1000, 0xb, &[0xa000, 0x3010])),
+            (Some(Err(Error::BadRelocations)), Err(Error::BadRelocations)));
+        assert_eq!(relocate(&good_relocs()[..6]),
+            (Some(Err(Error::BadRelocations)), Err(Error::BadRelocations)));
+
+        // A malformed block after a good one is still an error, and ends the iteration
+        let mut relocs = good_relocs();
+        relocs.extend_from_slice(&reloc_block(0x1000, 0, &[0xa000, 0]));
+        let bytes = build_relocatable(&relocs);
+        let pe = PeParser::parse(&bytes).unwrap();
+        let results: Vec<_> = pe.relocations().map(|reloc| reloc.map(|reloc| reloc.rva)).collect();
+        assert_eq!(results, [Ok(0x1000), Ok(0x1010), Err(Error::BadRelocations)]);
+
+        // Entry overflowing the RVA space
+        assert_eq!(relocate(&reloc_block(0xffff_ffff, 0xc, &[0xa001, 0])),
+            (Some(Err(Error::BadRelocations)), Err(Error::BadRelocations)));
+
+        // Unsupported type, `IMAGE_REL_BASED_HIGH`
+        assert_eq!(relocate(&reloc_block(0x1000, 0xc, &[0x1010, 0])),
+            (Some(Ok(0x1010)), Err(Error::UnsupportedRelocation { rva: 0x1010, typ: 1 })));
+
+        // Target outside of the image
+        assert_eq!(relocate(&reloc_block(0x2000, 0xc, &[0xaffc, 0])).1,
+            Err(Error::ImageOutOfBounds { what: "relocation target", rva: 0x2ffc, size: 8 }));
+    }
+
+    #[test]
+    fn test_bad_signatures() {
+        assert_eq!(PeParser::parse(&[]).err(), Some(Error::BadMzSignature));
+        assert_eq!(PeParser::parse(b"MZ").err(), Some(Error::Truncated {
+            what: "DOS header", offset: 0, size: 0x40,
+        }));
+
+        let (mut bytes, pe_offset) = bootloader();
+        bytes[pe_offset] = b'X';
+        assert_eq!(PeParser::parse(&bytes).err(),
+            Some(Error::BadPeSignature { offset: pe_offset }));
+
+        // Point the PE offset past the end of the file
+        let (mut bytes, _) = bootloader();
+        bytes[0x3c..0x40].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(PeParser::parse(&bytes).err(),
+            Some(Error::BadPeSignature { offset: u32::MAX as usize }));
+    }
+
+    #[test]
+    fn test_bad_machine() {
+        let (mut bytes, pe_offset) = bootloader();
+        bytes[pe_offset + 4..pe_offset + 6].copy_from_slice(&0xaa64u16.to_le_bytes());
+        assert_eq!(PeParser::parse(&bytes).err(), Some(Error::UnsupportedMachine(0xaa64)));
+
+        let (mut bytes, pe_offset) = bootloader();
+        bytes[pe_offset + 0x18..pe_offset + 0x1a].copy_from_slice(&0x1234u16.to_le_bytes());
+        assert_eq!(PeParser::parse(&bytes).err(), Some(Error::BadOptionalHeaderMagic(0x1234)));
+
+        // A PE32 header claiming to be x86_64
+        let (mut bytes, pe_offset) = bootloader();
+        bytes[pe_offset + 4..pe_offset + 6]
+            .copy_from_slice(&IMAGE_FILE_MACHINE_X86_64.to_le_bytes());
+        assert_eq!(PeParser::parse(&bytes).err(), Some(Error::MachineMismatch {
+            machine: IMAGE_FILE_MACHINE_X86_64, magic: IMAGE_NT_OPTIONAL_HDR32_MAGIC,
+        }));
+    }
+
+    #[test]
+    fn test_lying_headers() {
+        // Claim more sections than fit in the file
+        let (mut bytes, pe_offset) = bootloader();
+        bytes[pe_offset + 6..pe_offset + 8].copy_from_slice(&0xffffu16.to_le_bytes());
+        assert!(matches!(PeParser::parse(&bytes),
+            Err(Error::Truncated { what: "section table", .. })));
+
+        // Claim more data directories than fit in the optional header
+        let (mut bytes, pe_offset) = bootloader();
+        let num_rvas = pe_offset + 0x18 + 0x5c;
+        bytes[num_rvas..num_rvas + 4].copy_from_slice(&0x1000_0000u32.to_le_bytes());
+        assert!(matches!(PeParser::parse(&bytes),
+            Err(Error::Truncated { what: "data directory table", .. })));
+
+        // Point the raw data of the second section past the end of the file
+        let (mut bytes, pe_offset) = bootloader();
+        let opt_size = read_u16(&bytes, pe_offset + 0x14).unwrap() as usize;
+        let raw_offset = pe_offset + 0x18 + opt_size + SECTION_HEADER_SIZE + 0x14;
+        bytes[raw_offset..raw_offset + 4].copy_from_slice(&0xffff_0000u32.to_le_bytes());
+        let pe = PeParser::parse(&bytes).unwrap();
+        assert_eq!(pe.sections(|_, _, _, _, _, _| Ok::<(), Error>(())),
+            Err(Error::SectionOutOfBounds { section: 1, offset: 0xffff_0000, size: 0x35 }));
+    }
+
+    #[test]
+    fn test_truncated() {
+        // Every prefix of a valid file must fail cleanly or parse
+        for file in &[BOOTLOADER, FIXTURE] {
+            for len in 0..=file.len() {
+                exercise(&file[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mutated() {
+        // Corrupt random bytes in the headers with a fixed seed xorshift, such that failures
+        // reproduce
+        let mut seed = 0x8e3a_51c2_d07f_4b19u64;
+        let mut rand = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+
+        for _ in 0..20000 {
+            let mut bytes = BOOTLOADER[..0x1100].to_vec();
+            for _ in 0..(rand() % 8) + 1 {
+                let off = (rand() % 0x400) as usize;
+                bytes[off] = rand() as u8;
+            }
+            exercise(&bytes);
+        }
     }
 }
@@ -0,0 +1,192 @@
+//! Random number generation. Entropy comes from `rdseed` or `rdrand` when the CPU has them, and
+//! from the jitter of the time stamp counter otherwise. `Rng` is a fast, non-cryptographic PRNG
+//! to be seeded from that entropy, meant to be kept per core.
+#![no_std]
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of times to retry `rdrand` before giving up, as recommended by Intel. `rdrand` only
+/// fails if the generator is exhausted, which is very unlikely to last this long.
+const RDRAND_RETRIES: usize = 10;
+
+/// Number of times to retry `rdseed` before giving up. `rdseed` fails a lot more often under load,
+/// as it waits for fresh entropy rather than stretching it.
+const RDSEED_RETRIES: usize = 100;
+
+/// Number of timing samples mixed together by `tsc_jitter`
+const JITTER_SAMPLES: usize = 256;
+
+/// Retry `func` up to `retries` times until it returns a value, pausing between attempts
+fn retry<F: FnMut() -> Option<u32>>(retries: usize, mut func: F) -> Option<u32> {
+    for _ in 0..retries {
+        if let Some(val) = func() {
+            return Some(val);
+        }
+        cpu::pause();
+    }
+
+    None
+}
+
+/// Get 64 random bits from `rdrand`. Returns `None` if the CPU does not support it, or it kept
+/// failing.
+pub fn rdrand64() -> Option<u64> {
+    if !cpu::has_rdrand() {
+        return None;
+    }
+
+    // Only 32 bits at a time, such that this works the same in the 32-bit bootloader
+    let lo = retry(RDRAND_RETRIES, || unsafe { cpu::rdrand() })?;
+    let hi = retry(RDRAND_RETRIES, || unsafe { cpu::rdrand() })?;
+    Some(lo as u64 | ((hi as u64) << 32))
+}
+
+/// Get 64 random bits from `rdseed`. Returns `None` if the CPU does not support it, or it kept
+/// failing.
+pub fn rdseed64() -> Option<u64> {
+    if !cpu::has_rdseed() {
+        return None;
+    }
+
+    let lo = retry(RDSEED_RETRIES, || unsafe { cpu::rdseed() })?;
+    let hi = retry(RDSEED_RETRIES, || unsafe { cpu::rdseed() })?;
+    Some(lo as u64 | ((hi as u64) << 32))
+}
+
+/// splitmix64 finalizer, every output bit depends on every input bit
+#[inline]
+fn mix(mut val: u64) -> u64 {
+    val = (val ^ (val >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    val = (val ^ (val >> 27)).wrapping_mul(0x94d049bb133111eb);
+    val ^ (val >> 31)
+}
+
+/// Gather entropy from the jitter in how long a small, data dependent loop takes to run, as
+/// measured by the time stamp counter. This is far weaker than the hardware generators, and is
+/// only meant as a fallback when they are not available.
+pub fn tsc_jitter() -> u64 {
+    let mut acc = cpu::rdtsc();
+
+    for ii in 0..JITTER_SAMPLES {
+        let start = cpu::rdtsc();
+
+        // The number of iterations depends on the samples so far, such that the timing depends on
+        // branch prediction as well as caches and interrupts
+        let mut work = ii as u64;
+        for _ in 0..16 + (acc & 0xf) {
+            work = core::hint::black_box(work.rotate_left(5) ^ acc);
+        }
+
+        let delta = cpu::rdtsc().wrapping_sub(start);
+        acc = mix(acc.rotate_left(7) ^ delta ^ work);
+    }
+
+    acc
+}
+
+/// Get 64 bits of entropy, from the best source the CPU has
+pub fn entropy() -> u64 {
+    let seed = rdseed64()
+        .or_else(rdrand64)
+        .unwrap_or_else(tsc_jitter);
+
+    // Mix in the TSC regardless, in case the hardware generator is not to be trusted
+    mix(seed ^ cpu::rdtsc())
+}
+
+/// A wyrand pseudo random number generator. It is not cryptographically secure, but it is fast and
+/// has good statistical quality. The state is atomic such that it can be kept in structures which
+/// must be `Sync`, like the core locals, it is meant to be used from one core at a time though.
+pub struct Rng {
+    state: AtomicU64,
+}
+
+impl Rng {
+    /// Create a generator from `seed`
+    pub const fn new(seed: u64) -> Self {
+        Rng { state: AtomicU64::new(seed) }
+    }
+
+    /// Create a generator seeded from `entropy()`
+    pub fn from_entropy() -> Self {
+        Self::new(entropy())
+    }
+
+    /// Replace the state of the generator with `seed`
+    pub fn reseed(&self, seed: u64) {
+        self.state.store(seed, Ordering::Relaxed);
+    }
+
+    /// Get the next random 64-bit value
+    pub fn next_u64(&self) -> u64 {
+        let state = self.state.fetch_add(0xa0761d6478bd642f, Ordering::Relaxed)
+            .wrapping_add(0xa0761d6478bd642f);
+        let tmp = state as u128 * (state ^ 0xe7037ed1a0b428db) as u128;
+        ((tmp >> 64) ^ tmp) as u64
+    }
+
+    /// Get a random value in `[0, max)`, `max` must not be zero
+    pub fn range(&self, max: u64) -> u64 {
+        assert!(max != 0, "Empty random range");
+
+        // Multiply and keep the high half, which is much less biased than a modulo
+        ((self.next_u64() as u128 * max as u128) >> 64) as u64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+
+    #[test]
+    fn test_deterministic() {
+        let a = Rng::new(0x1337);
+        let b = Rng::new(0x1337);
+        for _ in 0..1000 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+
+        a.reseed(5);
+        b.reseed(6);
+        assert_ne!(a.next_u64(), b.next_u64());
+    }
+
+    #[test]
+    fn test_range() {
+        let rng = Rng::new(0);
+        let mut seen = [false; 10];
+        for _ in 0..10000 {
+            let val = rng.range(10);
+            assert!(val < 10);
+            seen[val as usize] = true;
+        }
+        assert!(seen.iter().all(|&x| x));
+
+        assert_eq!(rng.range(1), 0);
+    }
+
+    #[test]
+    fn test_bits() {
+        // Every bit should be set about half of the time
+        let rng = Rng::new(0xdead_beef);
+        let mut counts = [0usize; 64];
+        for _ in 0..10000 {
+            let val = rng.next_u64();
+            for (bit, count) in counts.iter_mut().enumerate() {
+                *count += (val >> bit) as usize & 1;
+            }
+        }
+        assert!(counts.iter().all(|&x| (4500..5500).contains(&x)), "{:?}", counts);
+    }
+
+    #[test]
+    fn test_entropy() {
+        // Every source on its own should give a different value every time
+        if let (Some(a), Some(b)) = (rdrand64(), rdrand64()) { assert_ne!(a, b); }
+        if let (Some(a), Some(b)) = (rdseed64(), rdseed64()) { assert_ne!(a, b); }
+        assert_ne!(tsc_jitter(), tsc_jitter());
+        assert_ne!(entropy(), entropy());
+    }
+}
@@ -0,0 +1,88 @@
+//! Errors of the build tool
+
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+/// Reasons building, running or testing sherlock can fail
+#[derive(Debug)]
+pub enum Error {
+    /// The command line is invalid
+    Usage(String),
+
+    /// Accessing `path` failed
+    Io { path: PathBuf, err: std::io::Error },
+
+    /// `path` is not valid UTF-8, so it cannot be passed to a tool
+    NonUtf8Path(PathBuf),
+
+    /// The external `tool` could not be started
+    Launch { tool: &'static str, err: std::io::Error },
+
+    /// The external `tool` failed with `status`, and wrote `stderr`
+    Tool { tool: &'static str, status: ExitStatus, stderr: String },
+
+    /// The bootloader PE cannot be turned into a flat image
+    BadBootloaderImage(String),
+
+    /// The bootloader is too large for PXE to load
+    BootloaderTooLarge { size: u64, max: u64 },
+
+    /// The boot config at `path` is invalid
+    BadBootConfig { path: PathBuf, err: boot_config::Error },
+
+    /// The signing key at `path` is not a hex encoded Ed25519 secret key
+    BadSigningKey(PathBuf),
+
+    /// QEMU was killed by a signal, rather than exiting
+    QemuKilled,
+
+    /// A smoke test boot did not go as expected
+    SmokeTest(String),
+
+    /// `failed` of `total` smoke tests failed
+    SmokeTestsFailed { failed: usize, total: usize },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Usage(msg) => write!(f, "{}\n\n{}", msg, crate::USAGE),
+            Error::Io { path, err } => write!(f, "cannot access {}: {}", path.display(), err),
+            Error::NonUtf8Path(path) => write!(f, "path {} is not valid UTF-8", path.display()),
+            Error::Launch { tool, err } => write!(f, "failed to launch {}: {}", tool, err),
+            Error::Tool { tool, status, stderr } =>
+                write!(f, "{} failed with {}:\n{}", tool, status, stderr.trim_end()),
+            Error::BadBootloaderImage(msg) =>
+                write!(f, "cannot flatten the bootloader image: {}", msg),
+            Error::BootloaderTooLarge { size, max } =>
+                write!(f, "bootloader is {} bytes, PXE can load at most {} bytes", size, max),
+            Error::BadBootConfig { path, err } =>
+                write!(f, "invalid boot config {}: {}", path.display(), err),
+            Error::BadSigningKey(path) => write!(f, "signing key {} is not {} hex encoded bytes",
+                path.display(), ed25519::SECRET_KEY_SIZE),
+            Error::QemuKilled => write!(f, "QEMU was killed by a signal"),
+            Error::SmokeTest(msg) => write!(f, "{}", msg),
+            Error::SmokeTestsFailed { failed, total } =>
+                write!(f, "{} of {} smoke tests failed", failed, total),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<parse_pe::Error> for Error {
+    fn from(err: parse_pe::Error) -> Self {
+        Error::BadBootloaderImage(err.to_string())
+    }
+}
+
+/// Turn an I/O error from accessing `path` into an `Error`, for use with `map_err`
+pub fn io(path: impl AsRef<Path>) -> impl FnOnce(std::io::Error) -> Error {
+    let path = path.as_ref().to_path_buf();
+    move |err| Error::Io { path, err }
+}
+
+/// Get `path` as a string to pass to a tool
+pub fn utf8(path: &Path) -> Result<&str, Error> {
+    path.to_str().ok_or_else(|| Error::NonUtf8Path(path.to_path_buf()))
+}
@@ -1,26 +1,244 @@
+mod error;
+
 use std::{
-    path::Path,
-    process::Command,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
     convert::TryInto,
+    io::BufRead,
+    sync::{Arc, atomic::{AtomicBool, Ordering}},
+    time::{Duration, Instant},
 };
 
 use parse_pe::PeParser;
+use manifest::Manifest;
+use error::{Error, io, utf8};
 
 // Base address to the Rust bootloader
 const BOOTLOADER_BASE: u32 = 0x8100;
 const MAX_BOOTLOADER_SIZE: u64 = 32 * 1024;
 
+/// Target the bootloader is built for
+const BOOTLOADER_TARGET: &str = "i586-pc-windows-msvc";
+
+/// Target the kernel is built for when `KERNEL_TARGET` is not set. Setting `KERNEL_TARGET` to
+/// `x86_64-unknown-none` builds an ELF kernel instead, the bootloader handles both.
+const DEFAULT_KERNEL_TARGET: &str = "x86_64-pc-windows-msvc";
+
+/// Environment variable naming a file with a hex encoded Ed25519 secret key, for example created
+/// with `head -c 32 /dev/urandom | xxd -p -c 32`. When set, the kernel manifest is signed with the
+/// key, and the bootloader is built to only boot kernels signed with it.
+const SIGNING_KEY_VAR: &str = "SHERLOCK_SIGNING_KEY";
+
+/// QEMU binary used by `run` when `QEMU` is not set
+const DEFAULT_QEMU: &str = "qemu-system-x86_64";
+
+/// Number of cores and amount of memory of the machine `run` boots
+const RUN_CORES: usize = 4;
+const RUN_MEMORY: &str = "2G";
+
+/// I/O port of the `isa-debug-exit` device the kernel reports its exit status through, and the
+/// offset the kernel adds to statuses. Must match `kernel/src/qemu.rs`.
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+const EXIT_STATUS_BASE: i32 = 0x10;
+
+/// Core counts the smoke tests boot with
+const SMOKE_TEST_CORES: [usize; 2] = [1, 4];
+
+/// How long a smoke test boot may take, generous for TCG on a slow machine
+const SMOKE_TEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Directory in the target directory the smoke tests boot from
+const SMOKE_TEST_DIR: &str = "test";
+
+/// Help for the command line
+const USAGE: &str = "\
+Usage: sherlock [COMMAND] [OPTIONS]
+
+Commands:
+    build    Build the bootloader and the kernel into the target directory (default)
+    clean    Remove everything `build` generated, keeping the boot config and modules
+    run      Build, then boot in QEMU and exit with the status the kernel reports
+    test     Build, then run the smoke tests in QEMU
+    size     Report the size of the build against the PXE limit
+
+Options:
+    --debug                 Build with the debug profile
+    --release               Build with the release profile (default)
+    --target-dir <DIR>      Directory to build into, which is the TFTP root (default: build)
+    --boot-file <NAME>      Name of the PXE boot image (default: sherlock.boot)
+    --flat-file <NAME>      Name of the flattened bootloader image (default: sherlock.flat)
+    -h, --help              Print this help";
+
+/// What the build tool was asked to do
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Subcommand {
+    Build,
+    Clean,
+    Run,
+    Test,
+    Size,
+    Help,
+}
+
+/// Options from the command line
+#[derive(Debug)]
+struct Options {
+    /// What to do
+    subcommand: Subcommand,
+
+    /// Whether to build with the release profile, rather than the debug one
+    release: bool,
+
+    /// Directory the build goes into, which is also the TFTP root
+    target_dir: PathBuf,
+
+    /// Name of the PXE boot image in the target directory
+    boot_file: String,
+
+    /// Name of the flattened bootloader image in the target directory
+    flat_file: String,
+}
+
+impl Options {
+    /// Parse the command line arguments `args`, without the program name
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Error> {
+        let mut options = Options {
+            subcommand: Subcommand::Build,
+            release: true,
+            target_dir: PathBuf::from("build"),
+            boot_file: "sherlock.boot".into(),
+            flat_file: "sherlock.flat".into(),
+        };
+
+        let mut subcommand = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--debug" => options.release = false,
+                "--release" => options.release = true,
+                "-h" | "--help" => subcommand = Some(Subcommand::Help),
+                "--target-dir" | "--boot-file" | "--flat-file" => {
+                    let value = args.next()
+                        .ok_or_else(|| Error::Usage(format!("{} needs a value", arg)))?;
+                    match arg.as_str() {
+                        "--target-dir" => options.target_dir = value.into(),
+                        "--boot-file" => options.boot_file = value,
+                        _ => options.flat_file = value,
+                    }
+                }
+                _ if subcommand.is_none() && !arg.starts_with('-') => {
+                    subcommand = Some(match arg.as_str() {
+                        "build" => Subcommand::Build,
+                        "clean" => Subcommand::Clean,
+                        "run" => Subcommand::Run,
+                        "test" => Subcommand::Test,
+                        "size" => Subcommand::Size,
+                        _ => return Err(Error::Usage(format!("unknown command {:?}", arg))),
+                    });
+                }
+                _ => return Err(Error::Usage(format!("unexpected argument {:?}", arg))),
+            }
+        }
+
+        options.subcommand = subcommand.unwrap_or(Subcommand::Build);
+        Ok(options)
+    }
+
+    /// Name of the cargo profile directory the build goes into
+    fn profile(&self) -> &'static str {
+        if self.release { "release" } else { "debug" }
+    }
+
+    /// Path of the PXE boot image
+    fn boot_file(&self) -> PathBuf {
+        self.target_dir.join(&self.boot_file)
+    }
+}
+
+/// Run the external `tool` with `command`. What the tool writes to stderr is passed on if it
+/// succeeds, and returned in the error if it fails.
+fn run_tool(tool: &'static str, command: &mut Command) -> Result<(), Error> {
+    let output = command.stdout(Stdio::inherit()).stderr(Stdio::piped()).output()
+        .map_err(|err| Error::Launch { tool, err })?;
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+
+    if !output.status.success() {
+        return Err(Error::Tool { tool, status: output.status, stderr });
+    }
+
+    eprint!("{}", stderr);
+    Ok(())
+}
+
+/// Parse the boot config `config`, read from `path`
+fn parse_config<'a>(config: &'a str, path: &Path) -> Result<boot_config::Config<'a>, Error> {
+    boot_config::Config::parse(config)
+        .map_err(|err| Error::BadBootConfig { path: path.to_path_buf(), err })
+}
+
+/// Encode `bytes` as lowercase hex
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Read the secret key named by `SIGNING_KEY_VAR`, if it is set
+fn signing_key() -> Result<Option<[u8; ed25519::SECRET_KEY_SIZE]>, Error> {
+    let path = match std::env::var_os(SIGNING_KEY_VAR) {
+        Some(path) => PathBuf::from(path),
+        None => return Ok(None),
+    };
+
+    let key = std::fs::read_to_string(&path).map_err(io(&path))?;
+    let key = key.trim();
+    if key.len() != ed25519::SECRET_KEY_SIZE * 2 || !key.bytes().all(|x| x.is_ascii_hexdigit()) {
+        return Err(Error::BadSigningKey(path));
+    }
+
+    Ok(Some(manifest::decode_hex(key)))
+}
+
+/// Path of the manifest of the file at `path`, `<path>.manifest`
+fn manifest_path(path: &Path) -> PathBuf {
+    let mut manifest_path = path.as_os_str().to_owned();
+    manifest_path.push(".manifest");
+    manifest_path.into()
+}
+
+/// Write the manifest the bootloader checks the file at `path` against to `<path>.manifest`,
+/// signed with `signing_key` if there is one
+fn write_manifest(path: &Path, signing_key: Option<&[u8; ed25519::SECRET_KEY_SIZE]>)
+        -> Result<(), Error> {
+    let file = std::fs::read(path).map_err(io(path))?;
+
+    let mut manifest = Manifest::new(&file);
+    if let Some(key) = signing_key {
+        manifest.sign(key);
+    }
+
+    let manifest_path = manifest_path(path);
+    let mut buf = [0u8; manifest::MAX_SIZE];
+    std::fs::write(&manifest_path, manifest.serialize(&mut buf)).map_err(io(&manifest_path))?;
+
+    println!("{} is {} bytes | SHA-256 {}{}", path.display(), manifest.size,
+        hex(&manifest.sha256), if signing_key.is_some() { " | signed" } else { "" });
+
+    Ok(())
+}
+
 /// Create a flattened PE image
-fn flatten_pe<P: AsRef<Path>>(filename: P) -> Option<(u32, u32, Vec<u8>)> {
-    let pe = std::fs::read(filename).ok()?;
-    let pe = PeParser::parse(&pe).unwrap();
+fn flatten_pe(filename: &Path) -> Result<(u32, u32, Vec<u8>), Error> {
+    let bad_image = |err: &dyn std::fmt::Display| Error::BadBootloaderImage(err.to_string());
+
+    let pe = std::fs::read(filename).map_err(io(filename))?;
+    let pe = PeParser::parse(&pe)?;
 
     // Compute the bounds of the _loaded_ image
     let mut image_start = None;
     let mut image_end = None;
 
-    pe.sections(|base, size, _raw, _, _, _| {
-        let end = base.checked_add(size.checked_sub(1)?.into())?;
+    pe.sections(|base, size, _raw, _, _, _| -> Result<(), Error> {
+        let end = size.checked_sub(1)
+            .and_then(|size| base.checked_add(size.into()))
+            .ok_or_else(|| bad_image(&"empty or overflowing section in PE"))?;
 
         if image_start.is_none() {
             image_start = Some(base);
@@ -30,137 +248,436 @@ fn flatten_pe<P: AsRef<Path>>(filename: P) -> Option<(u32, u32, Vec<u8>)> {
         image_start = image_start.map(|x| core::cmp::min(x, base));
         image_end = image_end.map(|x| core::cmp::max(x, end));
 
-        Some(())
+        Ok(())
     })?;
 
-    let image_start = image_start?;
-    let image_end = image_end?;
-    let image_size = image_end.checked_sub(image_start)?.checked_add(1)?.try_into().ok()?;
+    let image_start = image_start.ok_or_else(|| bad_image(&"PE has no sections"))?;
+    let image_end = image_end.ok_or_else(|| bad_image(&"PE has no sections"))?;
+    let image_size: usize = (image_end - image_start + 1).try_into()
+        .map_err(|err| bad_image(&err))?;
 
     // Allocate a zeroed image
     let mut flattened = std::vec![0u8; image_size];
 
-    pe.sections(|base, size, raw, _, _, _| {
-        let flat_off: usize = (base - image_start).try_into().ok()?;
-        let size: usize = size.try_into().ok()?;
+    pe.sections(|base, size, raw, _, _, _| -> Result<(), Error> {
+        let flat_off: usize = (base - image_start).try_into().map_err(|err| bad_image(&err))?;
+        let size: usize = size.try_into().map_err(|err| bad_image(&err))?;
 
         // Compute the number of bytes to initialize
         let to_copy = std::cmp::min(size, raw.len());
 
-        flattened[flat_off..flat_off.checked_add(to_copy)?].copy_from_slice(raw);
-        Some(())
+        flattened[flat_off..flat_off + to_copy].copy_from_slice(&raw[..to_copy]);
+        Ok(())
     })?;
 
     // Make sure the entry point falls within the image
     if pe.entry_point < image_start || pe.entry_point > image_end {
-        return None;
+        return Err(bad_image(&"PE entry point is outside of the image"));
     }
 
-    Some((pe.entry_point.try_into().ok()?, image_start.try_into().ok()?, flattened))
+    let entry_point = pe.entry_point.try_into().map_err(|err| bad_image(&err))?;
+    let image_start = image_start.try_into().map_err(|err| bad_image(&err))?;
+    Ok((entry_point, image_start, flattened))
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Create a build folder, if it does not exist
-    let build_dir = Path::new("build");
-    let bootloader_build_dir = build_dir.clone().join("bootloader").canonicalize().expect("Nope");
+/// Create the command booting `boot_file` from the TFTP root `tftp_root` in QEMU with `cores`
+/// cores. QEMU serves the directory over its built-in TFTP server. The serial port goes to stdout.
+fn qemu_command(tftp_root: &Path, boot_file: &str, cores: usize) -> Result<Command, Error> {
+    let qemu = std::env::var("QEMU").unwrap_or_else(|_| DEFAULT_QEMU.into());
+    let tftp_root = tftp_root.canonicalize().map_err(io(tftp_root))?;
+
+    // Use KVM when it is there, TCG otherwise. `max` gives the kernel NX, SMEP and SMAP either way.
+    let mut command = Command::new(qemu);
+    command.args([
+        "-machine", "pc", "-accel", "kvm", "-accel", "tcg", "-cpu", "max",
+        "-smp", &cores.to_string(), "-m", RUN_MEMORY,
+        "-netdev", &format!("user,id=net0,tftp={},bootfile={}", utf8(&tftp_root)?, boot_file),
+        "-device", "e1000,netdev=net0", "-boot", "n",
+        "-device", &format!("isa-debug-exit,iobase={:#x},iosize=0x04", DEBUG_EXIT_PORT),
+        "-display", "none", "-serial", "stdio", "-no-reboot",
+    ]);
+
+    Ok(command)
+}
 
-    std::fs::create_dir_all(&build_dir).expect("Failed to create build directory");
-    std::fs::create_dir_all(&bootloader_build_dir).expect("Failed to create boot directory");
-    std::fs::create_dir_all("build/kernel").expect("Failed to create kernel directory");
+/// Get the exit status the kernel reported from the exit code of QEMU, if it reported one. The
+/// kernel reports `status` by writing `EXIT_STATUS_BASE + status` to the debug exit device, which
+/// makes QEMU exit with `(value << 1) | 1`. Anything else is QEMU's own.
+fn kernel_status(code: i32) -> Option<i32> {
+    if code & 1 == 1 && code >> 1 >= EXIT_STATUS_BASE {
+        Some((code >> 1) - EXIT_STATUS_BASE)
+    } else {
+        None
+    }
+}
+
+/// Boot the build in QEMU. Returns the exit status the kernel reported, or the exit code of QEMU
+/// if the kernel reported none.
+fn run(options: &Options) -> Result<i32, Error> {
+    let status = qemu_command(&options.target_dir, &options.boot_file, RUN_CORES)?.status()
+        .map_err(|err| Error::Launch { tool: "qemu", err })?;
+
+    let code = status.code().ok_or(Error::QemuKilled)?;
+    if let Some(status) = kernel_status(code) {
+        println!("Kernel exited with status {}", status);
+        return Ok(status);
+    }
+
+    println!("QEMU exited with {}", code);
+    Ok(code)
+}
+
+/// Boot the build in QEMU with `cores` cores and all selftests enabled, and check that every core
+/// came online and the kernel reported success. The boot is served from a TFTP root of its own,
+/// `SMOKE_TEST_DIR`, with a boot config enabling the selftests and the exit status.
+fn smoke_test(options: &Options, cores: usize) -> Result<(), Error> {
+    let config_path = options.target_dir.join(boot_config::FILENAME);
+    let config = std::fs::read_to_string(&config_path).map_err(io(&config_path))?;
+    let config = parse_config(&config, &config_path)?;
+
+    let tftp_root = options.target_dir.join(SMOKE_TEST_DIR);
+    std::fs::create_dir_all(&tftp_root).map_err(io(&tftp_root))?;
+    let kernel_manifest = format!("{}.manifest", config.kernel());
+    for file in [&options.boot_file, config.kernel(), &kernel_manifest] {
+        std::fs::copy(options.target_dir.join(file), tftp_root.join(file))
+            .map_err(io(options.target_dir.join(file)))?;
+    }
+    let test_config = tftp_root.join(boot_config::FILENAME);
+    std::fs::write(&test_config, format!(
+        "kernel {}\ncmdline exit=qemu selftest=all cores={}\n", config.kernel(), cores))
+        .map_err(io(&test_config))?;
+
+    let mut qemu = qemu_command(&tftp_root, &options.boot_file, cores)?
+        .stdout(Stdio::piped()).spawn()
+        .map_err(|err| Error::Launch { tool: "qemu", err })?;
+
+    // Echo the serial output as it comes in, and keep it to check once QEMU is done. A panic
+    // fails the test right away, rather than leaving QEMU running until the timeout.
+    let stdout = qemu.stdout.take().unwrap();
+    let panicked = Arc::new(AtomicBool::new(false));
+    let reader_panicked = panicked.clone();
+    let reader = std::thread::spawn(move || -> std::io::Result<String> {
+        let mut stdout = std::io::BufReader::new(stdout);
+        let mut output = String::new();
+        let mut line = Vec::new();
+        while stdout.read_until(b'\n', &mut line)? != 0 {
+            let text = String::from_utf8_lossy(&line);
+            print!("{}", text);
+            if text.contains("PANIC:") {
+                reader_panicked.store(true, Ordering::SeqCst);
+            }
+            output.push_str(&text);
+            line.clear();
+        }
+        Ok(output)
+    });
+
+    let qemu_io = |err| Error::Launch { tool: "qemu", err };
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = qemu.try_wait().map_err(qemu_io)? {
+            break Some(status);
+        }
+        if panicked.load(Ordering::SeqCst) || start.elapsed() > SMOKE_TEST_TIMEOUT {
+            qemu.kill().map_err(qemu_io)?;
+            qemu.wait().map_err(qemu_io)?;
+            break None;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    let output = reader.join().expect("Serial reader panicked").map_err(qemu_io)?;
 
+    if panicked.load(Ordering::SeqCst) {
+        return Err(Error::SmokeTest("kernel panicked".into()));
+    }
+    for id in 0..cores {
+        if !output.contains(&format!("Core ID {} online!", id)) {
+            return Err(Error::SmokeTest(format!("core {} did not come online", id)));
+        }
+    }
+
+    let status = status.ok_or_else(|| Error::SmokeTest(
+        format!("timed out after {} seconds", SMOKE_TEST_TIMEOUT.as_secs())))?;
+    let code = status.code().ok_or(Error::QemuKilled)?;
+    match kernel_status(code) {
+        Some(0) => Ok(()),
+        Some(status) => Err(Error::SmokeTest(format!("kernel exited with status {}", status))),
+        None => Err(Error::SmokeTest(
+            format!("QEMU exited with {} without a kernel exit status", code))),
+    }
+}
+
+/// Run a smoke test for every core count in `SMOKE_TEST_CORES`
+fn test(options: &Options) -> Result<(), Error> {
+    let mut failed = 0;
+    for cores in SMOKE_TEST_CORES {
+        match smoke_test(options, cores) {
+            Ok(()) => println!("Smoke test with {} cores passed", cores),
+            Err(err) => {
+                println!("Smoke test with {} cores FAILED: {}", cores, err);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed != 0 {
+        return Err(Error::SmokeTestsFailed { failed, total: SMOKE_TEST_CORES.len() });
+    }
+
+    Ok(())
+}
+
+/// Report the size of the bootloader at `boot_file` against what PXE can load. Returns an error
+/// if it is too large.
+fn check_bootloader_size(boot_file: &Path) -> Result<(), Error> {
+    let size = boot_file.metadata().map_err(io(boot_file))?.len();
+    println!("Current bootloader size is {} of {} bytes [{:8.4} %]",
+                size, MAX_BOOTLOADER_SIZE,
+                        size as f64 / MAX_BOOTLOADER_SIZE as f64 * 100.);
+    if size > MAX_BOOTLOADER_SIZE {
+        return Err(Error::BootloaderTooLarge { size, max: MAX_BOOTLOADER_SIZE });
+    }
+
+    Ok(())
+}
+
+/// Report the size of the bootloader, the kernel and the modules in the target directory
+fn size(options: &Options) -> Result<(), Error> {
+    let config_path = options.target_dir.join(boot_config::FILENAME);
+    let config = std::fs::read_to_string(&config_path).map_err(io(&config_path))?;
+    let config = parse_config(&config, &config_path)?;
+
+    for file in std::iter::once(config.kernel()).chain(config.modules().map(|(_, file)| file)) {
+        let path = options.target_dir.join(file);
+        let size = path.metadata().map_err(io(&path))?.len();
+        println!("{} is {} bytes", path.display(), size);
+    }
+
+    check_bootloader_size(&options.boot_file())
+}
+
+/// Remove everything `build` generated from the target directory. The boot config and the modules
+/// are put there by the user, so they stay.
+fn clean(options: &Options) -> Result<(), Error> {
+    let remove_dir = |path: PathBuf| match std::fs::remove_dir_all(&path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(io(&path)(err)),
+        _ => Ok(()),
+    };
+    let remove_file = |path: PathBuf| match std::fs::remove_file(&path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(io(&path)(err)),
+        _ => Ok(()),
+    };
+
+    for dir in ["bootloader", "kernel", SMOKE_TEST_DIR] {
+        remove_dir(options.target_dir.join(dir))?;
+    }
+    remove_file(options.boot_file())?;
+    remove_file(options.target_dir.join(&options.flat_file))?;
+
+    let config_path = options.target_dir.join(boot_config::FILENAME);
+    if config_path.exists() {
+        let config = std::fs::read_to_string(&config_path).map_err(io(&config_path))?;
+        let config = parse_config(&config, &config_path)?;
+
+        let kernel = options.target_dir.join(config.kernel());
+        remove_file(manifest_path(&kernel))?;
+        remove_file(kernel)?;
+        for (_, file) in config.modules() {
+            remove_file(manifest_path(&options.target_dir.join(file)))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let result = Options::parse(std::env::args().skip(1)).and_then(|options| {
+        match options.subcommand {
+            Subcommand::Build => build(&options),
+            Subcommand::Clean => clean(&options),
+            Subcommand::Run => {
+                build(&options)?;
+                std::process::exit(run(&options)?);
+            }
+            Subcommand::Test => {
+                build(&options)?;
+                test(&options)
+            }
+            Subcommand::Size => size(&options),
+            Subcommand::Help => {
+                println!("{}", USAGE);
+                Ok(())
+            }
+        }
+    });
+
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+/// Build the bootloader and the kernel into the target directory, the TFTP root to boot from
+fn build(options: &Options) -> Result<(), Error> {
+    // Create the build folders, if they do not exist. Cargo runs in other directories, so it gets
+    // absolute paths.
+    let build_dir = &options.target_dir;
+    for dir in ["bootloader", "kernel"] {
+        let dir = build_dir.join(dir);
+        std::fs::create_dir_all(&dir).map_err(io(&dir))?;
+    }
+    let bootloader_build_dir = build_dir.join("bootloader");
+    let bootloader_build_dir = bootloader_build_dir.canonicalize()
+        .map_err(io(&bootloader_build_dir))?;
 
     // Create the boot file name
-    let boot_file = build_dir.clone().join("sherlock.boot");
+    let boot_file = options.boot_file();
 
     // Build the assembly routines for the bootloader
-    if !Command::new("nasm")
-        .args(&[
+    let asm_routines = bootloader_build_dir.join("asm_routines.obj");
+    run_tool("nasm", Command::new("nasm")
+        .args([
             "-f",
             "win32",
             &format!("-DPROGRAM_BASE={:#x}", BOOTLOADER_BASE),
-            Path::new("bootloader").join("src").join("asm_routines.asm").to_str().unwrap(),
+            utf8(&Path::new("bootloader").join("src").join("asm_routines.asm"))?,
             "-o",
-            Path::new("build").join("bootloader").join("asm_routines.obj").to_str().unwrap()
-        ])
-        .status()?
-        .success()
-    {
-        return Err("Failed to build bootloader assembly routines".into());
-    }
-
-    let boot_build_cmd = Command::new("cargo")
+            utf8(&asm_routines)?,
+        ]))?;
+
+    // With a signing key, the bootloader gets the public key baked in and requires signatures.
+    // The assembly routines are linked in from the target directory, wherever that is.
+    let signing_key = signing_key()?;
+    let mut boot_build_cmd = Command::new("cargo");
+    boot_build_cmd
         .current_dir("bootloader")
-        .args(&[
+        .args([
             "build",
-            "--release",
             "--target",
-            "i586-pc-windows-msvc",
+            BOOTLOADER_TARGET,
             "--target-dir",
-            bootloader_build_dir.to_str().unwrap()
-        ]).status()?;
+            utf8(&bootloader_build_dir)?,
+            "--config",
+            &format!("target.{}.rustflags=[\"-C\", \"link-arg={}\"]", BOOTLOADER_TARGET,
+                utf8(&asm_routines)?),
+        ]);
+
+    if options.release {
+        boot_build_cmd.arg("--release");
+    }
 
-    if !boot_build_cmd.success() {
-        return Err("Failed to build bootloader".into());
+    if let Some(key) = &signing_key {
+        boot_build_cmd
+            .args(["--features", "signed"])
+            .env("SHERLOCK_PUBLIC_KEY", hex(&ed25519::public_key(key)));
     }
 
+    run_tool("cargo", &mut boot_build_cmd)?;
+
     // Flatten the PE image
-    let (entry, base, image) = flatten_pe(bootloader_build_dir.join("i586-pc-windows-msvc")
-        .join("release").join("bootloader.exe"))
-        .ok_or("Failed to flatten bootloader PE image")?;
+    let (entry, base, image) = flatten_pe(&bootloader_build_dir.join(BOOTLOADER_TARGET)
+        .join(options.profile()).join("bootloader.exe"))?;
 
     // Make sure the PE gets loaded to where we expect
     if base != BOOTLOADER_BASE {
-        return Err("Base address for bootloader did not match expected".into());
+        return Err(Error::BadBootloaderImage(format!(
+            "loaded at {:#x}, expected {:#x}", base, BOOTLOADER_BASE)));
     }
 
     // Write out the flattened bootloader image
-    std::fs::write(Path::new("build").join("sherlock.flat"), image).expect("Failed to write flat");
-
+    let flat_file = build_dir.join(&options.flat_file);
+    std::fs::write(&flat_file, image).map_err(io(&flat_file))?;
 
     // Build the stage0
     let stage0 = Path::new("bootloader").join("src").join("stage0.asm");
 
     // Compile with `nasm`
-    let nasm_stage0_cmd = Command::new("nasm")
-        .args(&[
+    run_tool("nasm", Command::new("nasm")
+        .args([
             "-f", "bin", &format!("-Dentry_point={:#x}", entry), "-o",
-            boot_file.to_str().unwrap(), stage0.to_str().unwrap()
-        ]).status()?;
-
-    // Check command status
-    if !nasm_stage0_cmd.success() {
-        return Err("Failed to assemble stage0".into());
-    }
+            utf8(&boot_file)?, utf8(&stage0)?
+        ]))?;
 
     // Check bootloader size is within bounds
-    let bl_size = boot_file.metadata()?.len();
-    print!("Current bootloader size is {} of {} bytes [{:8.4} %]\n",
-                bl_size, MAX_BOOTLOADER_SIZE,
-                        bl_size as f64 / MAX_BOOTLOADER_SIZE as f64 * 100.);
-    if bl_size > MAX_BOOTLOADER_SIZE {
-        return Err("Bootloader size exceeds allowed PXE limit".into());
-    }
+    check_bootloader_size(&boot_file)?;
 
     // Build the kernel
-    let kernel_build_dir = Path::new("build").join("kernel").canonicalize()?;
-    let kernel_exe = kernel_build_dir.join("x86_64-pc-windows-msvc")
-        .join("release").join("kernel.exe");
-
-    if !Command::new("cargo")
+    let kernel_target = std::env::var("KERNEL_TARGET")
+        .unwrap_or_else(|_| DEFAULT_KERNEL_TARGET.into());
+    let kernel_build_dir = build_dir.join("kernel");
+    let kernel_build_dir = kernel_build_dir.canonicalize().map_err(io(&kernel_build_dir))?;
+    let kernel_exe = kernel_build_dir.join(&kernel_target).join(options.profile())
+        .join(if kernel_target.contains("windows") { "kernel.exe" } else { "kernel" });
+
+    let mut kernel_build_cmd = Command::new("cargo");
+    kernel_build_cmd
         .current_dir("kernel")
-        .args(&[
+        .args([
             "build",
-            "--release",
             "--target",
-            "x86_64-pc-windows-msvc",
+            &kernel_target,
             "--target-dir",
-            kernel_build_dir.to_str().unwrap()
-        ]).status()?.success() {
-       return Err("Failed to kernel".into()); 
+            utf8(&kernel_build_dir)?,
+        ]);
+
+    if options.release {
+        kernel_build_cmd.arg("--release");
+    }
+
+    run_tool("cargo", &mut kernel_build_cmd)?;
+
+    // The boot config is kept in the build directory, which is the TFTP root. Create the default
+    // one if there is none yet.
+    let config_path = build_dir.join(boot_config::FILENAME);
+    if !config_path.exists() {
+        std::fs::write(&config_path, boot_config::DEFAULT).map_err(io(&config_path))?;
     }
+    let config = std::fs::read_to_string(&config_path).map_err(io(&config_path))?;
+    let config = parse_config(&config, &config_path)?;
 
-    //std::fs::copy(boot_file, "/home/m3m0ry/fun/sherlock/build/sherlock.boot")?;
-    std::fs::copy(kernel_exe, Path::new("build").join("sherlock.kern"))?;
+    let kernel_path = build_dir.join(config.kernel());
+    std::fs::copy(&kernel_exe, &kernel_path).map_err(io(&kernel_exe))?;
+    write_manifest(&kernel_path, signing_key.as_ref())?;
+
+    // The modules are put in the build directory by the user, they only need their manifests
+    for (_, file) in config.modules() {
+        write_manifest(&build_dir.join(file), signing_key.as_ref())?;
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, Error> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_options() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(options.subcommand, Subcommand::Build);
+        assert!(options.release);
+        assert_eq!(options.boot_file(), Path::new("build").join("sherlock.boot"));
+
+        let options = parse(&["--debug", "run", "--target-dir", "out", "--boot-file", "a.boot"])
+            .unwrap();
+        assert_eq!(options.subcommand, Subcommand::Run);
+        assert_eq!(options.profile(), "debug");
+        assert_eq!(options.boot_file(), Path::new("out").join("a.boot"));
+
+        assert!(matches!(parse(&["bogus"]), Err(Error::Usage(_))));
+        assert!(matches!(parse(&["run", "test"]), Err(Error::Usage(_))));
+        assert!(matches!(parse(&["--flat-file"]), Err(Error::Usage(_))));
+    }
+
+    #[test]
+    fn test_kernel_status() {
+        assert_eq!(kernel_status((EXIT_STATUS_BASE << 1) | 1), Some(0));
+        assert_eq!(kernel_status(((EXIT_STATUS_BASE + 2) << 1) | 1), Some(2));
+        assert_eq!(kernel_status(0), None);
+        assert_eq!(kernel_status(1), None);
+    }
+}